@@ -1,5 +1,3 @@
-use cyclotron::muon::warp::ExecErr;
-
 #[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
 pub enum CmdType {
     #[default]
@@ -17,9 +15,21 @@ pub enum SimErr {
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum Completion {
-    OK,
-    EXECUTION,
+pub enum MemErrCode {
+    /// Host pointer range falls outside the shared memory region.
+    HOST,
+    /// Device address range falls outside the DRAM the command may access, or the access failed.
+    RANGE,
+    /// Descriptor address is not 8-byte aligned.
+    MISALIGNED,
+    /// Transfer of zero bytes.
+    ZERO,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemErr {
+    pub code: MemErrCode,
+    pub addr: u64,
 }
 
 pub trait Clocked {
@@ -31,43 +41,7 @@ pub trait Configurable<T: Default> {
     fn new(config: &T) -> Self;
 }
 
-#[derive(Debug, Default, Clone, Copy)]
-pub struct Command {
-    bytes: [u8; 16],
-}
-
-impl Command {
-    pub fn from_bytes(bytes: [u8; 16]) -> Self {
-        Command { bytes }
-    }
-
-    pub fn cmd_type(&self) -> CmdType {
-        match self.bytes[1] {
-            0 => CmdType::KERNEL,
-            1 => CmdType::MEM,
-            2 => CmdType::CSR,
-            3 => CmdType::FENCE,
-            _ => CmdType::UNDEFINED,
-        }
-    }
-
-    pub fn sid(&self) -> u8 {
-        self.bytes[0]
-    }
-
-    pub fn is_fence(&self) -> bool {
-        match self.cmd_type() {
-            CmdType::FENCE => true,
-            _ => false,
-        }
-    }
-
-    pub fn slice(&self, i: usize, j: usize) -> &[u8] {
-        &self.bytes[i..j]
-    }
-}
-
-#[derive(Debug, Default, Clone, Copy)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
 pub enum DMADir {
     #[default]
     H2D,
@@ -86,17 +60,24 @@ impl From<bool> for DMADir {
 
 #[derive(Debug, Default, Clone, Copy)]
 pub struct DMAReq {
+    /// Stream the request is issued on; selects whose host memory the DMA reaches.
+    pub sid: u8,
     pub dir: DMADir,
-    pub src_addr: u32,
-    pub target_addr: u32,
+    pub src_addr: u64,
+    pub target_addr: u64,
     pub sz: u32,
+    /// H2D only: hand the bytes read to the engine instead of writing them to `target_addr`.
+    pub fetch: bool,
     pub done: bool,
+    pub err: Option<MemErr>,
 }
 
 #[derive(Debug, Default, Clone)]
 pub struct MemReq {
-    pub addr: u32,
+    pub addr: u64,
     pub write: bool,
+    /// Write `data` and return the bytes it replaced in the same access.
+    pub swap: bool,
     pub bytes: u32,
     pub data: Vec<u8>,
 }
@@ -104,6 +85,8 @@ pub struct MemReq {
 #[derive(Debug, Default, Clone)]
 pub struct MemResp {
     pub data: Option<Vec<u8>>,
+    /// The access failed and had no effect.
+    pub err: Option<MemErr>,
 }
 
 #[derive(Debug, Default, Clone)]
@@ -115,41 +98,3 @@ pub struct ThreadBlocks {
     pub shmem: u32,
     pub bp: u32,
 }
-#[derive(Debug, Default, Clone, Copy)]
-pub struct Event {
-    pub bytes: [u8; 16],
-}
-
-impl Event {
-    pub fn from_kernel_err(sid: u8, err: Result<(), ExecErr>) -> Self {
-        if let Err(err) = err {
-            let mut bytes = [0u8; 16];
-            bytes[0] = sid;
-            bytes[1] = Completion::EXECUTION as u8;
-            bytes[2..6].copy_from_slice(&err.pc.to_le_bytes());
-            bytes[6..10].copy_from_slice(&(err.warp_id as u32).to_le_bytes());
-            Event { bytes }
-        } else {
-            Event::from_ok(sid)
-        }
-    }
-
-    pub fn from_ok(sid: u8) -> Self {
-        let mut bytes = [0u8; 16];
-        bytes[0] = sid;
-        bytes[1] = Completion::OK as u8;
-        Event { bytes }
-    }
-
-    pub fn to_exec_err(&self) -> ExecErr {
-        ExecErr {
-            pc: u32::from_le_bytes([self.bytes[2], self.bytes[3], self.bytes[4], self.bytes[5]]),
-            warp_id: self.bytes[6] as usize,
-            message: None,
-        }
-    }
-
-    pub fn sid(&self) -> u8 {
-        self.bytes[0]
-    }
-}
@@ -0,0 +1,770 @@
+use std::fmt;
+
+use cyclotron::muon::warp::ExecErr;
+
+use crate::common::base::{CmdType, MemErr, MemErrCode, SimErr};
+
+/// Bumped whenever the layout of a command, event or the handshake changes.
+pub const PROTOCOL_VERSION: u32 = 11;
+/// v2 widened headers from 16 to 32 bytes to carry 64-bit host and device addresses.
+pub const COMMAND_BYTES: usize = 32;
+pub const COMMAND_ARGS_BYTES: usize = COMMAND_BYTES - 2;
+/// v3 widened events from 16 to 32 bytes so `HELLO` can carry the client's context.
+pub const EVENT_BYTES: usize = 32;
+/// Handshake sent alongside the memfd: shared memory base (8 bytes), protocol version (4 bytes), reserved (4 bytes).
+pub const HANDSHAKE_BYTES: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    UnknownCmdType(u8),
+    UnknownMemOp(u8),
+    UnknownEventCode(u8),
+    /// The stream id is outside the range assigned to the client.
+    UnknownStream(u8),
+}
+
+impl DecodeError {
+    fn to_bytes(self) -> [u8; 2] {
+        match self {
+            DecodeError::UnknownCmdType(value) => [0, value],
+            DecodeError::UnknownMemOp(value) => [1, value],
+            DecodeError::UnknownEventCode(value) => [2, value],
+            DecodeError::UnknownStream(value) => [3, value],
+        }
+    }
+
+    fn from_bytes(bytes: [u8; 2]) -> Option<Self> {
+        match bytes[0] {
+            0 => Some(DecodeError::UnknownCmdType(bytes[1])),
+            1 => Some(DecodeError::UnknownMemOp(bytes[1])),
+            2 => Some(DecodeError::UnknownEventCode(bytes[1])),
+            3 => Some(DecodeError::UnknownStream(bytes[1])),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::UnknownCmdType(value) => write!(f, "unknown command type {value}"),
+            DecodeError::UnknownMemOp(value) => write!(f, "unknown mem op {value}"),
+            DecodeError::UnknownEventCode(value) => write!(f, "unknown event code {value}"),
+            DecodeError::UnknownStream(value) => write!(f, "unknown stream {value}"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct KernelCommand {
+    pub sid: u8,
+    pub host_addr: u64,
+    pub sz: u32,
+    pub gpu_addr: u64,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CopyCommand {
+    pub sid: u8,
+    pub src: u64,
+    pub dst: u64,
+    pub len: u32,
+    /// Bit 0: device to host instead of host to device. Bit 1: device to device, bit 0 is ignored.
+    pub flags: u8,
+}
+
+impl CopyCommand {
+    pub fn d2d(&self) -> bool {
+        self.flags & 2 != 0
+    }
+
+    pub fn d2h(&self) -> bool {
+        !self.d2d() && self.flags & 1 != 0
+    }
+}
+
+/// Pitched copy whose geometry is read from a descriptor in device memory, see `docs/gluon.md`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct StridedCommand {
+    pub sid: u8,
+    /// 2 or 3; a 2D copy ignores the depth and slice pitches of the descriptor.
+    pub dims: u8,
+    pub desc: u64,
+    /// Direction, as for `CopyCommand`.
+    pub flags: u8,
+}
+
+impl StridedCommand {
+    pub fn d2d(&self) -> bool {
+        self.flags & 2 != 0
+    }
+
+    pub fn d2h(&self) -> bool {
+        !self.d2d() && self.flags & 1 != 0
+    }
+}
+
+/// Batch of copies read from a chain of descriptors in host or device memory, see `docs/gluon.md`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ChainCommand {
+    pub sid: u8,
+    /// Address of the first descriptor.
+    pub desc: u64,
+    /// Bit 0: the descriptors are in host memory instead of device memory.
+    pub flags: u8,
+}
+
+impl ChainCommand {
+    pub fn host_desc(&self) -> bool {
+        self.flags & 1 != 0
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SetCommand {
+    pub sid: u8,
+    pub dst: u64,
+    /// Element repeated over the range; only the low `width()` bytes are used.
+    pub value: u64,
+    pub len: u32,
+    /// Bits 0-1: element width, 0 = 32-bit, 1 = 8-bit, 2 = 16-bit, 3 = 64-bit.
+    pub flags: u8,
+}
+
+impl SetCommand {
+    pub fn width(&self) -> usize {
+        match self.flags & 3 {
+            0 => 4,
+            1 => 1,
+            2 => 2,
+            _ => 8,
+        }
+    }
+
+    /// Fill byte at `offset` from `dst`. Elements start at `dst`, so a length that is not a multiple of
+    /// the width ends in a partial element.
+    pub fn byte_at(&self, offset: usize) -> u8 {
+        self.value.to_le_bytes()[offset % self.width()]
+    }
+}
+
+/// Control/status command. The CS engine does not interpret the arguments yet.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CsrCommand {
+    pub sid: u8,
+    pub args: [u8; COMMAND_ARGS_BYTES],
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FenceCommand {
+    pub sid: u8,
+    pub args: [u8; COMMAND_ARGS_BYTES],
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum MemOp {
+    #[default]
+    COPY,
+    SET,
+    COPY2D,
+    COPY3D,
+    CHAIN,
+}
+
+impl TryFrom<u8> for MemOp {
+    type Error = DecodeError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(MemOp::COPY),
+            1 => Ok(MemOp::SET),
+            2 => Ok(MemOp::COPY2D),
+            3 => Ok(MemOp::COPY3D),
+            4 => Ok(MemOp::CHAIN),
+            _ => Err(DecodeError::UnknownMemOp(value)),
+        }
+    }
+}
+
+/// A 32-byte command header: `{ CMD_ID, CMD_TYPE, 30 bytes of arguments }`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GluonCommand {
+    KERNEL(KernelCommand),
+    COPY(CopyCommand),
+    SET(SetCommand),
+    STRIDED(StridedCommand),
+    CHAIN(ChainCommand),
+    CSR(CsrCommand),
+    FENCE(FenceCommand),
+}
+
+impl GluonCommand {
+    pub fn decode(bytes: &[u8; COMMAND_BYTES]) -> Result<Self, DecodeError> {
+        let sid = bytes[0];
+        match bytes[1] {
+            0 => Ok(GluonCommand::KERNEL(KernelCommand {
+                sid,
+                host_addr: read_u64(bytes, 2),
+                sz: read_u32(bytes, 10),
+                gpu_addr: read_u64(bytes, 14),
+            })),
+            1 => match MemOp::try_from(bytes[2])? {
+                MemOp::COPY => Ok(GluonCommand::COPY(CopyCommand {
+                    sid,
+                    src: read_u64(bytes, 3),
+                    dst: read_u64(bytes, 11),
+                    len: read_u32(bytes, 19),
+                    flags: bytes[23],
+                })),
+                MemOp::SET => Ok(GluonCommand::SET(SetCommand {
+                    sid,
+                    dst: read_u64(bytes, 3),
+                    value: read_u32(bytes, 11) as u64 | (read_u32(bytes, 20) as u64) << 32,
+                    len: read_u32(bytes, 15),
+                    flags: bytes[19],
+                })),
+                op @ (MemOp::COPY2D | MemOp::COPY3D) => Ok(GluonCommand::STRIDED(StridedCommand {
+                    sid,
+                    dims: if op == MemOp::COPY2D { 2 } else { 3 },
+                    desc: read_u64(bytes, 3),
+                    flags: bytes[11],
+                })),
+                MemOp::CHAIN => Ok(GluonCommand::CHAIN(ChainCommand {
+                    sid,
+                    desc: read_u64(bytes, 3),
+                    flags: bytes[11],
+                })),
+            },
+            2 => Ok(GluonCommand::CSR(CsrCommand {
+                sid,
+                args: bytes[2..].try_into().expect("impossible"),
+            })),
+            3 => Ok(GluonCommand::FENCE(FenceCommand {
+                sid,
+                args: bytes[2..].try_into().expect("impossible"),
+            })),
+            other => Err(DecodeError::UnknownCmdType(other)),
+        }
+    }
+
+    pub fn encode(&self) -> [u8; COMMAND_BYTES] {
+        let mut bytes = [0u8; COMMAND_BYTES];
+        bytes[0] = self.sid();
+        match self {
+            GluonCommand::KERNEL(cmd) => {
+                bytes[1] = 0;
+                write_u64(&mut bytes, 2, cmd.host_addr);
+                write_u32(&mut bytes, 10, cmd.sz);
+                write_u64(&mut bytes, 14, cmd.gpu_addr);
+            }
+            GluonCommand::COPY(cmd) => {
+                bytes[1] = 1;
+                bytes[2] = MemOp::COPY as u8;
+                write_u64(&mut bytes, 3, cmd.src);
+                write_u64(&mut bytes, 11, cmd.dst);
+                write_u32(&mut bytes, 19, cmd.len);
+                bytes[23] = cmd.flags;
+            }
+            GluonCommand::SET(cmd) => {
+                bytes[1] = 1;
+                bytes[2] = MemOp::SET as u8;
+                write_u64(&mut bytes, 3, cmd.dst);
+                write_u32(&mut bytes, 11, cmd.value as u32);
+                write_u32(&mut bytes, 15, cmd.len);
+                bytes[19] = cmd.flags;
+                write_u32(&mut bytes, 20, (cmd.value >> 32) as u32);
+            }
+            GluonCommand::STRIDED(cmd) => {
+                bytes[1] = 1;
+                bytes[2] = if cmd.dims == 2 { MemOp::COPY2D } else { MemOp::COPY3D } as u8;
+                write_u64(&mut bytes, 3, cmd.desc);
+                bytes[11] = cmd.flags;
+            }
+            GluonCommand::CHAIN(cmd) => {
+                bytes[1] = 1;
+                bytes[2] = MemOp::CHAIN as u8;
+                write_u64(&mut bytes, 3, cmd.desc);
+                bytes[11] = cmd.flags;
+            }
+            GluonCommand::CSR(cmd) => {
+                bytes[1] = 2;
+                bytes[2..].copy_from_slice(&cmd.args);
+            }
+            GluonCommand::FENCE(cmd) => {
+                bytes[1] = 3;
+                bytes[2..].copy_from_slice(&cmd.args);
+            }
+        }
+        bytes
+    }
+
+    pub fn sid(&self) -> u8 {
+        match self {
+            GluonCommand::KERNEL(cmd) => cmd.sid,
+            GluonCommand::COPY(cmd) => cmd.sid,
+            GluonCommand::SET(cmd) => cmd.sid,
+            GluonCommand::STRIDED(cmd) => cmd.sid,
+            GluonCommand::CHAIN(cmd) => cmd.sid,
+            GluonCommand::CSR(cmd) => cmd.sid,
+            GluonCommand::FENCE(cmd) => cmd.sid,
+        }
+    }
+
+    pub fn set_sid(&mut self, sid: u8) {
+        match self {
+            GluonCommand::KERNEL(cmd) => cmd.sid = sid,
+            GluonCommand::COPY(cmd) => cmd.sid = sid,
+            GluonCommand::SET(cmd) => cmd.sid = sid,
+            GluonCommand::STRIDED(cmd) => cmd.sid = sid,
+            GluonCommand::CHAIN(cmd) => cmd.sid = sid,
+            GluonCommand::CSR(cmd) => cmd.sid = sid,
+            GluonCommand::FENCE(cmd) => cmd.sid = sid,
+        }
+    }
+
+    pub fn cmd_type(&self) -> CmdType {
+        match self {
+            GluonCommand::KERNEL(_) => CmdType::KERNEL,
+            GluonCommand::COPY(_) | GluonCommand::SET(_) | GluonCommand::STRIDED(_) | GluonCommand::CHAIN(_) => {
+                CmdType::MEM
+            }
+            GluonCommand::CSR(_) => CmdType::CSR,
+            GluonCommand::FENCE(_) => CmdType::FENCE,
+        }
+    }
+}
+
+/// A 32-byte response: `{ CMD_ID, CODE, 30 bytes of code-specific data }`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GluonEvent {
+    OK { sid: u8 },
+    EXECUTION { sid: u8, pc: u32, warp_id: u32 },
+    /// Returns command credits to the host.
+    CREDIT { credits: u32 },
+    /// First message after the handshake: server protocol version, initial command credits, and the
+    /// client's context: how many stream ids it may use and the DRAM window it may address.
+    HELLO {
+        version: u32,
+        credits: u32,
+        streams: u8,
+        dram_base: u64,
+        dram_size: u64,
+    },
+    /// The header could not be decoded and was dropped by the frontend.
+    INVALID { sid: u8, err: DecodeError },
+    /// A DMA or DRAM access of the command failed.
+    MEM { sid: u8, err: MemErr },
+    /// The simulator stopped with `err` before the command could complete.
+    SIM { sid: u8, err: SimErr },
+    /// Last message before the server shuts down: the simulator stopped with `err` after `cycles` cycles.
+    FATAL { err: SimErr, cycles: u64 },
+    /// The kernel ran out of its budget of `cycles` cycles and was killed.
+    TIMEOUT { sid: u8, cycles: u64 },
+    /// Sent instead of `HELLO` to a client whose protocol version differs from the server's `version`;
+    /// the server hangs up after it. Its layout must never change.
+    REJECT { version: u32 },
+}
+
+impl GluonEvent {
+    pub fn from_kernel_err(sid: u8, err: Result<(), ExecErr>) -> Self {
+        match err {
+            Ok(()) => GluonEvent::OK { sid },
+            Err(err) => GluonEvent::EXECUTION {
+                sid,
+                pc: err.pc,
+                warp_id: err.warp_id as u32,
+            },
+        }
+    }
+
+    pub fn from_mem_err(sid: u8, err: Result<(), MemErr>) -> Self {
+        match err {
+            Ok(()) => GluonEvent::OK { sid },
+            Err(err) => GluonEvent::MEM { sid, err },
+        }
+    }
+
+    pub fn decode(bytes: &[u8; EVENT_BYTES]) -> Result<Self, DecodeError> {
+        let sid = bytes[0];
+        match bytes[1] {
+            0 => Ok(GluonEvent::OK { sid }),
+            1 => Ok(GluonEvent::EXECUTION {
+                sid,
+                pc: read_u32(bytes, 2),
+                warp_id: read_u32(bytes, 6),
+            }),
+            2 => Ok(GluonEvent::CREDIT {
+                credits: read_u32(bytes, 2),
+            }),
+            3 => Ok(GluonEvent::HELLO {
+                version: read_u32(bytes, 2),
+                credits: read_u32(bytes, 6),
+                streams: bytes[10],
+                dram_base: read_u64(bytes, 11),
+                dram_size: read_u64(bytes, 19),
+            }),
+            4 => DecodeError::from_bytes([bytes[2], bytes[3]])
+                .map(|err| GluonEvent::INVALID { sid, err })
+                .ok_or(DecodeError::UnknownEventCode(bytes[1])),
+            5 => mem_err_code_from_u8(bytes[2])
+                .map(|code| GluonEvent::MEM {
+                    sid,
+                    err: MemErr {
+                        code,
+                        addr: read_u64(bytes, 3),
+                    },
+                })
+                .ok_or(DecodeError::UnknownEventCode(bytes[1])),
+            6 => sim_err_from_u8(bytes[2])
+                .map(|err| GluonEvent::SIM { sid, err })
+                .ok_or(DecodeError::UnknownEventCode(bytes[1])),
+            7 => sim_err_from_u8(bytes[2])
+                .map(|err| GluonEvent::FATAL {
+                    err,
+                    cycles: read_u64(bytes, 3),
+                })
+                .ok_or(DecodeError::UnknownEventCode(bytes[1])),
+            8 => Ok(GluonEvent::TIMEOUT {
+                sid,
+                cycles: read_u64(bytes, 2),
+            }),
+            9 => Ok(GluonEvent::REJECT {
+                version: read_u32(bytes, 2),
+            }),
+            other => Err(DecodeError::UnknownEventCode(other)),
+        }
+    }
+
+    pub fn encode(&self) -> [u8; EVENT_BYTES] {
+        let mut bytes = [0u8; EVENT_BYTES];
+        match *self {
+            GluonEvent::OK { sid } => {
+                bytes[0] = sid;
+                bytes[1] = 0;
+            }
+            GluonEvent::EXECUTION { sid, pc, warp_id } => {
+                bytes[0] = sid;
+                bytes[1] = 1;
+                write_u32(&mut bytes, 2, pc);
+                write_u32(&mut bytes, 6, warp_id);
+            }
+            GluonEvent::CREDIT { credits } => {
+                bytes[1] = 2;
+                write_u32(&mut bytes, 2, credits);
+            }
+            GluonEvent::HELLO {
+                version,
+                credits,
+                streams,
+                dram_base,
+                dram_size,
+            } => {
+                bytes[1] = 3;
+                write_u32(&mut bytes, 2, version);
+                write_u32(&mut bytes, 6, credits);
+                bytes[10] = streams;
+                write_u64(&mut bytes, 11, dram_base);
+                write_u64(&mut bytes, 19, dram_size);
+            }
+            GluonEvent::INVALID { sid, err } => {
+                bytes[0] = sid;
+                bytes[1] = 4;
+                bytes[2..4].copy_from_slice(&err.to_bytes());
+            }
+            GluonEvent::MEM { sid, err } => {
+                bytes[0] = sid;
+                bytes[1] = 5;
+                bytes[2] = err.code as u8;
+                write_u64(&mut bytes, 3, err.addr);
+            }
+            GluonEvent::SIM { sid, err } => {
+                bytes[0] = sid;
+                bytes[1] = 6;
+                bytes[2] = err as u8;
+            }
+            GluonEvent::FATAL { err, cycles } => {
+                bytes[1] = 7;
+                bytes[2] = err as u8;
+                write_u64(&mut bytes, 3, cycles);
+            }
+            GluonEvent::TIMEOUT { sid, cycles } => {
+                bytes[0] = sid;
+                bytes[1] = 8;
+                write_u64(&mut bytes, 2, cycles);
+            }
+            GluonEvent::REJECT { version } => {
+                bytes[1] = 9;
+                write_u32(&mut bytes, 2, version);
+            }
+        }
+        bytes
+    }
+
+    /// Stream the event completes, if it is a completion rather than a flow-control message.
+    pub fn sid(&self) -> Option<u8> {
+        match *self {
+            GluonEvent::OK { sid }
+            | GluonEvent::EXECUTION { sid, .. }
+            | GluonEvent::INVALID { sid, .. }
+            | GluonEvent::MEM { sid, .. }
+            | GluonEvent::SIM { sid, .. }
+            | GluonEvent::TIMEOUT { sid, .. } => Some(sid),
+            GluonEvent::CREDIT { .. }
+            | GluonEvent::HELLO { .. }
+            | GluonEvent::FATAL { .. }
+            | GluonEvent::REJECT { .. } => None,
+        }
+    }
+
+    pub fn set_sid(&mut self, new_sid: u8) {
+        match self {
+            GluonEvent::OK { sid }
+            | GluonEvent::EXECUTION { sid, .. }
+            | GluonEvent::INVALID { sid, .. }
+            | GluonEvent::MEM { sid, .. }
+            | GluonEvent::SIM { sid, .. }
+            | GluonEvent::TIMEOUT { sid, .. } => *sid = new_sid,
+            GluonEvent::CREDIT { .. }
+            | GluonEvent::HELLO { .. }
+            | GluonEvent::FATAL { .. }
+            | GluonEvent::REJECT { .. } => {}
+        }
+    }
+
+    pub fn to_exec_err(&self) -> Option<ExecErr> {
+        match *self {
+            GluonEvent::EXECUTION { pc, warp_id, .. } => Some(ExecErr {
+                pc,
+                warp_id: warp_id as usize,
+                message: None,
+            }),
+            _ => None,
+        }
+    }
+}
+
+fn mem_err_code_from_u8(value: u8) -> Option<MemErrCode> {
+    match value {
+        0 => Some(MemErrCode::HOST),
+        1 => Some(MemErrCode::RANGE),
+        2 => Some(MemErrCode::MISALIGNED),
+        3 => Some(MemErrCode::ZERO),
+        _ => None,
+    }
+}
+
+fn sim_err_from_u8(value: u8) -> Option<SimErr> {
+    match value {
+        0 => Some(SimErr::TIMEOUT),
+        1 => Some(SimErr::EXECUTION),
+        _ => None,
+    }
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().expect("impossible"))
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().expect("impossible"))
+}
+
+fn write_u32(bytes: &mut [u8], offset: usize, value: u32) {
+    bytes[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+fn write_u64(bytes: &mut [u8], offset: usize, value: u64) {
+    bytes[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roundtrip_command(cmd: GluonCommand) {
+        assert_eq!(GluonCommand::decode(&cmd.encode()), Ok(cmd));
+    }
+
+    fn roundtrip_event(event: GluonEvent) {
+        assert_eq!(GluonEvent::decode(&event.encode()), Ok(event));
+    }
+
+    fn args() -> [u8; COMMAND_ARGS_BYTES] {
+        std::array::from_fn(|i| i as u8 + 1)
+    }
+
+    #[test]
+    fn command_roundtrip() {
+        roundtrip_command(GluonCommand::KERNEL(KernelCommand {
+            sid: 3,
+            host_addr: 0x1234_5678_9abc_def0,
+            sz: 0xdead_beef,
+            gpu_addr: 0x1_0000_0040,
+        }));
+        for flags in [0, 1] {
+            roundtrip_command(GluonCommand::COPY(CopyCommand {
+                sid: 7,
+                src: 0xffff_ffff_0000_0010,
+                dst: 0x2_0000_0000,
+                len: 4096,
+                flags,
+            }));
+        }
+        for dims in [2, 3] {
+            roundtrip_command(GluonCommand::STRIDED(StridedCommand {
+                sid: 5,
+                dims,
+                desc: 0x1_2345_6780,
+                flags: 2,
+            }));
+        }
+        for flags in [0, 1] {
+            let chain = ChainCommand {
+                sid: 4,
+                desc: 0x7fff_0000_1000,
+                flags,
+            };
+            assert_eq!(chain.host_desc(), flags == 1);
+            roundtrip_command(GluonCommand::CHAIN(chain));
+        }
+        roundtrip_command(GluonCommand::CSR(CsrCommand { sid: 1, args: args() }));
+        roundtrip_command(GluonCommand::FENCE(FenceCommand { sid: 255, args: args() }));
+    }
+
+    #[test]
+    fn event_roundtrip() {
+        roundtrip_event(GluonEvent::OK { sid: 9 });
+        roundtrip_event(GluonEvent::EXECUTION {
+            sid: 2,
+            pc: 0x8000_0040,
+            warp_id: 5,
+        });
+        roundtrip_event(GluonEvent::CREDIT { credits: 0x0102_0304 });
+        roundtrip_event(GluonEvent::HELLO {
+            version: PROTOCOL_VERSION,
+            credits: 16,
+            streams: 4,
+            dram_base: 0x1_0000_0000,
+            dram_size: 0x1_0000_0000,
+        });
+        for err in [
+            DecodeError::UnknownCmdType(9),
+            DecodeError::UnknownMemOp(200),
+            DecodeError::UnknownEventCode(77),
+            DecodeError::UnknownStream(31),
+        ] {
+            roundtrip_event(GluonEvent::INVALID { sid: 4, err });
+        }
+        roundtrip_event(GluonEvent::REJECT { version: 0x0a0b_0c0d });
+    }
+
+    #[test]
+    fn copy_direction() {
+        let copy = |flags| CopyCommand { flags, ..CopyCommand::default() };
+        assert!(!copy(0).d2h() && !copy(0).d2d());
+        assert!(copy(1).d2h() && !copy(1).d2d());
+        // Bit 1 overrides bit 0.
+        for flags in [2, 3] {
+            assert!(copy(flags).d2d() && !copy(flags).d2h());
+            roundtrip_command(GluonCommand::COPY(CopyCommand {
+                sid: 2,
+                src: 0x1_0000_0000,
+                dst: 0x1_0000_1000,
+                len: 64,
+                flags,
+            }));
+        }
+    }
+
+    #[test]
+    fn set_width() {
+        let set = |flags| SetCommand {
+            value: 0x1122_3344_5566_7788,
+            flags,
+            ..SetCommand::default()
+        };
+        // 0 keeps the 32-bit fill of earlier clients.
+        assert_eq!(set(0).width(), 4);
+        assert_eq!(set(1).width(), 1);
+        assert_eq!(set(2).width(), 2);
+        assert_eq!(set(3).width(), 8);
+        assert_eq!((0..6).map(|i| set(0).byte_at(i)).collect::<Vec<_>>(), [0x88, 0x77, 0x66, 0x55, 0x88, 0x77]);
+        assert_eq!(set(3).byte_at(7), 0x11);
+        for flags in 0..4 {
+            roundtrip_command(GluonCommand::SET(SetCommand {
+                sid: 1,
+                dst: 0x1_0000_0003,
+                len: 13,
+                ..set(flags)
+            }));
+        }
+    }
+
+    #[test]
+    fn error_event_roundtrip() {
+        for code in [MemErrCode::HOST, MemErrCode::RANGE, MemErrCode::MISALIGNED, MemErrCode::ZERO] {
+            roundtrip_event(GluonEvent::MEM {
+                sid: 6,
+                err: MemErr {
+                    code,
+                    addr: 0xffff_0000_1234_5678,
+                },
+            });
+        }
+        for err in [SimErr::TIMEOUT, SimErr::EXECUTION] {
+            roundtrip_event(GluonEvent::SIM { sid: 1, err });
+            roundtrip_event(GluonEvent::FATAL {
+                err,
+                cycles: u64::MAX - 1,
+            });
+        }
+    }
+
+    #[test]
+    fn timeout_event() {
+        let event = GluonEvent::TIMEOUT {
+            sid: 3,
+            cycles: 0x1_0000_0400,
+        };
+        let bytes = event.encode();
+        assert_eq!(bytes[..2], [3, 8]);
+        assert_eq!(read_u64(&bytes, 2), 0x1_0000_0400);
+        roundtrip_event(event);
+    }
+
+    #[test]
+    fn command_rejected() {
+        let mut bytes = [0u8; COMMAND_BYTES];
+        bytes[1] = 4;
+        assert_eq!(GluonCommand::decode(&bytes), Err(DecodeError::UnknownCmdType(4)));
+        bytes[1] = 0xff;
+        assert_eq!(GluonCommand::decode(&bytes), Err(DecodeError::UnknownCmdType(0xff)));
+        bytes[1] = 1;
+        bytes[2] = 5;
+        assert_eq!(GluonCommand::decode(&bytes), Err(DecodeError::UnknownMemOp(5)));
+        bytes[2] = 0xff;
+        assert_eq!(GluonCommand::decode(&bytes), Err(DecodeError::UnknownMemOp(0xff)));
+    }
+
+    #[test]
+    fn event_rejected() {
+        let mut bytes = [0u8; EVENT_BYTES];
+        bytes[1] = 10;
+        assert_eq!(GluonEvent::decode(&bytes), Err(DecodeError::UnknownEventCode(10)));
+        bytes[1] = 4;
+        bytes[2] = 4;
+        assert_eq!(GluonEvent::decode(&bytes), Err(DecodeError::UnknownEventCode(4)));
+        bytes[1] = 5;
+        for code in [4, 0xff] {
+            bytes[2] = code;
+            assert_eq!(GluonEvent::decode(&bytes), Err(DecodeError::UnknownEventCode(5)));
+        }
+        for code in [6, 7] {
+            bytes[1] = code;
+            bytes[2] = 2;
+            assert_eq!(GluonEvent::decode(&bytes), Err(DecodeError::UnknownEventCode(code)));
+        }
+    }
+}
@@ -1,19 +1,16 @@
 use std::collections::VecDeque;
 
 #[derive(Debug, Default, Clone)]
-pub struct Queue<T>
-where
-    T: Default,
-{
+pub struct Queue<T> {
     data: VecDeque<T>,
     cap: usize,
 }
 
-impl<T: Default> Queue<T> {
+impl<T> Queue<T> {
     pub fn new(cap: usize) -> Self {
         Queue {
             data: VecDeque::with_capacity(cap),
-            cap: cap,
+            cap,
         }
     }
 
@@ -22,7 +19,11 @@ impl<T: Default> Queue<T> {
     }
 
     pub fn empty(&self) -> bool {
-        self.data.len() == 0
+        self.data.is_empty()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.empty()
     }
 
     pub fn push(&mut self, item: T) -> bool {
@@ -46,7 +47,15 @@ impl<T: Default> Queue<T> {
         self.data.get_mut(idx)
     }
 
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.data.iter()
+    }
+
     pub fn len(&self) -> usize {
         self.data.len()
     }
+
+    pub fn cap(&self) -> usize {
+        self.cap
+    }
 }
@@ -0,0 +1,134 @@
+use std::error::Error;
+use std::fs;
+
+use serde::Deserialize;
+
+use crate::context::Context;
+use crate::glug::decode_dispatch::DecodeDispatchConfig;
+use crate::glug::dma::DMAConfig;
+use crate::glug::dram::DRAMConfig;
+use crate::glug::engine::EngineConfig;
+use crate::glug::engines::cs_engine::CSEngineConfig;
+use crate::glug::engines::kernel_engine::KernelEngineConfig;
+use crate::glug::engines::mem_engine::MemEngineConfig;
+use crate::glug::frontend::FrontendConfig;
+use crate::glug::glug::GLUGConfig;
+use crate::top::{SimConfig, TopConfig};
+
+/// The sections of `config.toml`, shared by the server and the trace replayer.
+#[derive(Deserialize)]
+pub struct Config {
+    pub server: ServerConfig,
+    #[serde(default)]
+    pub sim: SimConfig,
+    #[serde(default)]
+    pub glug: GLUGConfig,
+    #[serde(default)]
+    pub frontend: FrontendConfig,
+    #[serde(default, rename = "decode_dispatch")]
+    pub decode_dispatch: DecodeDispatchConfig,
+    #[serde(default)]
+    pub engine: EngineConfig,
+    #[serde(default, rename = "kernel_engine")]
+    pub kernel_engine: KernelEngineConfig,
+    #[serde(default, rename = "mem_engine")]
+    pub mem_engine: MemEngineConfig,
+    #[serde(default, rename = "cs_engine")]
+    pub cs_engine: CSEngineConfig,
+    #[serde(default)]
+    pub dma: DMAConfig,
+    #[serde(default)]
+    pub dram: DRAMConfig,
+}
+
+#[derive(Deserialize)]
+pub struct ServerConfig {
+    pub socket_path: String,
+    /// Clients served concurrently. Stream ids are split evenly between them.
+    #[serde(default = "default_max_clients")]
+    pub max_clients: usize,
+    /// Start of the device memory handed out to clients; client `i` owns
+    /// `dram_base + i * dram_bytes_per_client` onwards.
+    #[serde(default)]
+    pub dram_base: u64,
+    #[serde(default = "default_dram_bytes_per_client")]
+    pub dram_bytes_per_client: u64,
+    /// Record every command, event and host memory read into this file, see `gluon::trace`.
+    #[serde(default)]
+    pub trace_path: Option<String>,
+}
+
+fn default_max_clients() -> usize {
+    1
+}
+
+fn default_dram_bytes_per_client() -> u64 {
+    1 << 32
+}
+
+impl ServerConfig {
+    /// Split the device between `max_clients` contexts.
+    pub fn contexts(&self, num_streams: usize) -> Result<Vec<Context>, Box<dyn Error>> {
+        let streams = num_streams.checked_div(self.max_clients).unwrap_or(0);
+        if streams == 0 || num_streams > u8::MAX as usize + 1 {
+            return Err(format!(
+                "cannot split {num_streams} streams between {} clients",
+                self.max_clients
+            )
+            .into());
+        }
+        (0..self.max_clients)
+            .map(|id| {
+                let dram_start = (id as u64)
+                    .checked_mul(self.dram_bytes_per_client)
+                    .and_then(|offset| offset.checked_add(self.dram_base));
+                let dram_end = dram_start.and_then(|start| start.checked_add(self.dram_bytes_per_client));
+                match (dram_start, dram_end) {
+                    (Some(start), Some(end)) => Ok(Context {
+                        id,
+                        sids: (id * streams) as u8..((id + 1) * streams) as u8,
+                        dram: start..end,
+                    }),
+                    _ => Err(format!("DRAM window of client {id} overflows the device address space").into()),
+                }
+            })
+            .collect()
+    }
+}
+
+impl Config {
+    pub fn parse(text: &str) -> Result<Config, toml::de::Error> {
+        toml::from_str(text)
+    }
+
+    pub fn into_server_and_top(self) -> (ServerConfig, TopConfig) {
+        let mut glug_config = self.glug;
+        let mut engine_config = self.engine;
+        engine_config.kernel_engine_config = self.kernel_engine;
+        engine_config.mem_engine_config = self.mem_engine;
+        engine_config.cs_engine_config = self.cs_engine;
+
+        glug_config.frontend = self.frontend;
+        glug_config.decode_dispatch = self.decode_dispatch;
+        glug_config.engine = engine_config;
+        glug_config.dma = self.dma;
+        glug_config.dram = self.dram;
+        // An invalid split is reported when the server builds its context pool.
+        glug_config.contexts = self.server.contexts(glug_config.stream.num_sq).unwrap_or_default();
+
+        (
+            self.server,
+            TopConfig {
+                sim: self.sim,
+                glug: glug_config,
+            },
+        )
+    }
+}
+
+/// The parsed configuration along with its text, which traces embed.
+pub fn load_config(path: &str) -> Result<(Config, String), Box<dyn Error>> {
+    let contents = fs::read_to_string(path)?;
+    let config = Config::parse(&contents)?;
+    Ok((config, contents))
+}
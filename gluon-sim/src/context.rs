@@ -0,0 +1,87 @@
+use std::ops::Range;
+
+use crate::common::base::{MemErr, MemErrCode};
+use crate::common::protocol::{DecodeError, GluonCommand, GluonEvent, COMMAND_BYTES};
+use crate::glug::engines::mem_engine::{CHAIN_DESC_BYTES, STRIDED_DESC_BYTES};
+
+/// A client's slice of the simulator: the stream ids its commands map onto and the DRAM window its
+/// commands may address.
+///
+/// Clients number their streams from 0; the context translates them to and from the global stream ids
+/// used inside GLUG. Device addresses are not translated, the client is told its window at handshake and
+/// allocates inside it.
+#[derive(Debug, Clone)]
+pub struct Context {
+    pub id: usize,
+    pub sids: Range<u8>,
+    pub dram: Range<u64>,
+}
+
+impl Context {
+    pub fn streams(&self) -> u8 {
+        self.sids.end - self.sids.start
+    }
+
+    pub fn to_global_sid(&self, sid: u8) -> Option<u8> {
+        (sid < self.streams()).then(|| self.sids.start + sid)
+    }
+
+    pub fn to_local_sid(&self, sid: u8) -> u8 {
+        assert!(self.sids.contains(&sid), "Context: sid {} not owned by context {}", sid, self.id);
+        sid - self.sids.start
+    }
+
+    /// Decode a header from the client and move it onto the context's streams. Commands that cannot be
+    /// decoded, name a stream the client does not own, or reach outside its DRAM window come back as the
+    /// error event to report instead.
+    pub fn admit(&self, buffer: &[u8; COMMAND_BYTES]) -> Result<GluonCommand, GluonEvent> {
+        let fallback_sid = self.to_global_sid(buffer[0]).unwrap_or(self.sids.start);
+        let mut command = GluonCommand::decode(buffer).map_err(|err| GluonEvent::INVALID { sid: fallback_sid, err })?;
+        let sid = self.to_global_sid(command.sid()).ok_or(GluonEvent::INVALID {
+            sid: fallback_sid,
+            err: DecodeError::UnknownStream(command.sid()),
+        })?;
+        command.set_sid(sid);
+        self.check_command(&command)
+            .map_err(|err| GluonEvent::MEM { sid, err })?;
+        Ok(command)
+    }
+
+    /// Check every device address range in the command against the context's DRAM window.
+    /// Kernel payloads are only checked where they are copied to; the cores themselves can still
+    /// reach all of DRAM. The rows of a strided copy are read from its descriptor, so the memory
+    /// engine checks them as it copies; likewise for chains, whose first device-memory descriptor is
+    /// also checked here so a bad chain is rejected before it is queued.
+    pub fn check_command(&self, cmd: &GluonCommand) -> Result<(), MemErr> {
+        match cmd {
+            GluonCommand::KERNEL(cmd) => self.check_dram(cmd.gpu_addr, cmd.sz as u64),
+            GluonCommand::COPY(cmd) if cmd.d2d() => {
+                self.check_dram(cmd.src, cmd.len as u64)?;
+                self.check_dram(cmd.dst, cmd.len as u64)
+            }
+            GluonCommand::COPY(cmd) => {
+                let device_addr = if cmd.d2h() { cmd.src } else { cmd.dst };
+                self.check_dram(device_addr, cmd.len as u64)
+            }
+            GluonCommand::SET(cmd) => self.check_dram(cmd.dst, cmd.len as u64),
+            GluonCommand::STRIDED(cmd) => self.check_dram(cmd.desc, STRIDED_DESC_BYTES as u64),
+            GluonCommand::CHAIN(cmd) if cmd.host_desc() => Ok(()),
+            GluonCommand::CHAIN(cmd) => self.check_dram(cmd.desc, CHAIN_DESC_BYTES as u64),
+            GluonCommand::CSR(_) | GluonCommand::FENCE(_) => Ok(()),
+        }
+    }
+
+    pub fn check_dram(&self, addr: u64, len: u64) -> Result<(), MemErr> {
+        let in_window = addr
+            .checked_add(len)
+            .is_some_and(|end| addr >= self.dram.start && end <= self.dram.end);
+        if in_window {
+            Ok(())
+        } else {
+            Err(MemErr {
+                code: MemErrCode::RANGE,
+                addr,
+            })
+        }
+    }
+}
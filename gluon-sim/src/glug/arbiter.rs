@@ -0,0 +1,148 @@
+use serde::Deserialize;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum ArbiterPolicy {
+    /// Round-robin: start after the requester granted last.
+    #[default]
+    RR,
+    /// Fixed priority: lower requester indices first.
+    FIXED,
+    /// Longest waiting requester first, lower index on ties.
+    AGE,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct ArbiterConfig {
+    pub policy: ArbiterPolicy,
+    /// Requests granted per cycle.
+    pub ports: usize,
+}
+
+impl Default for ArbiterConfig {
+    fn default() -> Self {
+        Self {
+            policy: ArbiterPolicy::RR,
+            ports: 1,
+        }
+    }
+}
+
+/// Per-requester arbitration counters.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ArbiterStats {
+    pub grants: u64,
+    /// Cycles spent requesting without being granted.
+    pub wait_cycles: u64,
+}
+
+/// Grants up to `ports` of a fixed set of requesters per cycle in the order set by the policy.
+pub struct Arbiter {
+    config: ArbiterConfig,
+    rr_idx: usize,
+    /// Cycle each requester started waiting, if it is waiting.
+    waiting_since: Vec<Option<u64>>,
+    stats: Vec<ArbiterStats>,
+}
+
+impl Arbiter {
+    pub fn new(config: &ArbiterConfig, num_requesters: usize) -> Self {
+        assert!(config.ports > 0, "Arbiter: ports must be non-zero");
+        Arbiter {
+            config: *config,
+            rr_idx: 0,
+            waiting_since: vec![None; num_requesters],
+            stats: vec![ArbiterStats::default(); num_requesters],
+        }
+    }
+
+    /// Arbitrate one cycle. `requesting` tells which requesters want service; `grant` is offered them in
+    /// priority order and returns whether the request could be served, e.g. because the resource it needs
+    /// is free. Returns the requesters granted.
+    pub fn arbitrate(
+        &mut self,
+        cycle: u64,
+        requesting: impl Fn(usize) -> bool,
+        mut grant: impl FnMut(usize) -> bool,
+    ) -> Vec<usize> {
+        let num_requesters = self.waiting_since.len();
+        let mut order = (0..num_requesters).filter(|idx| requesting(*idx)).collect::<Vec<_>>();
+        order.iter().for_each(|idx| {
+            self.waiting_since[*idx].get_or_insert(cycle);
+        });
+        match self.config.policy {
+            ArbiterPolicy::RR => {
+                order.sort_by_key(|idx| (idx + num_requesters - self.rr_idx) % num_requesters)
+            }
+            ArbiterPolicy::FIXED => {}
+            ArbiterPolicy::AGE => order.sort_by_key(|idx| (self.waiting_since[*idx], *idx)),
+        }
+
+        let mut granted = Vec::new();
+        for idx in order {
+            if granted.len() < self.config.ports && grant(idx) {
+                self.waiting_since[idx] = None;
+                self.stats[idx].grants += 1;
+                granted.push(idx);
+            } else {
+                self.stats[idx].wait_cycles += 1;
+            }
+        }
+        if let Some(last) = granted.last() {
+            self.rr_idx = (last + 1) % num_requesters;
+        }
+        granted
+    }
+
+    pub fn stats(&self) -> &[ArbiterStats] {
+        &self.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn arbiter(policy: ArbiterPolicy, ports: usize) -> Arbiter {
+        Arbiter::new(&ArbiterConfig { policy, ports }, 4)
+    }
+
+    #[test]
+    fn rr_rotates_past_last_grant() {
+        let mut arb = arbiter(ArbiterPolicy::RR, 1);
+        let grants = (0..5)
+            .flat_map(|cycle| arb.arbitrate(cycle, |_| true, |_| true))
+            .collect::<Vec<_>>();
+        assert_eq!(grants, vec![0, 1, 2, 3, 0]);
+        // Skips requesters that are not requesting, resuming after the last grant.
+        assert_eq!(arb.arbitrate(5, |idx| idx != 1, |_| true), vec![2]);
+    }
+
+    #[test]
+    fn fixed_prefers_low_indices() {
+        let mut arb = arbiter(ArbiterPolicy::FIXED, 2);
+        assert_eq!(arb.arbitrate(0, |_| true, |_| true), vec![0, 1]);
+        assert_eq!(arb.arbitrate(1, |_| true, |_| true), vec![0, 1]);
+        assert_eq!(arb.arbitrate(2, |idx| idx >= 2, |_| true), vec![2, 3]);
+        assert_eq!(arb.stats()[3].grants, 1);
+        assert_eq!(arb.stats()[3].wait_cycles, 2);
+    }
+
+    #[test]
+    fn age_prefers_longest_waiting() {
+        let mut arb = arbiter(ArbiterPolicy::AGE, 1);
+        // 3 starts waiting at cycle 0, the others at cycle 1.
+        assert_eq!(arb.arbitrate(0, |idx| idx == 3, |_| false), vec![]);
+        assert_eq!(arb.arbitrate(1, |_| true, |_| true), vec![3]);
+        // Ties go to the lower index.
+        assert_eq!(arb.arbitrate(2, |_| true, |_| true), vec![0]);
+        assert_eq!(arb.arbitrate(3, |_| true, |_| true), vec![1]);
+    }
+
+    #[test]
+    fn refused_grant_offers_next() {
+        let mut arb = arbiter(ArbiterPolicy::FIXED, 1);
+        assert_eq!(arb.arbitrate(0, |_| true, |idx| idx != 0), vec![1]);
+        assert_eq!(arb.stats()[0].wait_cycles, 1);
+    }
+}
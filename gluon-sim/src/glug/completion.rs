@@ -1,9 +1,11 @@
 use crate::{common::{
-    base::{Configurable, Event},
+    base::Configurable,
+    protocol::GluonEvent,
 }, glug::stream::StreamConfig};
+use std::ops::Range;
 
 pub struct Completion {
-    pub eq: Vec<Option<Event>>,
+    pub eq: Vec<Option<GluonEvent>>,
 }
 
 impl Configurable<StreamConfig> for Completion {
@@ -16,14 +18,17 @@ impl Configurable<StreamConfig> for Completion {
 
 impl Completion {
 
-    pub fn set_completion(&mut self, event: Event) {
-        let evnt = self.eq.get_mut(event.sid() as usize).expect("sid out of bounds");
-        assert!(evnt.is_none(), "impossible unset completion for sid {}", event.sid());
+    pub fn set_completion(&mut self, event: GluonEvent) {
+        let sid = event.sid().expect("Completion: event does not complete a stream");
+        let evnt = self.eq.get_mut(sid as usize).expect("sid out of bounds");
+        assert!(evnt.is_none(), "impossible unset completion for sid {}", sid);
         *evnt = Some(event);
     }
 
-    pub fn try_clear_completion(&mut self) -> Option<Event> {
+    pub fn try_clear_completion(&mut self, sids: Range<u8>) -> Option<GluonEvent> {
         self.eq.iter_mut()
+        .skip(sids.start as usize)
+        .take(sids.len())
         .find(|event| event.is_some())
         .map(|event| {
             let evnt = event.take().expect("impossible");
@@ -31,4 +36,12 @@ impl Completion {
             evnt
         })
     }
+
+    pub fn take(&mut self, sid: u8) -> Option<GluonEvent> {
+        self.eq.get_mut(sid as usize).expect("sid out of bounds").take()
+    }
+
+    pub fn pending(&self, sid: u8) -> bool {
+        self.eq.get(sid as usize).is_some_and(|event| event.is_some())
+    }
 }
@@ -1,9 +1,7 @@
-use crate::{
-    common::{
-        base::{CmdType, Command, Configurable},
-        queue::Queue,
-    },
-    glug::engine::EngineCommand,
+use crate::common::{
+    base::{CmdType, Configurable},
+    protocol::GluonCommand,
+    queue::Queue,
 };
 use serde::Deserialize;
 
@@ -25,9 +23,9 @@ impl Default for DecodeDispatchConfig {
     }
 }
 
-#[derive(Debug, Default, Clone)]
+#[derive(Debug, Clone)]
 pub struct EngineQueue {
-    pub q: Queue<EngineCommand>,
+    pub q: Queue<GluonCommand>,
     pub engine_type: CmdType,
 }
 
@@ -64,15 +62,13 @@ impl DecodeDispatch {
             .any(|eq| !eq.q.full())
     }
 
-    pub fn enqueue(&mut self, cmd: Command) {
+    pub fn enqueue(&mut self, cmd: GluonCommand) {
         if let Some(engine_queue) = self
             .qs
             .iter_mut()
             .find(|eq| eq.engine_type == cmd.cmd_type() && !eq.q.full())
         {
-            engine_queue
-                .q
-                .push(EngineCommand::from_command(cmd));
+            engine_queue.q.push(cmd);
         }
     }
 }
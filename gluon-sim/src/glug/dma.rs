@@ -0,0 +1,552 @@
+use crate::common::base::{DMADir, DMAReq, MemErr, MemErrCode, MemReq, MemResp};
+use crate::glug::arbiter::{Arbiter, ArbiterConfig, ArbiterStats};
+use crate::glug::dram::DRAM;
+use crate::glug::engine::Engine;
+use crate::shared_memory::HostMemory;
+use crate::trace::{SharedTrace, TraceRecord};
+use cyclotron::info;
+use cyclotron::sim::log::Logger;
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct DMAConfig {
+    /// Bytes moved by one read beat.
+    pub beat_bytes: u32,
+    /// Read beats a block may have in flight.
+    pub max_outstanding_beats: usize,
+    /// Cycles from issuing a host read beat to its response; DRAM reads are timed by the DRAM model.
+    pub read_latency: u64,
+    /// Beats the read reassembly buffer collects into one write burst.
+    pub reassembly_beats: u32,
+    /// Write bursts the DMA buffer holds.
+    pub buffer_bursts: usize,
+    /// Pairs of H2D and D2H blocks; each serves one request at a time.
+    pub num_blocks: usize,
+    /// Arbitration between engines for the blocks; `ports` is the number of requests started per cycle.
+    pub arbiter: ArbiterConfig,
+}
+
+impl Default for DMAConfig {
+    fn default() -> Self {
+        Self {
+            beat_bytes: 64,
+            max_outstanding_beats: 8,
+            read_latency: 4,
+            reassembly_beats: 4,
+            buffer_bursts: 4,
+            num_blocks: 1,
+            arbiter: ArbiterConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum DMAState {
+    #[default]
+    D0,
+    D1,
+    D2,
+}
+
+/// What a block moves data between: host memory of the request's stream and device DRAM.
+pub struct DMAPorts<'a> {
+    pub hosts: &'a [Option<Arc<dyn HostMemory>>],
+    pub dram: &'a mut DRAM,
+    pub trace: Option<&'a SharedTrace>,
+}
+
+impl DMAPorts<'_> {
+    fn host(&self, sid: u8, addr: u64) -> Result<&Arc<dyn HostMemory>, MemErr> {
+        self.hosts
+            .get(sid as usize)
+            .and_then(|host| host.as_ref())
+            .ok_or(MemErr { code: MemErrCode::HOST, addr })
+    }
+
+    fn host_read(&self, sid: u8, addr: u64, len: usize) -> Result<Vec<u8>, MemErr> {
+        let data = self.host(sid, addr)?.read(addr, len)?;
+        if let Some(trace) = self.trace {
+            trace.lock().expect("trace poisoned").record(TraceRecord::HOSTREAD {
+                sid,
+                addr,
+                data: data.clone(),
+            });
+        }
+        Ok(data)
+    }
+}
+
+/// Outcome of a request: the bytes read for a fetch, nothing for a transfer.
+pub type DMAResult = Result<Option<Vec<u8>>, MemErr>;
+
+/// A read beat in flight: from host memory it arrives at the given cycle, from DRAM with the next
+/// response on the block's port.
+pub enum Beat {
+    HOST(u64, Vec<u8>),
+    DRAM,
+}
+
+/// One direction of the DMA unit. Reads are issued a beat at a time with up to
+/// `max_outstanding_beats` in flight; responses are collected in the read reassembly buffer until they
+/// form a write burst, which moves to the DMA buffer and is written out from there. A burst entering an
+/// empty DMA buffer is written in the same cycle, short-circuiting it. Host reads take `read_latency`
+/// cycles and host writes complete at once; DRAM accesses go through the block's DRAM port, so a
+/// transfer takes cycles in proportion to its size and to the DRAM bandwidth. A fetch collects the
+/// bursts for the requesting engine instead of writing them to DRAM.
+pub struct DMABlock {
+    dir: DMADir,
+    config: DMAConfig,
+    state: DMAState,
+    /// The block's DRAM port.
+    port: usize,
+
+    /// Engine whose request is being served, and the request.
+    req: Option<(usize, DMAReq)>,
+    read_ctr: u32,
+    write_ctr: u32,
+    outstanding: VecDeque<Beat>,
+    /// DRAM writes issued but not yet acknowledged.
+    pending_writes: usize,
+    reassembly: Vec<u8>,
+    buffer: VecDeque<Vec<u8>>,
+    /// Bytes collected by a fetch.
+    fetched: Vec<u8>,
+    err: Option<MemErr>,
+}
+
+impl DMABlock {
+    pub fn new(dir: DMADir, config: &DMAConfig, port: usize) -> Self {
+        assert!(config.beat_bytes > 0, "DMA: beat_bytes must be non-zero");
+        assert!(config.max_outstanding_beats > 0, "DMA: max_outstanding_beats must be non-zero");
+        assert!(config.buffer_bursts > 0, "DMA: buffer_bursts must be non-zero");
+        DMABlock {
+            dir,
+            config: *config,
+            state: DMAState::D0,
+            port,
+            req: None,
+            read_ctr: 0,
+            write_ctr: 0,
+            outstanding: VecDeque::new(),
+            pending_writes: 0,
+            reassembly: Vec::new(),
+            buffer: VecDeque::new(),
+            fetched: Vec::new(),
+            err: None,
+        }
+    }
+
+    pub fn busy(&self) -> bool {
+        self.state != DMAState::D0
+    }
+
+    /// Engine whose request the block is serving.
+    pub fn engine_idx(&self) -> Option<usize> {
+        self.req.as_ref().map(|(idx, _)| *idx)
+    }
+
+    fn burst_bytes(&self) -> usize {
+        (self.config.beat_bytes * self.config.reassembly_beats.max(1)) as usize
+    }
+
+    /// D0: accept a request from `engine_idx`.
+    pub fn start(&mut self, engine_idx: usize, req: DMAReq) {
+        assert_eq!(req.dir, self.dir, "DMA: request routed to the wrong block");
+        assert!(!req.fetch || req.dir == DMADir::H2D, "DMA: fetches must be H2D");
+        assert!(!self.busy(), "DMA: block busy");
+        self.req = Some((engine_idx, req));
+        self.read_ctr = 0;
+        self.write_ctr = 0;
+        self.outstanding.clear();
+        self.pending_writes = 0;
+        self.reassembly.clear();
+        self.buffer.clear();
+        self.fetched.clear();
+        self.err = None;
+        self.state = DMAState::D1;
+    }
+
+    /// Advance the transfer by a cycle. Returns the engine and result once the request completes; the
+    /// result of a successful fetch carries the bytes read.
+    pub fn tick(&mut self, ports: &mut DMAPorts) -> Option<(usize, DMAResult)> {
+        match self.state {
+            DMAState::D0 => None,
+            DMAState::D1 => {
+                let (_, req) = *self.req.as_ref().expect("DMA: D1 without request");
+                self.transfer(&req, ports);
+                // DRAM accesses already issued are left to finish so the port is clean for the next request.
+                let drained = self.pending_writes == 0 && ports.dram.port_idle(self.port);
+                if drained && (self.err.is_some() || self.write_ctr == req.sz) {
+                    self.state = DMAState::D2;
+                }
+                None
+            }
+            DMAState::D2 => {
+                let (engine_idx, req) = self.req.take().expect("DMA: D2 without request");
+                self.state = DMAState::D0;
+                let fetched = req.fetch.then(|| std::mem::take(&mut self.fetched));
+                Some((engine_idx, self.err.map_or(Ok(fetched), Err)))
+            }
+        }
+    }
+
+    fn transfer(&mut self, req: &DMAReq, ports: &mut DMAPorts) {
+        let cycle = ports.dram.cycle();
+
+        // Write acknowledgements from DRAM.
+        if self.dir == DMADir::H2D {
+            if let Some(resp) = ports.dram.take_resp(self.port) {
+                self.pending_writes -= 1;
+                if let Some(err) = resp.err {
+                    self.err.get_or_insert(err);
+                }
+            }
+        }
+
+        // Move arrived beats into the reassembly buffer while it has room. DRAM read data stays in the
+        // port until then.
+        while self.reassembly.len() < self.burst_bytes() {
+            match self.outstanding.front_mut() {
+                Some(Beat::HOST(ready, data)) if *ready <= cycle => {
+                    let data = std::mem::take(data);
+                    self.reassembly.extend_from_slice(&data);
+                }
+                Some(Beat::DRAM) => match ports.dram.take_resp(self.port) {
+                    Some(MemResp { err: Some(err), .. }) => {
+                        self.err.get_or_insert(err);
+                    }
+                    Some(resp) => {
+                        self.reassembly.extend_from_slice(&resp.data.expect("DMA: DRAM read returned no data"));
+                    }
+                    None => break,
+                },
+                _ => break,
+            }
+            self.outstanding.pop_front();
+        }
+        // After an error nothing is written, so reads still in flight are dropped as they arrive.
+        if self.err.is_some() && self.dir == DMADir::D2H && ports.dram.take_resp(self.port).is_some() {
+            self.outstanding.pop_front();
+        }
+
+        // A burst is complete when the reassembly buffer is full or holds the tail of the transfer.
+        let reads_done = self.read_ctr == req.sz && self.outstanding.is_empty();
+        let burst_ready = !self.reassembly.is_empty() && (self.reassembly.len() >= self.burst_bytes() || reads_done);
+        if burst_ready && self.buffer.len() < self.config.buffer_bursts {
+            self.buffer.push_back(std::mem::take(&mut self.reassembly));
+        }
+
+        // Write port
+        if self.err.is_none() && !self.buffer.is_empty() {
+            let addr = req.target_addr + self.write_ctr as u64;
+            match self.dir {
+                DMADir::H2D if req.fetch => {
+                    let burst = self.buffer.pop_front().expect("DMA: unreachable");
+                    self.write_ctr += burst.len() as u32;
+                    self.fetched.extend_from_slice(&burst);
+                }
+                DMADir::H2D => {
+                    if ports.dram.can_request(self.port) {
+                        let burst = self.buffer.pop_front().expect("DMA: unreachable");
+                        self.write_ctr += burst.len() as u32;
+                        self.pending_writes += 1;
+                        ports.dram.request(self.port, MemReq {
+                            addr,
+                            write: true,
+                            swap: false,
+                            bytes: burst.len() as u32,
+                            data: burst,
+                        });
+                    }
+                }
+                DMADir::D2H => {
+                    let burst = self.buffer.pop_front().expect("DMA: unreachable");
+                    match ports.host(req.sid, addr).and_then(|host| host.write(addr, &burst)) {
+                        Ok(()) => self.write_ctr += burst.len() as u32,
+                        Err(err) => self.err = Some(err),
+                    }
+                }
+            }
+        }
+
+        // Read port: issue one beat.
+        if self.err.is_none() && self.read_ctr < req.sz && self.outstanding.len() < self.config.max_outstanding_beats {
+            let len = self.config.beat_bytes.min(req.sz - self.read_ctr);
+            let addr = req.src_addr + self.read_ctr as u64;
+            match self.dir {
+                DMADir::H2D => match ports.host_read(req.sid, addr, len as usize) {
+                    Ok(data) => {
+                        self.outstanding.push_back(Beat::HOST(cycle + self.config.read_latency, data));
+                        self.read_ctr += len;
+                    }
+                    Err(err) => self.err = Some(err),
+                },
+                DMADir::D2H => {
+                    if ports.dram.can_request(self.port) {
+                        ports.dram.request(self.port, MemReq {
+                            addr,
+                            write: false,
+                            swap: false,
+                            bytes: len,
+                            data: vec![],
+                        });
+                        self.outstanding.push_back(Beat::DRAM);
+                        self.read_ctr += len;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// `num_blocks` pairs of H2D and D2H blocks, serving the engines' DMA requests.
+pub struct DMAUnit {
+    blocks: Vec<DMABlock>,
+    arbiter: Arbiter,
+    logger: Arc<Logger>,
+}
+
+impl DMAUnit {
+    /// Block pair `i` uses DRAM ports `first_port + 2 * i` (H2D) and `first_port + 2 * i + 1` (D2H).
+    pub fn new(config: &DMAConfig, num_engines: usize, first_port: usize, logger: Arc<Logger>) -> Self {
+        assert!(config.num_blocks > 0, "DMA: num_blocks must be non-zero");
+        DMAUnit {
+            blocks: (0..config.num_blocks)
+                .flat_map(|idx| {
+                    [
+                        DMABlock::new(DMADir::H2D, config, first_port + 2 * idx),
+                        DMABlock::new(DMADir::D2H, config, first_port + 2 * idx + 1),
+                    ]
+                })
+                .collect(),
+            arbiter: Arbiter::new(&config.arbiter, num_engines),
+            logger,
+        }
+    }
+
+    pub fn busy(&self) -> bool {
+        self.blocks.iter().any(DMABlock::busy)
+    }
+
+    /// Grant and wait counts per engine.
+    pub fn stats(&self) -> &[ArbiterStats] {
+        self.arbiter.stats()
+    }
+
+    /// Hand new requests to idle blocks, advance all blocks and complete finished requests.
+    pub fn tick(&mut self, engines: &mut [Box<dyn Engine>], ports: &mut DMAPorts) {
+        let requests = engines
+            .iter()
+            .enumerate()
+            .map(|(idx, engine)| {
+                engine
+                    .get_dma_req()
+                    .filter(|req| !req.done && !self.blocks.iter().any(|block| block.engine_idx() == Some(idx)))
+                    .copied()
+            })
+            .collect::<Vec<_>>();
+        let blocks = &mut self.blocks;
+        let logger = &self.logger;
+        self.arbiter.arbitrate(
+            ports.dram.cycle(),
+            |idx| requests[idx].is_some(),
+            |idx| {
+                let req = requests[idx].expect("DMA: grant without request");
+                match blocks.iter_mut().find(|block| block.dir == req.dir && !block.busy()) {
+                    Some(block) => {
+                        info!(logger, "DMA: engine {} {:?}", idx, req);
+                        block.start(idx, req);
+                        true
+                    }
+                    None => false,
+                }
+            },
+        );
+
+        for block in self.blocks.iter_mut() {
+            if let Some((idx, result)) = block.tick(ports) {
+                if let Err(err) = result {
+                    info!(self.logger, "DMA for engine {} failed: {:?}", idx, err);
+                }
+                let engine = engines.get_mut(idx).expect("Engine idx out of bounds");
+                // Fetched bytes reach the engine like a response on its DRAM port.
+                if let Ok(Some(data)) = &result {
+                    engine.set_mem_resp(&MemResp {
+                        data: Some(data.clone()),
+                        err: None,
+                    });
+                }
+                engine.done_dma_req(result.map(|_| ()));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Mutex, RwLock};
+
+    use cyclotron::base::mem::HasMemory;
+    use cyclotron::sim::config::MemConfig;
+    use cyclotron::sim::flat_mem::FlatMemory;
+
+    use super::*;
+    use crate::glug::dram::DRAMConfig;
+
+    const HOST: u64 = 0x7f00_0000;
+
+    /// Host memory at `HOST` that records the reads it serves.
+    struct TestHost {
+        data: Mutex<Vec<u8>>,
+        reads: Mutex<Vec<(u64, usize)>>,
+    }
+
+    impl TestHost {
+        fn new(data: Vec<u8>) -> Arc<Self> {
+            Arc::new(TestHost {
+                data: Mutex::new(data),
+                reads: Mutex::new(vec![]),
+            })
+        }
+
+        fn range(&self, addr: u64, len: usize) -> Result<std::ops::Range<usize>, MemErr> {
+            let start = (addr - HOST) as usize;
+            if start + len > self.data.lock().unwrap().len() {
+                return Err(MemErr { code: MemErrCode::HOST, addr });
+            }
+            Ok(start..start + len)
+        }
+    }
+
+    impl HostMemory for TestHost {
+        fn read(&self, addr: u64, len: usize) -> Result<Vec<u8>, MemErr> {
+            let range = self.range(addr, len)?;
+            self.reads.lock().unwrap().push((addr, len));
+            Ok(self.data.lock().unwrap()[range].to_vec())
+        }
+
+        fn write(&self, addr: u64, data: &[u8]) -> Result<(), MemErr> {
+            let range = self.range(addr, data.len())?;
+            self.data.lock().unwrap()[range].copy_from_slice(data);
+            Ok(())
+        }
+    }
+
+    struct Bench {
+        block: DMABlock,
+        host: Arc<TestHost>,
+        hosts: Vec<Option<Arc<dyn HostMemory>>>,
+        mem: Arc<RwLock<FlatMemory>>,
+        dram: DRAM,
+    }
+
+    impl Bench {
+        fn new(dir: DMADir, config: DMAConfig, host: Vec<u8>) -> Self {
+            let host = TestHost::new(host);
+            let mem = Arc::new(RwLock::new(FlatMemory::new(Some(MemConfig::default()))));
+            Bench {
+                block: DMABlock::new(dir, &config, 0),
+                hosts: vec![Some(Arc::clone(&host) as Arc<dyn HostMemory>)],
+                host,
+                dram: DRAM::new(&DRAMConfig::default(), Arc::clone(&mem), 1),
+                mem,
+            }
+        }
+
+        fn tick(&mut self) -> Option<(usize, DMAResult)> {
+            self.dram.tick();
+            self.block.tick(&mut DMAPorts {
+                hosts: &self.hosts,
+                dram: &mut self.dram,
+                trace: None,
+            })
+        }
+
+        /// Serve `req` to completion; the block must be clean afterwards.
+        fn run(&mut self, req: DMAReq) -> DMAResult {
+            self.block.start(0, req);
+            for _ in 0..1000 {
+                if let Some((engine, result)) = self.tick() {
+                    assert_eq!(engine, 0);
+                    assert!(!self.block.busy() && self.dram.port_idle(0));
+                    return result;
+                }
+            }
+            panic!("DMA did not complete");
+        }
+
+        fn dram(&self, addr: u64, len: usize) -> Vec<u8> {
+            self.mem.read().unwrap().read(addr as usize, len).unwrap().to_vec()
+        }
+    }
+
+    fn req(dir: DMADir, src_addr: u64, target_addr: u64, sz: u32) -> DMAReq {
+        DMAReq {
+            sid: 0,
+            dir,
+            src_addr,
+            target_addr,
+            sz,
+            ..DMAReq::default()
+        }
+    }
+
+    fn config() -> DMAConfig {
+        DMAConfig {
+            beat_bytes: 16,
+            max_outstanding_beats: 2,
+            reassembly_beats: 2,
+            ..DMAConfig::default()
+        }
+    }
+
+    #[test]
+    fn splits_at_beat_bytes() {
+        let mut bench = Bench::new(DMADir::H2D, config(), (0..=255).collect());
+        assert_eq!(bench.run(req(DMADir::H2D, HOST + 8, 0x1000, 40)), Ok(None));
+        let reads = bench.host.reads.lock().unwrap().clone();
+        assert_eq!(reads, [(HOST + 8, 16), (HOST + 24, 16), (HOST + 40, 8)]);
+        assert_eq!(bench.dram(0x1000, 40), (8..48).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn outstanding_capped() {
+        let config = DMAConfig {
+            read_latency: 50,
+            ..config()
+        };
+        let mut bench = Bench::new(DMADir::H2D, config, vec![7; 256]);
+        bench.block.start(0, req(DMADir::H2D, HOST, 0x1000, 128));
+        for _ in 0..20 {
+            assert!(bench.tick().is_none());
+        }
+        // No beat has arrived yet, so only `max_outstanding_beats` have been issued.
+        assert_eq!(bench.host.reads.lock().unwrap().len(), 2);
+        assert_eq!(bench.block.outstanding.len(), 2);
+        while bench.tick().is_none() {}
+        assert_eq!(bench.host.reads.lock().unwrap().len(), 8);
+        assert_eq!(bench.dram(0x1000, 128), vec![7; 128]);
+    }
+
+    #[test]
+    fn error_drains_before_next_transfer() {
+        let mut bench = Bench::new(DMADir::D2H, config(), vec![0; 48]);
+        bench.mem.write().unwrap().write(0x1000, &[0xaa; 128]).unwrap();
+        bench.mem.write().unwrap().write(0x2000, &[0x55; 32]).unwrap();
+
+        // The second burst overruns the host buffer while DRAM reads are still in flight.
+        let err = MemErr {
+            code: MemErrCode::HOST,
+            addr: HOST + 32,
+        };
+        assert_eq!(bench.run(req(DMADir::D2H, 0x1000, HOST, 128)), Err(err));
+        assert_eq!(bench.host.data.lock().unwrap()[..32], [0xaa; 32]);
+
+        // Beats of the failed transfer must not leak into the next one.
+        assert_eq!(bench.run(req(DMADir::D2H, 0x2000, HOST, 32)), Ok(None));
+        assert_eq!(bench.host.data.lock().unwrap()[..], [[0x55; 32].as_slice(), &[0; 16]].concat());
+    }
+}
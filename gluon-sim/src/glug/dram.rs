@@ -0,0 +1,245 @@
+use crate::common::base::{MemErr, MemErrCode, MemReq, MemResp};
+use crate::glug::arbiter::{Arbiter, ArbiterConfig, ArbiterPolicy, ArbiterStats};
+use cyclotron::base::mem::HasMemory;
+use cyclotron::sim::flat_mem::FlatMemory;
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::sync::{Arc, RwLock};
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct DRAMConfig {
+    /// Cycles from the last byte of an access leaving its channel to the response.
+    pub latency: u64,
+    /// Bytes a channel transfers per cycle.
+    pub bytes_per_cycle: u32,
+    pub num_channels: usize,
+    /// Consecutive bytes mapped to one channel before moving to the next.
+    pub interleave_bytes: u64,
+    /// Arbitration between ports; `ports` is the number of accesses granted per cycle.
+    pub arbiter: ArbiterConfig,
+}
+
+impl Default for DRAMConfig {
+    fn default() -> Self {
+        Self {
+            latency: 20,
+            bytes_per_cycle: 32,
+            num_channels: 4,
+            interleave_bytes: 256,
+            arbiter: ArbiterConfig {
+                policy: ArbiterPolicy::RR,
+                ports: 4,
+            },
+        }
+    }
+}
+
+/// One requester's connection to DRAM: a request slot, and the granted accesses in flight with the
+/// cycle their response arrives.
+#[derive(Debug, Default)]
+struct DRAMPort {
+    slot: Option<MemReq>,
+    inflight: VecDeque<(u64, MemResp)>,
+}
+
+/// Timing model in front of the functional device memory. Requesters place one access at a time in
+/// their port's slot; each cycle the arbiter grants up to its port count of slots whose channel is free. A granted
+/// access holds its channel for `bytes / bytes_per_cycle` cycles and responds `latency` cycles after
+/// that. Responses on a port arrive in request order. The access itself is performed on the flat
+/// memory when it is granted; one the flat memory rejects responds with a `RANGE` error.
+pub struct DRAM {
+    config: DRAMConfig,
+    cycle: u64,
+    mem: Arc<RwLock<FlatMemory>>,
+    /// Cycle from which each channel can accept an access.
+    channels: Vec<u64>,
+    ports: Vec<DRAMPort>,
+    arbiter: Arbiter,
+}
+
+impl DRAM {
+    pub fn new(config: &DRAMConfig, mem: Arc<RwLock<FlatMemory>>, num_ports: usize) -> Self {
+        assert!(config.bytes_per_cycle > 0, "DRAM: bytes_per_cycle must be non-zero");
+        assert!(config.num_channels > 0, "DRAM: num_channels must be non-zero");
+        assert!(config.interleave_bytes > 0, "DRAM: interleave_bytes must be non-zero");
+        DRAM {
+            config: *config,
+            cycle: 0,
+            mem,
+            channels: vec![0; config.num_channels],
+            ports: (0..num_ports).map(|_| DRAMPort::default()).collect(),
+            arbiter: Arbiter::new(&config.arbiter, num_ports),
+        }
+    }
+
+    pub fn cycle(&self) -> u64 {
+        self.cycle
+    }
+
+    /// The port's slot is free for a new request.
+    pub fn can_request(&self, port: usize) -> bool {
+        self.port(port).slot.is_none()
+    }
+
+    /// Nothing is waiting or in flight on the port.
+    pub fn port_idle(&self, port: usize) -> bool {
+        let port = self.port(port);
+        port.slot.is_none() && port.inflight.is_empty()
+    }
+
+    pub fn request(&mut self, port: usize, req: MemReq) {
+        let port = self.ports.get_mut(port).expect("DRAM: port out of bounds");
+        assert!(port.slot.is_none(), "DRAM: request slot occupied");
+        port.slot = Some(req);
+    }
+
+    /// Oldest response on the port, once it has arrived. Writes respond with no data.
+    pub fn take_resp(&mut self, port: usize) -> Option<MemResp> {
+        let cycle = self.cycle;
+        let port = self.ports.get_mut(port).expect("DRAM: port out of bounds");
+        if port.inflight.front().is_some_and(|(done, _)| *done <= cycle) {
+            port.inflight.pop_front().map(|(_, resp)| resp)
+        } else {
+            None
+        }
+    }
+
+    /// Grant and wait counts per port.
+    pub fn stats(&self) -> &[ArbiterStats] {
+        self.arbiter.stats()
+    }
+
+    /// Advance a cycle and grant waiting requests.
+    pub fn tick(&mut self) {
+        self.cycle += 1;
+        let cycle = self.cycle;
+        let config = &self.config;
+        let ports = &self.ports;
+        let channels = &mut self.channels;
+        let channel_of = |req: &MemReq| ((req.addr / config.interleave_bytes) % config.num_channels as u64) as usize;
+        let granted = self.arbiter.arbitrate(
+            cycle,
+            |idx| ports[idx].slot.is_some(),
+            |idx| {
+                let req = ports[idx].slot.as_ref().expect("DRAM: grant without request");
+                let channel = channel_of(req);
+                if channels[channel] > cycle {
+                    return false;
+                }
+                channels[channel] = cycle + req.bytes.max(1).div_ceil(config.bytes_per_cycle) as u64;
+                true
+            },
+        );
+
+        for idx in granted {
+            let req = self.ports[idx].slot.take().expect("DRAM: unreachable");
+            let resp = self.access(&req);
+            let busy = req.bytes.max(1).div_ceil(self.config.bytes_per_cycle) as u64;
+            let port = &mut self.ports[idx];
+            let done = port
+                .inflight
+                .back()
+                .map_or(0, |(done, _)| *done)
+                .max(cycle + busy + self.config.latency);
+            port.inflight.push_back((done, resp));
+        }
+    }
+
+    fn access(&self, req: &MemReq) -> MemResp {
+        let mut mem = self.mem.write().expect("gmem poisoned");
+        let result = if req.swap {
+            mem.read(req.addr as usize, req.data.len()).and_then(|old_data| {
+                let old_data = old_data.to_vec();
+                mem.write(req.addr as usize, &req.data).map(|()| Some(old_data))
+            })
+        } else if req.write {
+            mem.write(req.addr as usize, &req.data).map(|()| None)
+        } else {
+            mem.read(req.addr as usize, req.bytes as usize).map(|data| Some(data.to_vec()))
+        };
+        match result {
+            Ok(data) => MemResp { data, err: None },
+            Err(_) => MemResp {
+                data: None,
+                err: Some(MemErr {
+                    code: MemErrCode::RANGE,
+                    addr: req.addr,
+                }),
+            },
+        }
+    }
+
+    fn port(&self, port: usize) -> &DRAMPort {
+        self.ports.get(port).expect("DRAM: port out of bounds")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cyclotron::sim::config::MemConfig;
+
+    use super::*;
+
+    const LATENCY: u64 = 10;
+
+    /// Two channels interleaved every 256 bytes, 32 bytes per cycle each.
+    fn dram(num_ports: usize) -> DRAM {
+        let config = DRAMConfig {
+            latency: LATENCY,
+            num_channels: 2,
+            ..DRAMConfig::default()
+        };
+        DRAM::new(&config, Arc::new(RwLock::new(FlatMemory::new(Some(MemConfig::default())))), num_ports)
+    }
+
+    fn read(addr: u64, bytes: u32) -> MemReq {
+        MemReq {
+            addr,
+            write: false,
+            swap: false,
+            bytes,
+            data: vec![],
+        }
+    }
+
+    /// Issue one access per port at cycle 0 and return the cycle each response arrives.
+    fn arrivals(reqs: Vec<MemReq>) -> Vec<u64> {
+        let mut dram = dram(reqs.len());
+        let mut arrived = vec![None; reqs.len()];
+        for (port, req) in reqs.into_iter().enumerate() {
+            dram.request(port, req);
+        }
+        while arrived.iter().any(Option::is_none) {
+            assert!(dram.cycle() < 1000, "DRAM did not respond");
+            dram.tick();
+            for (port, arrived) in arrived.iter_mut().enumerate() {
+                if dram.take_resp(port).is_some() {
+                    assert!(arrived.replace(dram.cycle()).is_none());
+                }
+            }
+        }
+        arrived.into_iter().map(Option::unwrap).collect()
+    }
+
+    #[test]
+    fn single_access_latency() {
+        // Granted at cycle 1, one cycle on the channel, then `latency`.
+        assert_eq!(arrivals(vec![read(0, 32)]), [1 + 1 + LATENCY]);
+        assert_eq!(arrivals(vec![read(0, 1)]), [1 + 1 + LATENCY]);
+        assert_eq!(arrivals(vec![read(0, 128)]), [1 + 4 + LATENCY]);
+    }
+
+    #[test]
+    fn same_channel_serializes() {
+        // The second access waits until the first has left the channel at cycle 3.
+        assert_eq!(arrivals(vec![read(0, 64), read(64, 64)]), [1 + 2 + LATENCY, 3 + 2 + LATENCY]);
+        // 512 maps to channel 0 again.
+        assert_eq!(arrivals(vec![read(0, 64), read(512, 64)]), [1 + 2 + LATENCY, 3 + 2 + LATENCY]);
+    }
+
+    #[test]
+    fn different_channels_overlap() {
+        assert_eq!(arrivals(vec![read(0, 64), read(256, 64)]), [1 + 2 + LATENCY; 2]);
+    }
+}
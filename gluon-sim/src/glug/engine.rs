@@ -1,11 +1,14 @@
 use std::iter::repeat_with;
 
-use crate::common::base::{Clocked, CmdType, Command, Configurable, DMAReq, Event, MemReq};
+use crate::common::base::{Clocked, CmdType, Configurable, DMAReq, MemErr, MemReq, MemResp};
+use crate::common::protocol::{GluonCommand, GluonEvent};
+use crate::context::Context;
 use crate::glug::engines::{
     cs_engine::{CSEngine, CSEngineConfig},
     kernel_engine::{KernelEngine, KernelEngineConfig},
     mem_engine::{MemEngine, MemEngineConfig},
 };
+use crate::glug::partition::PartitionPolicy;
 use crate::glul::glul::{GLULReq, GLULStatus};
 use cyclotron::muon::warp::ExecErr;
 use cyclotron::sim::log::Logger;
@@ -13,20 +16,28 @@ use std::sync::Arc;
 use serde::Deserialize;
 
 pub trait Engine: Clocked + Send {
-    fn set_cmd(&mut self, cmd: EngineCommand);
+    fn set_cmd(&mut self, cmd: GluonCommand);
     fn busy(&self) -> bool;
     fn cmd_type(&self) -> CmdType;
     fn set_logger(&mut self, logger: Arc<Logger>);
     fn set_gluls(&mut self, gluls: Vec<GLULStatus>);
+    /// Client contexts, see `GLUGConfig::contexts`.
+    fn set_contexts(&mut self, contexts: Vec<Context>);
+    /// GLULs the engine's undispatched thread blocks fit on; empty if there are none.
+    fn wants_gluls(&self) -> Vec<bool>;
+    /// GLULs the engine may dispatch to this cycle.
+    fn set_glul_grants(&mut self, grants: Vec<bool>);
     fn get_dma_req(&self) -> Option<&DMAReq>;
-    fn done_dma_req(&mut self);
+    fn done_dma_req(&mut self, result: Result<(), MemErr>);
     fn get_mem_req(&self) -> Option<&MemReq>;
-    fn set_mem_resp(&mut self, data: Option<&Vec<u8>>);
+    fn set_mem_resp(&mut self, resp: &MemResp);
     fn get_glul_req(&self) -> Option<&GLULReq>;
     fn clear_glul_req(&mut self);
     fn notify_glul_done(&mut self, tbs: u32);
     fn notify_glul_err(&mut self, err: ExecErr);
-    fn get_completion(&self) -> Option<Event>;
+    /// True once if the engine stopped its kernel and the kernel's thread blocks must be killed.
+    fn take_kill(&mut self) -> bool;
+    fn get_completion(&self) -> Option<GluonEvent>;
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -35,6 +46,8 @@ pub struct EngineConfig {
     pub num_kernel_engines: usize,
     pub num_mem_engines: usize,
     pub num_cs_engines: usize,
+    /// How concurrent kernels share the GLULs.
+    pub glul_policy: PartitionPolicy,
     pub kernel_engine_config: KernelEngineConfig,
     pub mem_engine_config: MemEngineConfig,
     pub cs_engine_config: CSEngineConfig,
@@ -46,6 +59,7 @@ impl Default for EngineConfig {
             num_kernel_engines: 1,
             num_mem_engines: 1,
             num_cs_engines: 1,
+            glul_policy: PartitionPolicy::FIRST,
             kernel_engine_config: KernelEngineConfig::default(),
             mem_engine_config: MemEngineConfig::default(),
             cs_engine_config: CSEngineConfig::default(),
@@ -80,28 +94,3 @@ impl EngineConfig {
         engines
     }
 }
-
-#[derive(Debug, Default, Clone, Copy)]
-pub struct EngineCommand {
-    sid: u8,
-    bytes: [u8; 14],
-}
-
-impl EngineCommand {
-    pub fn from_command(cmd: Command) -> Self {
-        let mut bytes = [0u8; 14];
-        bytes.copy_from_slice(cmd.slice(2, 16));
-        EngineCommand {
-            sid: cmd.sid(),
-            bytes,
-        }
-    }
-
-    pub fn sid(&self) -> u8 {
-        self.sid
-    }
-
-    pub fn bytes(&self) -> &[u8; 14] {
-        &self.bytes
-    }
-}
@@ -1,6 +1,8 @@
 use crate::{
-    common::base::{Clocked, CmdType, Configurable, Event, SimErr},
-    glug::engine::{Engine, EngineCommand},
+    common::base::{Clocked, CmdType, Configurable, MemErr, SimErr},
+    common::protocol::{GluonCommand, GluonEvent},
+    context::Context,
+    glug::engine::Engine,
     glul::glul::GLULStatus,
 };
 use cyclotron::muon::warp::ExecErr;
@@ -17,7 +19,7 @@ pub struct CSEngine {
 }
 
 impl Engine for CSEngine {
-    fn set_cmd(&mut self, _: EngineCommand) {}
+    fn set_cmd(&mut self, _: GluonCommand) {}
 
     fn busy(&self) -> bool {
         false
@@ -35,13 +37,13 @@ impl Engine for CSEngine {
         None
     }
 
-    fn done_dma_req(&mut self) {}
+    fn done_dma_req(&mut self, _: Result<(), MemErr>) {}
 
     fn get_mem_req(&self) -> Option<&crate::common::base::MemReq> {
         None
     }
 
-    fn set_mem_resp(&mut self, _: Option<&Vec<u8>>) {
+    fn set_mem_resp(&mut self, _: &crate::common::base::MemResp) {
         panic!("CSR engine: cannot set mem resp");
     }
 
@@ -59,11 +61,23 @@ impl Engine for CSEngine {
 
     fn set_gluls(&mut self, _: Vec<GLULStatus>) {}
 
+    fn set_contexts(&mut self, _: Vec<Context>) {}
+
+    fn wants_gluls(&self) -> Vec<bool> {
+        vec![]
+    }
+
+    fn set_glul_grants(&mut self, _: Vec<bool>) {}
+
     fn notify_glul_err(&mut self, _: ExecErr) {
         panic!("CSR engine: cannot notify glul err");
     }
 
-    fn get_completion(&self) -> Option<Event> {
+    fn take_kill(&mut self) -> bool {
+        false
+    }
+
+    fn get_completion(&self) -> Option<GluonEvent> {
         None
     }
 }
@@ -2,13 +2,20 @@ use crate::common::base::Clocked;
 use crate::common::base::Configurable;
 use crate::common::base::DMADir;
 use crate::common::base::DMAReq;
-use crate::common::base::Event;
+use crate::common::base::MemErr;
+use crate::common::base::MemErrCode;
 use crate::common::base::MemReq;
 use crate::common::base::MemResp;
 use crate::common::base::SimErr;
 use crate::common::base::ThreadBlocks;
+use crate::common::protocol::GluonCommand;
+use crate::common::protocol::GluonEvent;
+use crate::common::protocol::KernelCommand;
+use crate::context::Context;
 use crate::glug::engine::Engine;
-use crate::glug::engine::EngineCommand;
+use crate::glug::engines::printf_unit::PrintfUnit;
+use crate::glug::engines::tb_scheduler::{TbScheduler, TbSchedulerPolicy};
+use crate::glul::glul::GLULConfig;
 use crate::glul::glul::GLULReq;
 use crate::glul::glul::GLULStatus;
 use cyclotron::info;
@@ -27,37 +34,34 @@ pub enum KernelEngineState {
     S5,
 }
 
-#[derive(Default, Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 #[serde(default)]
-pub struct KernelEngineConfig {}
-
-#[derive(Debug, Default, Clone, Copy)]
-pub struct KernelCommand {
-    pub sid: u8,
-    pub host_addr: u32,
-    pub sz: u32,
-    pub gpu_addr: u32,
+pub struct KernelEngineConfig {
+    /// Cycles between printf ring drains while a kernel runs; 0 only drains once it has finished.
+    pub printf_drain_interval: u64,
+    /// Which GLUL thread blocks are dispatched to, and how many at once.
+    pub tb_scheduler: TbSchedulerPolicy,
+    /// Cycles a launch may take, from the payload DMA until its thread blocks complete, before it completes
+    /// with `TIMEOUT`, for launches whose payload does not set a budget; 0 disables the watchdog.
+    pub timeout_cycles: u64,
 }
 
-impl KernelCommand {
-    pub fn from_engine_cmd(cmd: EngineCommand) -> Self {
-        let payload = cmd.bytes();
-
-        let host_addr = u32::from_le_bytes([payload[0], payload[1], payload[2], payload[3]]);
-        let sz = u32::from_le_bytes([payload[4], payload[5], payload[6], payload[7]]);
-        let gpu_addr = u32::from_le_bytes([payload[8], payload[9], payload[10], payload[11]]);
-
-        KernelCommand {
-            sid: cmd.sid(),
-            host_addr,
-            sz,
-            gpu_addr,
+impl Default for KernelEngineConfig {
+    fn default() -> Self {
+        Self {
+            printf_drain_interval: 1024,
+            tb_scheduler: TbSchedulerPolicy::SMALLEST,
+            timeout_cycles: 0,
         }
     }
+}
 
-    pub fn sid(&self) -> u8 {
-        self.sid
-    }
+#[derive(Debug, Clone)]
+pub enum KernelErr {
+    EXECUTION(ExecErr),
+    MEM(MemErr),
+    /// The kernel ran longer than its budget of cycles.
+    TIMEOUT(u64),
 }
 
 #[derive(Default, Clone, Copy)]
@@ -73,7 +77,11 @@ pub struct KernelPayload {
     regs_per_thread: u8,
     shmem_per_block: u32,
     flags: u8,
-    printf_host_addr: u32,
+    printf_host_addr: u64,
+    /// Device address of the printf ring header, 0 if the kernel has no ring.
+    printf_ring_addr: u64,
+    /// Watchdog budget in cycles, 0 for the `kernel_engine.timeout_cycles` default.
+    timeout_cycles: u32,
 }
 
 impl fmt::Debug for KernelPayload {
@@ -90,7 +98,9 @@ impl fmt::Debug for KernelPayload {
             .field("regs_per_thread", &self.regs_per_thread)
             .field("shmem_per_block", &self.shmem_per_block)
             .field("flags", &self.flags)
-            .field("printf_host_addr", &format_args!("0x{:08x}", self.printf_host_addr))
+            .field("printf_host_addr", &format_args!("0x{:016x}", self.printf_host_addr))
+            .field("printf_ring_addr", &format_args!("0x{:016x}", self.printf_ring_addr))
+            .field("timeout_cycles", &self.timeout_cycles)
             .finish()
     }
 }
@@ -113,10 +123,12 @@ impl KernelPayload {
             u32::from_le_bytes([bytes[40], bytes[41], bytes[42], bytes[43]]),
             u32::from_le_bytes([bytes[44], bytes[45], bytes[46], bytes[47]]),
         );
-        let printf_host_addr = u32::from_le_bytes([bytes[48], bytes[49], bytes[50], bytes[51]]);
-        let regs_per_thread = bytes[52];
-        let shmem_per_block = u32::from_le_bytes([bytes[53], bytes[54], bytes[55], bytes[56]]);
-        let flags = bytes[57];
+        let printf_host_addr = u64::from_le_bytes(bytes[48..56].try_into().expect("impossible"));
+        let regs_per_thread = bytes[56];
+        let shmem_per_block = u32::from_le_bytes([bytes[57], bytes[58], bytes[59], bytes[60]]);
+        let flags = bytes[61];
+        let printf_ring_addr = u64::from_le_bytes(bytes[62..70].try_into().expect("impossible"));
+        let timeout_cycles = u32::from_le_bytes([bytes[70], bytes[71], bytes[72], bytes[73]]);
         KernelPayload {
             start_pc,
             kernel_pc,
@@ -130,6 +142,8 @@ impl KernelPayload {
             shmem_per_block,
             flags,
             printf_host_addr,
+            printf_ring_addr,
+            timeout_cycles,
         }
     }
 }
@@ -149,15 +163,29 @@ pub struct KernelEngine {
     tb_done: u32,
 
     gluls: Vec<GLULStatus>,
+    /// GLULs the engine may dispatch to this cycle.
+    glul_grants: Vec<bool>,
     glul_req: GLULReq,
+    scheduler: Box<dyn TbScheduler>,
+    printf: PrintfUnit,
+
+    /// Default watchdog budget, and the budget and cycles since launch of the current kernel. The
+    /// default applies until the payload has been read.
+    timeout_cycles: u64,
+    budget: u64,
+    run_cycles: u64,
+    /// The kernel's thread blocks must be killed.
+    kill: bool,
 
-    err: Option<Result<(), ExecErr>>,
+    /// Execution error held back until the printf ring has been drained.
+    exec_err: Option<KernelErr>,
+    err: Option<Result<(), KernelErr>>,
 
     logger: Arc<Logger>,
 }
 
 impl Configurable<KernelEngineConfig> for KernelEngine {
-    fn new(_config: &KernelEngineConfig) -> Self {
+    fn new(config: &KernelEngineConfig) -> Self {
         KernelEngine {
             cmd: None,
             dma_req: None,
@@ -170,7 +198,15 @@ impl Configurable<KernelEngineConfig> for KernelEngine {
             total_tb: 0,
             tb_done: 0,
             gluls: vec![],
+            glul_grants: vec![],
             glul_req: GLULReq::default(),
+            scheduler: config.tb_scheduler.scheduler(),
+            printf: PrintfUnit::new(config.printf_drain_interval),
+            timeout_cycles: config.timeout_cycles,
+            budget: 0,
+            run_cycles: 0,
+            kill: false,
+            exec_err: None,
             err: None,
             logger: Arc::new(Logger::new(0)),
         }
@@ -178,8 +214,11 @@ impl Configurable<KernelEngineConfig> for KernelEngine {
 }
 
 impl Engine for KernelEngine {
-    fn set_cmd(&mut self, cmd: EngineCommand) {
-        self.cmd = Some(KernelCommand::from_engine_cmd(cmd));
+    fn set_cmd(&mut self, cmd: GluonCommand) {
+        match cmd {
+            GluonCommand::KERNEL(cmd) => self.cmd = Some(cmd),
+            other => panic!("Kernel engine: cannot execute {:?}", other),
+        }
     }
 
     fn busy(&self) -> bool {
@@ -191,6 +230,7 @@ impl Engine for KernelEngine {
     }
 
     fn set_logger(&mut self, logger: Arc<Logger>) {
+        self.printf.set_logger(logger.clone());
         self.logger = logger;
     }
 
@@ -198,20 +238,45 @@ impl Engine for KernelEngine {
         self.gluls = gluls
     }
 
+    fn set_contexts(&mut self, _: Vec<Context>) {}
+
+    fn wants_gluls(&self) -> Vec<bool> {
+        if matches!(self.state, KernelEngineState::S3) && self.tb_ctr < self.total_tb {
+            self.gluls.iter().map(|glul| self.tbs_per_glul(&glul.config) > 0).collect()
+        } else {
+            vec![]
+        }
+    }
+
+    fn set_glul_grants(&mut self, grants: Vec<bool>) {
+        self.glul_grants = grants;
+    }
+
+    // The printf unit shares the engine's memory and DMA ports; the engine itself only uses them before
+    // the kernel starts.
     fn get_dma_req(&self) -> Option<&DMAReq> {
-        self.dma_req.as_ref()
+        self.dma_req.as_ref().or(self.printf.get_dma_req())
     }
 
-    fn done_dma_req(&mut self) {
-        self.dma_req.as_mut().expect("Kernel engine: DMA req not set").done = true;
+    fn done_dma_req(&mut self, result: Result<(), MemErr>) {
+        let Some(dma_req) = self.dma_req.as_mut() else {
+            self.printf.done_dma_req(result);
+            return;
+        };
+        dma_req.done = true;
+        dma_req.err = result.err();
     }
 
     fn get_mem_req(&self) -> Option<&MemReq> {
-        self.mem_req.as_ref()
+        self.mem_req.as_ref().or(self.printf.get_mem_req())
     }
 
-    fn set_mem_resp(&mut self, data: Option<&Vec<u8>>) {
-        self.mem_resp = data.map(|bytes| MemResp { data: Some(bytes.clone()) });
+    fn set_mem_resp(&mut self, resp: &MemResp) {
+        if self.mem_req.is_none() {
+            self.printf.set_mem_resp(resp);
+            return;
+        }
+        self.mem_resp = Some(resp.clone());
     }
 
     fn get_glul_req(&self) -> Option<&GLULReq> {
@@ -234,57 +299,122 @@ impl Engine for KernelEngine {
     fn notify_glul_err(&mut self, err: ExecErr) {
         assert_ne!(self.total_tb, 0);
         assert_ne!(self.tb_ctr, 0);
-        self.state = KernelEngineState::S5;
-        self.err = Some(Err(err));
+        // The kernel's thread blocks are killed; drop a dispatch that has not reached its GLUL yet
+        self.glul_req.thread_blocks = None;
+        if self.exec_err.is_none() {
+            self.exec_err = Some(KernelErr::EXECUTION(err));
+            self.printf.drain();
+        }
+        self.state = KernelEngineState::S4;
+    }
+
+    fn take_kill(&mut self) -> bool {
+        std::mem::take(&mut self.kill)
     }
 
-    fn get_completion(&self) -> Option<Event> {
-        self.err
-            .as_ref()
-            .map(|err| {
-                Event::from_kernel_err(
-                    self.cmd.expect("Command not set, no completion exists").sid,
-                    err.clone(),
-                )
-            })
+    fn get_completion(&self) -> Option<GluonEvent> {
+        self.err.as_ref().map(|err| {
+            let sid = self.cmd.expect("Command not set, no completion exists").sid;
+            match err {
+                Ok(()) => GluonEvent::OK { sid },
+                Err(KernelErr::EXECUTION(err)) => GluonEvent::from_kernel_err(sid, Err(err.clone())),
+                Err(KernelErr::MEM(err)) => GluonEvent::from_mem_err(sid, Err(*err)),
+                Err(KernelErr::TIMEOUT(cycles)) => GluonEvent::TIMEOUT { sid, cycles: *cycles },
+            }
+        })
+    }
+}
+
+impl KernelEngine {
+    /// Count a cycle of the launch in progress. True once it has run past its budget, until a failure
+    /// has been recorded. The final printf drain in S4 is not counted: the kernel has already stopped.
+    fn count_cycle(&mut self) -> bool {
+        if !matches!(self.state, KernelEngineState::S1 | KernelEngineState::S2 | KernelEngineState::S3) {
+            return false;
+        }
+        self.run_cycles += 1;
+        self.budget != 0 && self.run_cycles > self.budget && self.exec_err.is_none()
+    }
+
+    /// Thread blocks of the current kernel that fit on a GLUL at once.
+    fn tbs_per_glul(&self, glul_cfg: &GLULConfig) -> usize {
+        let threads_per_block = self.kernel_payload.block.0
+            * self.kernel_payload.block.1
+            * self.kernel_payload.block.2;
+        let warps_per_tb = (threads_per_block / glul_cfg.num_lanes as u32).max(1);
+        let cores_per_tb = (warps_per_tb as f32 / glul_cfg.num_warps as f32).ceil() as usize;
+        (glul_cfg.num_cores / cores_per_tb).min(
+            glul_cfg.regs_per_core * glul_cfg.num_cores
+                / (self.kernel_payload.regs_per_thread as usize * glul_cfg.num_lanes)
+                    .min(glul_cfg.shmem / self.kernel_payload.shmem_per_block as usize),
+        )
     }
 }
 
 impl Clocked for KernelEngine {
     fn tick(&mut self) -> Result<(), SimErr> {
+        self.printf.tick(matches!(self.state, KernelEngineState::S3));
+        let expired = self.count_cycle();
+        if expired {
+            info!(self.logger, "Kernel engine: launch exceeded its budget of {} cycles", self.budget);
+        }
+
         match &self.state {
             KernelEngineState::S0 => {
                 if let Some(cmd) = &self.cmd {
                     self.state = KernelEngineState::S1;
                     self.tb_ctr = 0;
                     self.tb_done = 0;
+                    self.budget = self.timeout_cycles;
+                    self.run_cycles = 0;
                      info!(
                         self.logger,
-                        "Init kernel engine: id={} host=0x{:08x} size=0x{:08x} gpu=0x{:08x}",
+                        "Init kernel engine: id={} host=0x{:016x} size=0x{:08x} gpu=0x{:016x}",
                         cmd.sid, cmd.host_addr, cmd.sz, cmd.gpu_addr
                     );
+                    // The cores are 32-bit and get the payload address as their base pointer.
+                    if u32::try_from(cmd.gpu_addr).is_err() {
+                        info!(self.logger, "Kernel engine: payload at 0x{:016x} is not addressable by the cores", cmd.gpu_addr);
+                        self.err = Some(Err(KernelErr::MEM(MemErr {
+                            code: MemErrCode::RANGE,
+                            addr: cmd.gpu_addr,
+                        })));
+                        self.state = KernelEngineState::S5;
+                    }
                 }
             }
 
             KernelEngineState::S1 => {
+                // A timeout waits for the DMA in flight, which the engine cannot withdraw.
+                if expired {
+                    self.exec_err = Some(KernelErr::TIMEOUT(self.budget));
+                }
                 if let Some(dma_req) = &self.dma_req {
                     if dma_req.done {
+                        if let Some(err) = dma_req.err {
+                            info!(self.logger, "Kernel engine: payload DMA failed {:?}", err);
+                            self.err = Some(Err(KernelErr::MEM(err)));
+                            self.state = KernelEngineState::S5;
+                        } else if self.exec_err.is_some() {
+                            self.state = KernelEngineState::S4;
+                        } else {
+                            self.state = KernelEngineState::S2;
+                        }
                         self.dma_req = None;
-                        self.state = KernelEngineState::S2;
                     }
+                } else if self.exec_err.is_some() {
+                    self.state = KernelEngineState::S4;
                 } else {
-                    let mut dma_req = DMAReq::default();
-                    dma_req.done = false;
-                    dma_req.dir = DMADir::H2D;
-                    dma_req.src_addr = self
-                        .cmd
-                        .expect("Unreachable:Kernel command not set")
-                        .host_addr;
-                    dma_req.target_addr = self
-                        .cmd
-                        .expect("Unreachable:Kernel command not set")
-                        .gpu_addr;
-                    dma_req.sz = self.cmd.expect("Unreachable:Kernel command not set").sz;
+                    let cmd = self.cmd.expect("Unreachable:Kernel command not set");
+                    let dma_req = DMAReq {
+                        done: false,
+                        sid: cmd.sid,
+                        dir: DMADir::H2D,
+                        src_addr: cmd.host_addr,
+                        target_addr: cmd.gpu_addr,
+                        sz: cmd.sz,
+                        ..DMAReq::default()
+                    };
                     self.dma_req = Some(dma_req);
                     info!(
                         self.logger,
@@ -294,22 +424,47 @@ impl Clocked for KernelEngine {
             }
 
             KernelEngineState::S2 => {
+                if expired {
+                    self.exec_err = Some(KernelErr::TIMEOUT(self.budget));
+                }
                 if self.mem_req.is_some() {
-                    if self.mem_resp.is_some() {
+                    if let Some(err) = self.mem_resp.as_ref().and_then(|resp| resp.err) {
+                        info!(self.logger, "Kernel engine: payload read failed {:?}", err);
+                        self.mem_req = None;
+                        self.mem_resp = None;
+                        self.err = Some(Err(KernelErr::MEM(err)));
+                        self.state = KernelEngineState::S5;
+                    } else if self.exec_err.is_some() && self.mem_resp.is_some() {
                         self.mem_req = None;
-                        self.kernel_payload = KernelPayload::from_bytes(&self.mem_resp.as_ref().expect("Unreachable:Kernel mem resp not set").data.as_ref().expect("Unreachable:Kernel mem resp no data"));
+                        self.mem_resp = None;
+                        self.state = KernelEngineState::S4;
+                    } else if let Some(resp) = self.mem_resp.take() {
+                        self.mem_req = None;
+                        self.kernel_payload = KernelPayload::from_bytes(resp.data.as_ref().expect("Unreachable:Kernel mem resp no data"));
                         info!(
                             self.logger,
                             "Received kernel payload: {:?}", self.kernel_payload
                         );
-                        self.total_tb = self.kernel_payload.grid.0 as u32
-                            * self.kernel_payload.grid.1 as u32
-                            * self.kernel_payload.grid.2 as u32;
-                        self.tb_size = self.kernel_payload.block.0 as u32
-                            * self.kernel_payload.block.1 as u32
-                            * self.kernel_payload.block.2 as u32;
+                        self.total_tb = self.kernel_payload.grid.0
+                            * self.kernel_payload.grid.1
+                            * self.kernel_payload.grid.2;
+                        self.tb_size = self.kernel_payload.block.0
+                            * self.kernel_payload.block.1
+                            * self.kernel_payload.block.2;
+                        self.scheduler.start(self.total_tb, self.gluls.len());
+                        self.budget = match self.kernel_payload.timeout_cycles {
+                            0 => self.timeout_cycles,
+                            cycles => cycles as u64,
+                        };
+                        self.printf.init(
+                            self.cmd.expect("Unreachable:Kernel command not set").sid,
+                            self.kernel_payload.printf_ring_addr,
+                            self.kernel_payload.printf_host_addr,
+                        );
                         self.state = KernelEngineState::S3;
                     }
+                } else if self.exec_err.is_some() {
+                    self.state = KernelEngineState::S4;
                 } else {
                     self.mem_req = Some(MemReq {
                         addr: self
@@ -317,6 +472,7 @@ impl Clocked for KernelEngine {
                             .expect("Unreachable:Kernel command not set")
                             .gpu_addr,
                         write: false,
+                        swap: false,
                         bytes: size_of::<KernelPayload>() as u32,
                         data: vec![],
                     });
@@ -330,69 +486,62 @@ impl Clocked for KernelEngine {
             KernelEngineState::S3 => {
                 let available_tbs = self.total_tb - self.tb_ctr;
 
-                if available_tbs > 0 {
-                    let threads_per_block = self.kernel_payload.block.0 as u32
-                        * self.kernel_payload.block.1 as u32
-                        * self.kernel_payload.block.2 as u32;
-                    if let Some((glul_if_idx, n_tb)) = self
+                // Thread blocks that completed in time are not killed, even if the budget runs out as
+                // the last of them is acknowledged.
+                if expired && self.tb_done < self.total_tb {
+                    self.exec_err = Some(KernelErr::TIMEOUT(self.budget));
+                    self.glul_req.thread_blocks = None;
+                    self.kill = true;
+                    self.printf.drain();
+                    self.state = KernelEngineState::S4;
+                } else if available_tbs > 0 {
+                    let free = self
                         .gluls
                         .iter()
-                        .filter(|glul| !*glul.busy.read().expect("GLUL busy poisoned"))
                         .enumerate()
+                        .filter(|(idx, _)| self.glul_grants.get(*idx).copied().unwrap_or(false))
                         .map(|(idx, glul)| {
-                            let glul_cfg = glul.config;
-                            let warps_per_tb = (threads_per_block / glul_cfg.num_lanes as u32).max(1);
-                            let cores_per_tb = (warps_per_tb as f32 / glul_cfg.num_warps as f32).ceil() as usize;
-                            (
-                                idx,
-                                (glul_cfg.num_cores
-                                    / cores_per_tb).min(
-                                        glul_cfg.regs_per_core * glul_cfg.num_cores
-                                            / (self.kernel_payload.regs_per_thread as usize
-                                                * glul_cfg.num_lanes)
-                                                .min(
-                                                    glul_cfg.shmem
-                                                        / self.kernel_payload.shmem_per_block as usize,
-                                                ),
-                                    ),
-                            )
+                            let running = *glul.tbs.read().expect("GLUL tbs poisoned");
+                            (idx, (self.tbs_per_glul(&glul.config) as u32).saturating_sub(running))
                         })
-                        .filter(|(_, value)| *value > 0)
-                        .min_by_key(|(_, value)| *value)
-                    {
-                        let n_tb_req = (n_tb as u32).min(available_tbs);
-                        let mut block_idxs = Vec::new();
-                        for _ in 0..n_tb_req {
-                            let gx = self.kernel_payload.grid.0;
-                            let gy = self.kernel_payload.grid.1;
-                            let plane = gx * gy;
-                            let block_z = self.tb_ctr / plane;
-                            let rem = self.tb_ctr % plane;
-                            let block_y = rem / gx;
-                            let block_x = rem % gx;
-                            block_idxs.push((block_x, block_y, block_z));
-                            self.tb_ctr += 1;
-                        }
+                        .filter(|(_, slots)| *slots > 0)
+                        .collect::<Vec<_>>();
+                    if let Some(dispatch) = self.scheduler.pick(&free) {
+                        let gx = self.kernel_payload.grid.0;
+                        let plane = gx * self.kernel_payload.grid.1;
+                        let block_idxs = (dispatch.first..dispatch.first + dispatch.n)
+                            .map(|tb| (tb % plane % gx, tb % plane / gx, tb / plane))
+                            .collect();
+                        self.tb_ctr += dispatch.n;
                         self.glul_req.thread_blocks = Some(ThreadBlocks {
                             pc: self.kernel_payload.start_pc,
-                            block_idxs: block_idxs,
+                            block_idxs,
                             block_dim: self.kernel_payload.block,
                             regs: self.kernel_payload.regs_per_thread as u32,
                             shmem: self.kernel_payload.shmem_per_block,
-                            bp: self.cmd.expect("Unreachable: Kernel command not set").gpu_addr,
+                            bp: self.cmd.expect("Unreachable: Kernel command not set").gpu_addr as u32,
                         });
-                        self.glul_req.idx = glul_if_idx;
+                        self.glul_req.idx = dispatch.glul;
                     }
                 }
 
                 if self.tb_done == self.total_tb {
+                    self.printf.drain();
                     self.state = KernelEngineState::S4;
                 }
             }
 
             KernelEngineState::S4 => {
-                self.state = KernelEngineState::S5;
-                self.err = Some(Ok(()));
+                // A drain already under way is finished, so the output printed so far reaches the host.
+                // The kernel has stopped, so a printf lock one of its warps held is taken over.
+                if self.printf.idle() {
+                    self.err = Some(match (self.exec_err.take(), self.printf.err()) {
+                        (Some(err), _) => Err(err),
+                        (None, Some(err)) => Err(KernelErr::MEM(err)),
+                        (None, None) => Ok(()),
+                    });
+                    self.state = KernelEngineState::S5;
+                }
             }
 
             KernelEngineState::S5 => {
@@ -406,6 +555,152 @@ impl Clocked for KernelEngine {
     }
 
     fn busy(&mut self) -> bool {
-        false
+        self.cmd.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAYLOAD_ADDR: u64 = 0x2000;
+    const RING: u64 = 0x4000;
+
+    /// Launch a kernel of 8 single-warp thread blocks, each running `tb_cycles` cycles, on one GLUL and
+    /// serve it to completion. `ring_lock` gives the kernel a printf ring whose lock word holds it.
+    /// Returns the completion, the cycles the watchdog counted and whether the thread blocks were killed.
+    fn run(config: KernelEngineConfig, budget: u32, tb_cycles: u64, ring_lock: Option<u32>) -> (GluonEvent, u64, bool) {
+        let mut payload = [0u8; 128];
+        payload[24..28].copy_from_slice(&8u32.to_le_bytes());
+        for dim in [28, 32] {
+            payload[dim..dim + 4].copy_from_slice(&1u32.to_le_bytes());
+        }
+        payload[36..40].copy_from_slice(&16u32.to_le_bytes());
+        payload[40..44].copy_from_slice(&1u32.to_le_bytes());
+        payload[44..48].copy_from_slice(&1u32.to_le_bytes());
+        payload[56] = 1;
+        payload[57..61].copy_from_slice(&1u32.to_le_bytes());
+        if ring_lock.is_some() {
+            payload[62..70].copy_from_slice(&RING.to_le_bytes());
+        }
+        payload[70..74].copy_from_slice(&budget.to_le_bytes());
+
+        // Ring header: lock, head, tail, limit.
+        let mut ring = [ring_lock.unwrap_or(0), 0, 8, 64].iter().flat_map(|word| word.to_le_bytes()).collect::<Vec<_>>();
+
+        let mut engine = KernelEngine::new(&config);
+        let glul = GLULStatus::new(&GLULConfig::default());
+        engine.set_gluls(vec![glul.clone()]);
+        engine.set_cmd(GluonCommand::KERNEL(KernelCommand {
+            sid: 1,
+            host_addr: 0x1000,
+            sz: payload.len() as u32,
+            gpu_addr: PAYLOAD_ADDR,
+        }));
+        let mut running = vec![];
+        let mut killed = false;
+        for cycle in 0..100_000u64 {
+            engine.set_glul_grants(vec![true]);
+            engine.tick().unwrap();
+            if let Some(event) = engine.get_completion() {
+                if let Some(lock) = ring_lock {
+                    // The final drain ran and released the lock, whoever held it.
+                    assert_eq!(ring[..4], [0; 4], "lock {lock} not released");
+                }
+                return (event, engine.run_cycles, killed);
+            }
+
+            if engine.take_kill() {
+                killed = true;
+                running.clear();
+                *glul.tbs.write().unwrap() = 0;
+            }
+            if let Some(req) = engine.get_glul_req() {
+                let tbs = req.thread_blocks.as_ref().unwrap().block_idxs.len() as u32;
+                running.push((cycle + tb_cycles, tbs));
+                *glul.tbs.write().unwrap() += tbs;
+                engine.clear_glul_req();
+            }
+            running.retain(|&(done, tbs)| {
+                if done > cycle {
+                    return true;
+                }
+                engine.notify_glul_done(tbs);
+                *glul.tbs.write().unwrap() -= tbs;
+                false
+            });
+
+            if engine.get_dma_req().is_some_and(|req| !req.done) {
+                engine.done_dma_req(Ok(()));
+            }
+            if let Some(req) = engine.get_mem_req().cloned() {
+                let data = if req.addr == PAYLOAD_ADDR {
+                    payload.iter().copied().chain(std::iter::repeat(0)).take(req.bytes as usize).collect()
+                } else {
+                    let offset = (req.addr - RING) as usize;
+                    let old = ring[offset..offset + req.bytes as usize].to_vec();
+                    if req.write {
+                        ring[offset..offset + req.data.len()].copy_from_slice(&req.data);
+                    }
+                    old
+                };
+                engine.set_mem_resp(&MemResp { data: Some(data), err: None });
+            }
+        }
+        panic!("kernel did not complete");
+    }
+
+    fn with_timeout(timeout_cycles: u64) -> KernelEngineConfig {
+        KernelEngineConfig {
+            timeout_cycles,
+            ..KernelEngineConfig::default()
+        }
+    }
+
+    #[test]
+    fn expires_in_s3() {
+        let (event, cycles, killed) = run(with_timeout(50), 0, 1000, None);
+        assert_eq!(event, GluonEvent::TIMEOUT { sid: 1, cycles: 50 });
+        assert_eq!(cycles, 51);
+        assert!(killed);
+    }
+
+    #[test]
+    fn completes_under_budget() {
+        let (event, cycles, killed) = run(with_timeout(0), 0, 40, None);
+        assert_eq!(event, GluonEvent::OK { sid: 1 });
+        assert!(!killed);
+        // The last counted cycle is the one that sees every thread block done, so a budget one short
+        // of it still completes; one more cycle short kills the last thread blocks.
+        let (event, _, killed) = run(with_timeout(cycles - 1), 0, 40, None);
+        assert_eq!((event, killed), (GluonEvent::OK { sid: 1 }, false));
+        let (event, _, killed) = run(with_timeout(cycles - 2), 0, 40, None);
+        assert_eq!((event, killed), (GluonEvent::TIMEOUT { sid: 1, cycles: cycles - 2 }, true));
+    }
+
+    #[test]
+    fn final_drain_not_counted() {
+        let (_, cycles, _) = run(with_timeout(0), 0, 40, None);
+        // The drain after the thread blocks complete takes several more cycles.
+        let (event, _, killed) = run(with_timeout(cycles), 0, 40, Some(0));
+        assert_eq!((event, killed), (GluonEvent::OK { sid: 1 }, false));
+    }
+
+    #[test]
+    fn payload_budget_overrides_default() {
+        let (event, _, _) = run(with_timeout(10_000), 60, 1000, None);
+        assert_eq!(event, GluonEvent::TIMEOUT { sid: 1, cycles: 60 });
+        let (event, _, _) = run(with_timeout(40), 5000, 100, None);
+        assert_eq!(event, GluonEvent::OK { sid: 1 });
+        let (event, _, _) = run(with_timeout(40), 0, 100, None);
+        assert_eq!(event, GluonEvent::TIMEOUT { sid: 1, cycles: 40 });
+    }
+
+    #[test]
+    fn killed_with_printf_lock_held() {
+        // A warp of the killed kernel held the ring lock; the final drain must not wait for it.
+        let (event, _, killed) = run(with_timeout(50), 0, 1000, Some(1));
+        assert_eq!(event, GluonEvent::TIMEOUT { sid: 1, cycles: 50 });
+        assert!(killed);
     }
 }
@@ -1,6 +1,8 @@
 use crate::{
-    common::base::{Clocked, CmdType, Configurable, DMADir, DMAReq, Event, MemReq, MemResp, SimErr},
-    glug::engine::{Engine, EngineCommand},
+    common::base::{Clocked, CmdType, Configurable, DMADir, DMAReq, MemErr, MemErrCode, MemReq, MemResp, SimErr},
+    common::protocol::{ChainCommand, CopyCommand, GluonCommand, GluonEvent, SetCommand, StridedCommand},
+    context::Context,
+    glug::engine::Engine,
     glul::glul::{GLULReq, GLULStatus},
 };
 use cyclotron::{info, muon::warp::ExecErr};
@@ -8,9 +10,24 @@ use cyclotron::sim::log::Logger;
 use std::sync::Arc;
 use serde::Deserialize;
 
-#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[derive(Debug, Clone, Copy, Deserialize)]
 #[serde(default)]
-pub struct MemEngineConfig {}
+pub struct MemEngineConfig {
+    /// Bytes per DRAM access of device-to-device copies and memsets.
+    #[serde(alias = "d2d_chunk_bytes")]
+    pub chunk_bytes: u32,
+    /// Longest descriptor chain; a longer one, such as a chain that loops, fails with `MEM (5)`.
+    pub max_chain_descs: u32,
+}
+
+impl Default for MemEngineConfig {
+    fn default() -> Self {
+        Self {
+            chunk_bytes: 256,
+            max_chain_descs: 1024,
+        }
+    }
+}
 
 #[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
 pub enum MemEngineState {
@@ -19,103 +36,159 @@ pub enum MemEngineState {
     C0,
     C1,
     C2,
+    /// Device-to-device copy: read the next chunk.
+    D0,
+    /// Device-to-device copy: write the chunk back.
+    D1,
+    /// Device-to-device copy: wait for the write.
+    D2,
+    /// Strided copy: wait for the descriptor.
+    T0,
+    /// Descriptor chain: wait for the next descriptor.
+    G0,
     S0,
     S1,
 }
 
+/// Byte size of a strided copy descriptor.
+pub const STRIDED_DESC_BYTES: u32 = 60;
+
+/// Geometry of a strided copy, read from device memory:
+/// `{ src @0, dst @8, src_pitch @16, dst_pitch @24, src_slice_pitch @32, dst_slice_pitch @40: u64,
+/// width @48, height @52, depth @56: u32 }`. `width` is in bytes.
 #[derive(Debug, Default, Clone, Copy)]
-pub enum MemOp {
-    #[default]
-    COPY,
-    SET,
+pub struct StridedDesc {
+    pub src: u64,
+    pub dst: u64,
+    pub src_pitch: u64,
+    pub dst_pitch: u64,
+    pub src_slice_pitch: u64,
+    pub dst_slice_pitch: u64,
+    pub width: u32,
+    pub height: u32,
+    pub depth: u32,
 }
 
-impl From<u8> for MemOp {
-    fn from(value: u8) -> Self {
-        match value {
-            0 => MemOp::COPY,
-            1 => MemOp::SET,
-            _ => panic!("Invalid mem op"),
+impl StridedDesc {
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        let u64_at = |offset: usize| u64::from_le_bytes(bytes[offset..offset + 8].try_into().expect("Mem engine: short descriptor"));
+        let u32_at = |offset: usize| u32::from_le_bytes(bytes[offset..offset + 4].try_into().expect("Mem engine: short descriptor"));
+        StridedDesc {
+            src: u64_at(0),
+            dst: u64_at(8),
+            src_pitch: u64_at(16),
+            dst_pitch: u64_at(24),
+            src_slice_pitch: u64_at(32),
+            dst_slice_pitch: u64_at(40),
+            width: u32_at(48),
+            height: u32_at(52),
+            depth: u32_at(56),
         }
     }
-}
-
-#[derive(Debug, Default, Clone, Copy)]
-pub struct MemCommand {
-    pub sid: u8,
-    pub op: MemOp,
-    pub bytes: [u8; 13],
-}
-
-#[derive(Debug, Default, Clone, Copy)]
-pub struct CopyCommand {
-    pub src: u32,
-    pub dst: u32,
-    pub len: u32,
-    pub flags: u8,
-}
 
-impl CopyCommand {
-    pub fn from_bytes(bytes: [u8; 13]) -> Self {
-        CopyCommand {
-            src: u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
-            dst: u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]),
-            len: u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]),
-            flags: bytes[12],
+    fn rows(&self) -> u64 {
+        if self.width == 0 {
+            0
+        } else {
+            self.height as u64 * self.depth as u64
         }
     }
+
+    /// Source and destination of row `row`, counting rows of every slice in order; `None` if either
+    /// lies beyond the 64-bit address space.
+    fn row(&self, row: u64) -> Option<(u64, u64)> {
+        let (z, y) = (row / self.height as u64, row % self.height as u64);
+        let offset = |slice_pitch: u64, pitch: u64| z.checked_mul(slice_pitch)?.checked_add(y.checked_mul(pitch)?);
+        Some((
+            self.src.checked_add(offset(self.src_slice_pitch, self.src_pitch)?)?,
+            self.dst.checked_add(offset(self.dst_slice_pitch, self.dst_pitch)?)?,
+        ))
+    }
 }
 
+/// Byte size of a chain descriptor.
+pub const CHAIN_DESC_BYTES: u32 = 32;
+
+/// One link of a descriptor chain: `{ src @0, dst @8, next @16: u64, len @24, flags @28: u32 }`.
+/// `flags` select the direction as for `COPY`; `next` is the address of the next descriptor in the same
+/// memory as this one, 0 ends the chain.
 #[derive(Debug, Default, Clone, Copy)]
-pub struct SetCommand {
-    pub dst: u32,
-    pub value: u32,
+pub struct ChainDesc {
+    pub src: u64,
+    pub dst: u64,
+    pub next: u64,
     pub len: u32,
-    pub flags: u8,
+    pub flags: u32,
 }
 
-impl SetCommand {
-    pub fn from_bytes(bytes: [u8; 13]) -> Self {
-        SetCommand {
-            dst: u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
-            value: u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]),
-            len: u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]),
-            flags: bytes[12],
+impl ChainDesc {
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        let u64_at = |offset: usize| u64::from_le_bytes(bytes[offset..offset + 8].try_into().expect("Mem engine: short descriptor"));
+        let u32_at = |offset: usize| u32::from_le_bytes(bytes[offset..offset + 4].try_into().expect("Mem engine: short descriptor"));
+        ChainDesc {
+            src: u64_at(0),
+            dst: u64_at(8),
+            next: u64_at(16),
+            len: u32_at(24),
+            flags: u32_at(28),
         }
     }
-}
 
-impl MemCommand {
-    pub fn from_engine_cmd(cmd: EngineCommand) -> Self {
-        let engine_bytes = cmd.bytes();
-
-        let op = MemOp::from(engine_bytes[0]);
-        let bytes = engine_bytes[1..14].try_into().unwrap();
+    fn d2d(&self) -> bool {
+        self.flags & 2 != 0
+    }
 
-        MemCommand {
-            sid: cmd.sid(),
-            op,
-            bytes,
-        }
+    fn d2h(&self) -> bool {
+        !self.d2d() && self.flags & 1 != 0
     }
 }
 
+#[derive(Debug, Default, Clone, Copy)]
+struct Transfer {
+    src: u64,
+    dst: u64,
+    len: u32,
+    d2d: bool,
+    dir: DMADir,
+}
+
 pub struct MemEngine {
-    cmd: Option<MemCommand>,
+    cmd: Option<GluonCommand>,
 
     dma_req: Option<DMAReq>,
     mem_req: Option<MemReq>,
     mem_resp: Option<MemResp>,
 
     state: MemEngineState,
-    err: Option<Result<(), String>>, // TODO mem errors
-
+    err: Option<Result<(), MemErr>>,
+
+    /// Contiguous transfer in progress: the whole copy, or one row of a strided copy.
+    xfer: Transfer,
+    /// Descriptor of the strided copy in progress and the next row to transfer.
+    strided: Option<(StridedDesc, u64)>,
+    /// Address of the descriptor after the one in progress in a chain, 0 at the end of the chain.
+    chain_next: Option<u64>,
+    /// Descriptors of the chain fetched so far.
+    chain_descs: u32,
+
+    chunk_bytes: u32,
+    max_chain_descs: u32,
+    /// Bytes of the device-to-device transfer or memset done so far, and the offset of the chunk in flight.
+    progress: u32,
+    chunk_offset: u32,
+
+    contexts: Vec<Context>,
     logger: Arc<Logger>,
 }
 
 impl Engine for MemEngine {
-    fn set_cmd(&mut self, cmd: EngineCommand) {
-        self.cmd = Some(MemCommand::from_engine_cmd(cmd));
+    fn set_cmd(&mut self, cmd: GluonCommand) {
+        match cmd {
+            GluonCommand::COPY(_) | GluonCommand::SET(_) | GluonCommand::STRIDED(_) | GluonCommand::CHAIN(_) => {
+                self.cmd = Some(cmd)
+            }
+            other => panic!("Mem engine: cannot execute {:?}", other),
+        }
     }
 
     fn busy(&self) -> bool {
@@ -134,17 +207,18 @@ impl Engine for MemEngine {
         self.dma_req.as_ref()
     }
 
-    fn done_dma_req(&mut self) {
-        self.dma_req.as_mut().expect("Mem engine: DMA req not set").done = true;
+    fn done_dma_req(&mut self, result: Result<(), MemErr>) {
+        let dma_req = self.dma_req.as_mut().expect("Mem engine: DMA req not set");
+        dma_req.done = true;
+        dma_req.err = result.err();
     }
 
     fn get_mem_req(&self) -> Option<&MemReq> {
         self.mem_req.as_ref()
     }
 
-    fn set_mem_resp(&mut self, data: Option<&Vec<u8>>) {
-        assert!(data.is_none(), "Mem engine: has issued a read");
-        self.mem_resp = Some(MemResp { data: None });
+    fn set_mem_resp(&mut self, resp: &MemResp) {
+        self.mem_resp = Some(resp.clone());
     }
 
     fn get_glul_req(&self) -> Option<&GLULReq> {
@@ -161,20 +235,34 @@ impl Engine for MemEngine {
 
     fn set_gluls(&mut self, _: Vec<GLULStatus>) {}
 
+    fn set_contexts(&mut self, contexts: Vec<Context>) {
+        self.contexts = contexts;
+    }
+
+    fn wants_gluls(&self) -> Vec<bool> {
+        vec![]
+    }
+
+    fn set_glul_grants(&mut self, _: Vec<bool>) {}
+
     fn notify_glul_err(&mut self, _: ExecErr) {
         panic!("Mem engine: no gluls to notify");
     }
 
-    fn get_completion(&self) -> Option<Event> {
-        self.err.as_ref().map(|err|{
-            assert!(err.is_ok(), "Mem engine: cannot error");
-            Event::from_ok(self.cmd.expect("Command not set, no completion exists").sid)
+    fn take_kill(&mut self) -> bool {
+        false
+    }
+
+    fn get_completion(&self) -> Option<GluonEvent> {
+        self.err.map(|err| {
+            GluonEvent::from_mem_err(self.cmd.expect("Command not set, no completion exists").sid(), err)
         })
     }
 }
 
 impl Configurable<MemEngineConfig> for MemEngine {
-    fn new(_config: &MemEngineConfig) -> Self {
+    fn new(config: &MemEngineConfig) -> Self {
+        assert!(config.chunk_bytes > 0, "Mem engine: chunk_bytes must be non-zero");
         MemEngine {
             cmd: None,
             dma_req: None,
@@ -182,47 +270,275 @@ impl Configurable<MemEngineConfig> for MemEngine {
             mem_resp: None,
             state: MemEngineState::I,
             err: None,
+            xfer: Transfer::default(),
+            strided: None,
+            chain_next: None,
+            chain_descs: 0,
+            chunk_bytes: config.chunk_bytes,
+            max_chain_descs: config.max_chain_descs,
+            progress: 0,
+            chunk_offset: 0,
+            contexts: vec![],
             logger: Arc::new(Logger::new(0)),
         }
     }
 }
 
+impl MemEngine {
+    fn copy_command(&self) -> CopyCommand {
+        match self.cmd {
+            Some(GluonCommand::COPY(cmd)) => cmd,
+            _ => panic!("Mem engine: copy command not set"),
+        }
+    }
+
+    /// Check a device range against the DRAM window of the command's client. Ranges read from
+    /// descriptors never passed the frontend's check.
+    fn check_dram(&self, addr: u64, len: u64) -> Result<(), MemErr> {
+        let sid = self.cmd.expect("Mem engine: command not set").sid();
+        self.contexts
+            .iter()
+            .find(|context| context.sids.contains(&sid))
+            .map_or(Ok(()), |context| context.check_dram(addr, len))
+    }
+
+    /// Start `self.xfer` through the DMA unit, or through the memory port if it is device to device.
+    fn start_transfer(&mut self) {
+        let len = self.xfer.len as u64;
+        let in_window = match (self.xfer.d2d, self.xfer.dir) {
+            (true, _) => self.check_dram(self.xfer.src, len).and_then(|()| self.check_dram(self.xfer.dst, len)),
+            (false, DMADir::D2H) => self.check_dram(self.xfer.src, len),
+            (false, DMADir::H2D) => self.check_dram(self.xfer.dst, len),
+        };
+        if self.xfer.len == 0 {
+            self.finish(Err(MemErr {
+                code: MemErrCode::ZERO,
+                addr: self.xfer.dst,
+            }));
+        } else if let Err(err) = in_window {
+            self.finish(Err(err));
+        } else if self.xfer.d2d {
+            self.progress = 0;
+            self.state = MemEngineState::D0;
+        } else {
+            self.state = MemEngineState::C0;
+        }
+    }
+
+    /// Move on to the next row of a strided copy or the next descriptor of a chain, or complete the
+    /// command.
+    fn transfer_done(&mut self) {
+        match (&mut self.strided, self.chain_next) {
+            (Some((desc, row)), _) if *row < desc.rows() => match desc.row(*row) {
+                Some((src, dst)) => {
+                    *row += 1;
+                    self.xfer.src = src;
+                    self.xfer.dst = dst;
+                    self.xfer.len = desc.width;
+                    self.start_transfer();
+                }
+                None => {
+                    let addr = self.strided_command().desc;
+                    self.finish(Err(MemErr {
+                        code: MemErrCode::RANGE,
+                        addr,
+                    }));
+                }
+            },
+            (None, Some(next)) if next != 0 => self.fetch_chain_desc(next),
+            _ => self.finish(Ok(())),
+        }
+    }
+
+    /// Read the chain descriptor at `addr`, from host memory through the DMA unit or from device memory
+    /// through the engine's DRAM port. Device descriptors are checked against the DRAM window, host ones
+    /// by the DMA unit.
+    fn fetch_chain_desc(&mut self, addr: u64) {
+        let chain_cmd = self.chain_command();
+        if !addr.is_multiple_of(8) {
+            self.finish(Err(MemErr {
+                code: MemErrCode::MISALIGNED,
+                addr,
+            }));
+            return;
+        }
+        if self.chain_descs == self.max_chain_descs {
+            self.finish(Err(MemErr {
+                code: MemErrCode::RANGE,
+                addr,
+            }));
+            return;
+        }
+        if !chain_cmd.host_desc() {
+            if let Err(err) = self.check_dram(addr, CHAIN_DESC_BYTES as u64) {
+                self.finish(Err(err));
+                return;
+            }
+        }
+        self.chain_descs += 1;
+        if chain_cmd.host_desc() {
+            self.dma_req = Some(DMAReq {
+                sid: chain_cmd.sid,
+                dir: DMADir::H2D,
+                src_addr: addr,
+                target_addr: 0,
+                sz: CHAIN_DESC_BYTES,
+                fetch: true,
+                done: false,
+                err: None,
+            });
+        } else {
+            self.mem_req = Some(MemReq {
+                addr,
+                write: false,
+                swap: false,
+                bytes: CHAIN_DESC_BYTES,
+                data: vec![],
+            });
+        }
+        self.state = MemEngineState::G0;
+    }
+
+    /// Response to `mem_req`. A failed access completes the command with its error and yields nothing.
+    fn take_mem_resp(&mut self) -> Option<MemResp> {
+        let resp = self.mem_resp.take()?;
+        match resp.err {
+            Some(err) => {
+                self.mem_req = None;
+                self.finish(Err(err));
+                None
+            }
+            None => Some(resp),
+        }
+    }
+
+    fn finish(&mut self, result: Result<(), MemErr>) {
+        info!(self.logger, "Mem engine: command done {:?}", result);
+        self.strided = None;
+        self.chain_next = None;
+        self.err = Some(result);
+        self.state = MemEngineState::C2;
+    }
+
+    /// Offset and size of the next device-to-device chunk. When the destination lies above the source
+    /// the copy runs from the end, so overlapping ranges behave like `memmove`: every chunk is read
+    /// before any write can reach it.
+    fn d2d_chunk(&self) -> (u32, u32) {
+        let remaining = self.xfer.len - self.progress;
+        let bytes = remaining.min(self.chunk_bytes);
+        if self.xfer.dst > self.xfer.src {
+            (remaining - bytes, bytes)
+        } else {
+            (self.progress, bytes)
+        }
+    }
+
+    fn set_command(&self) -> SetCommand {
+        match self.cmd {
+            Some(GluonCommand::SET(cmd)) => cmd,
+            _ => panic!("Mem engine: set command not set"),
+        }
+    }
+
+    fn chain_command(&self) -> ChainCommand {
+        match self.cmd {
+            Some(GluonCommand::CHAIN(cmd)) => cmd,
+            _ => panic!("Mem engine: chain command not set"),
+        }
+    }
+
+    fn strided_command(&self) -> StridedCommand {
+        match self.cmd {
+            Some(GluonCommand::STRIDED(cmd)) => cmd,
+            _ => panic!("Mem engine: strided command not set"),
+        }
+    }
+}
+
 impl Clocked for MemEngine {
     fn tick(&mut self) -> Result<(), SimErr> {
         match self.state {
             MemEngineState::I => {
-                if let Some(cmd) = &self.cmd {
-                    match cmd.op {
-                        MemOp::COPY => {
-                            self.state = MemEngineState::C0;
+                if let Some(cmd) = self.cmd {
+                    info!(self.logger, "Mem engine: command {:?}", cmd);
+                    match cmd {
+                        GluonCommand::COPY(_) => {
+                            let copy_cmd = self.copy_command();
+                            self.xfer = Transfer {
+                                src: copy_cmd.src,
+                                dst: copy_cmd.dst,
+                                len: copy_cmd.len,
+                                d2d: copy_cmd.d2d(),
+                                dir: DMADir::from(copy_cmd.d2h()),
+                            };
+                            self.start_transfer();
+                        }
+                        GluonCommand::STRIDED(_) => {
+                            let strided_cmd = self.strided_command();
+                            self.xfer = Transfer {
+                                d2d: strided_cmd.d2d(),
+                                dir: DMADir::from(strided_cmd.d2h()),
+                                ..Transfer::default()
+                            };
+                            if !strided_cmd.desc.is_multiple_of(8) {
+                                self.finish(Err(MemErr {
+                                    code: MemErrCode::MISALIGNED,
+                                    addr: strided_cmd.desc,
+                                }));
+                            } else {
+                                self.mem_req = Some(MemReq {
+                                    addr: strided_cmd.desc,
+                                    write: false,
+                                    swap: false,
+                                    bytes: STRIDED_DESC_BYTES,
+                                    data: vec![],
+                                });
+                                self.state = MemEngineState::T0;
+                            }
+                        }
+                        GluonCommand::CHAIN(_) => {
+                            let chain_cmd = self.chain_command();
+                            self.chain_descs = 0;
+                            self.fetch_chain_desc(chain_cmd.desc);
                         }
-                        MemOp::SET => {
-                            self.state = MemEngineState::S0;
+                        GluonCommand::SET(_) => {
+                            let set_cmd = self.set_command();
+                            if set_cmd.len == 0 {
+                                self.finish(Err(MemErr {
+                                    code: MemErrCode::ZERO,
+                                    addr: set_cmd.dst,
+                                }));
+                            } else {
+                                self.progress = 0;
+                                self.state = MemEngineState::S0;
+                            }
                         }
+                        _ => unreachable!("Mem engine: non-mem command accepted"),
                     }
-                    info!(self.logger, "Mem engine: command {:?}", cmd);
                 }
             }
             MemEngineState::C0 => {
-                let copy_cmd = CopyCommand::from_bytes(self.cmd.expect("Mem engine: Command not set").bytes);
-                let dir = DMADir::from(copy_cmd.flags & 1 == 1);
+                let sid = self.cmd.expect("Mem engine: command not set").sid();
                 self.dma_req = Some(DMAReq {
-                    dir,
-                    src_addr: copy_cmd.src,
-                    target_addr: copy_cmd.dst,
-                    sz: copy_cmd.len,
+                    sid,
+                    dir: self.xfer.dir,
+                    src_addr: self.xfer.src,
+                    target_addr: self.xfer.dst,
+                    sz: self.xfer.len,
+                    fetch: false,
                     done: false,
+                    err: None,
                 });
                 self.state = MemEngineState::C1;
                 info!(self.logger, "Mem engine: DMA req {:?}", self.dma_req.as_ref().expect("Mem engine: DMA req not set"));
             }
             MemEngineState::C1 => {
-                if let Some(dma_req) = &self.dma_req {
-                    if dma_req.done {
-                        self.dma_req = None;
-                        self.state = MemEngineState::C2;
-                        self.err = Some(Ok(()));
-                        info!(self.logger, "Mem engine: DMA req done");
+                if self.dma_req.as_ref().is_some_and(|dma_req| dma_req.done) {
+                    let dma_req = self.dma_req.take().expect("Mem engine: unreachable");
+                    info!(self.logger, "Mem engine: DMA req done {:?}", dma_req.err);
+                    match dma_req.err {
+                        Some(err) => self.finish(Err(err)),
+                        None => self.transfer_done(),
                     }
                 }
             }
@@ -235,26 +551,111 @@ impl Clocked for MemEngine {
                 self.err = None;
                 
             }
+            MemEngineState::D0 => {
+                if self.progress == self.xfer.len {
+                    self.transfer_done();
+                } else {
+                    let (offset, bytes) = self.d2d_chunk();
+                    self.chunk_offset = offset;
+                    self.mem_req = Some(MemReq {
+                        addr: self.xfer.src + offset as u64,
+                        write: false,
+                        swap: false,
+                        bytes,
+                        data: vec![],
+                    });
+                    self.state = MemEngineState::D1;
+                }
+            }
+            MemEngineState::D1 => {
+                if let Some(resp) = self.take_mem_resp() {
+                    let data = resp.data.expect("Mem engine: D2D read returned no data");
+                    self.mem_req = Some(MemReq {
+                        addr: self.xfer.dst + self.chunk_offset as u64,
+                        write: true,
+                        swap: false,
+                        bytes: data.len() as u32,
+                        data,
+                    });
+                    self.state = MemEngineState::D2;
+                }
+            }
+            MemEngineState::D2 => {
+                if self.take_mem_resp().is_some() {
+                    self.progress += self.mem_req.take().expect("Mem engine: D2D write not set").bytes;
+                    self.state = MemEngineState::D0;
+                }
+            }
+            MemEngineState::T0 => {
+                if let Some(resp) = self.take_mem_resp() {
+                    self.mem_req = None;
+                    let strided_cmd = self.strided_command();
+                    let desc = StridedDesc::from_bytes(&resp.data.expect("Mem engine: descriptor read returned no data"));
+                    let desc = if strided_cmd.dims == 2 {
+                        StridedDesc { depth: 1, ..desc }
+                    } else {
+                        desc
+                    };
+                    info!(self.logger, "Mem engine: strided copy {:?}", desc);
+                    if desc.rows() == 0 {
+                        self.finish(Err(MemErr {
+                            code: MemErrCode::ZERO,
+                            addr: strided_cmd.desc,
+                        }));
+                    } else {
+                        self.strided = Some((desc, 0));
+                        self.transfer_done();
+                    }
+                }
+            }
+            MemEngineState::G0 => {
+                // Host descriptors arrive with the DMA completion, device ones on the DRAM port.
+                let fetch_done = self.dma_req.as_ref().is_none_or(|dma_req| dma_req.done);
+                if fetch_done {
+                    if let Some(err) = self.dma_req.take().and_then(|dma_req| dma_req.err) {
+                        self.finish(Err(err));
+                    } else if let Some(resp) = self.take_mem_resp() {
+                        self.mem_req = None;
+                        let desc = ChainDesc::from_bytes(&resp.data.expect("Mem engine: descriptor read returned no data"));
+                        info!(self.logger, "Mem engine: chain descriptor {:?}", desc);
+                        self.xfer = Transfer {
+                            src: desc.src,
+                            dst: desc.dst,
+                            len: desc.len,
+                            d2d: desc.d2d(),
+                            dir: DMADir::from(desc.d2h()),
+                        };
+                        self.chain_next = Some(desc.next);
+                        self.start_transfer();
+                    }
+                }
+            }
             MemEngineState::S0 => {
-                let set_cmd = SetCommand::from_bytes(self.cmd.expect("Mem engine: Command not set").bytes);
-                let data = set_cmd.value.to_le_bytes().repeat((set_cmd.len / 4) as usize);
-                self.mem_req = Some(MemReq {
-                    addr: set_cmd.dst,
-                    write: true,
-                    bytes: set_cmd.len,
-                    data,
-                });
-                self.state = MemEngineState::S1;
-                info!(self.logger, "Mem engine: command {:?}", set_cmd);
+                let set_cmd = self.set_command();
+                if self.progress == set_cmd.len {
+                    self.finish(Ok(()));
+                } else {
+                    // Chunks are aligned in device memory, so only the first and last can be partial.
+                    let addr = set_cmd.dst + self.progress as u64;
+                    let to_boundary = self.chunk_bytes - (addr % self.chunk_bytes as u64) as u32;
+                    let bytes = to_boundary.min(set_cmd.len - self.progress);
+                    let data = (self.progress..self.progress + bytes)
+                        .map(|offset| set_cmd.byte_at(offset as usize))
+                        .collect::<Vec<_>>();
+                    self.mem_req = Some(MemReq {
+                        addr,
+                        write: true,
+                        swap: false,
+                        bytes,
+                        data,
+                    });
+                    self.state = MemEngineState::S1;
+                }
             }
             MemEngineState::S1 => {
-                if let Some(_) = &self.mem_resp {
-                    self.cmd = None;
-                    self.mem_req = None;
-                    self.mem_resp = None;
-                    self.state = MemEngineState::I;
-                    self.err = Some(Ok(()));
-                    info!(self.logger, "Mem engine: mem set done");
+                if self.take_mem_resp().is_some() {
+                    self.progress += self.mem_req.take().expect("Mem engine: set write not set").bytes;
+                    self.state = MemEngineState::S0;
                 }
             }
         }
@@ -263,6 +664,139 @@ impl Clocked for MemEngine {
     }
 
     fn busy(&mut self) -> bool {
-        false
+        self.cmd.is_some() || self.err.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strided_rows() {
+        let desc = StridedDesc {
+            src: 0x1000,
+            dst: 0x8000,
+            src_pitch: 0x100,
+            dst_pitch: 0x40,
+            src_slice_pitch: 0x1000,
+            dst_slice_pitch: 0x200,
+            width: 0x20,
+            height: 3,
+            depth: 2,
+        };
+        assert_eq!(desc.rows(), 6);
+        assert_eq!(desc.row(0), Some((0x1000, 0x8000)));
+        assert_eq!(desc.row(2), Some((0x1200, 0x8080)));
+        assert_eq!(desc.row(4), Some((0x2100, 0x8240)));
+        assert_eq!(StridedDesc { width: 0, ..desc }.rows(), 0);
+    }
+
+    #[test]
+    fn strided_row_overflow() {
+        let desc = StridedDesc {
+            src: 0x1000,
+            dst: 0x8000,
+            src_pitch: 0x100,
+            dst_pitch: u64::MAX / 2,
+            src_slice_pitch: 0,
+            dst_slice_pitch: 0,
+            width: 1,
+            height: 4,
+            depth: 1,
+        };
+        assert_eq!(desc.row(1), Some((0x1100, 0x8000 + u64::MAX / 2)));
+        assert_eq!(desc.row(2), None);
+        assert_eq!(StridedDesc { src_slice_pitch: u64::MAX, ..desc }.row(4), None);
+    }
+
+    /// Run a device-memory chain starting at `desc` to completion, serving descriptor reads from
+    /// `descs` and any other DRAM access with zeros. Returns the completion and the descriptors read.
+    fn run_chain(max_chain_descs: u32, desc: u64, descs: &[(u64, ChainDesc)]) -> (GluonEvent, u32) {
+        let mut engine = MemEngine::new(&MemEngineConfig {
+            max_chain_descs,
+            ..MemEngineConfig::default()
+        });
+        engine.set_contexts(vec![Context {
+            id: 0,
+            sids: 0..4,
+            dram: 0..0x10000,
+        }]);
+        engine.set_cmd(GluonCommand::CHAIN(ChainCommand { sid: 0, desc, flags: 0 }));
+        let mut fetches = 0;
+        for _ in 0..10000 {
+            engine.tick().expect("tick failed");
+            if let Some(event) = engine.get_completion() {
+                return (event, fetches);
+            }
+            if engine.mem_resp.is_some() {
+                continue;
+            }
+            if let Some(req) = engine.get_mem_req() {
+                let desc = descs.iter().find(|(addr, _)| *addr == req.addr && !req.write);
+                let data = match desc {
+                    Some((_, desc)) => {
+                        fetches += 1;
+                        [desc.src, desc.dst, desc.next]
+                            .iter()
+                            .flat_map(|field| field.to_le_bytes())
+                            .chain([desc.len, desc.flags].iter().flat_map(|field| field.to_le_bytes()))
+                            .collect()
+                    }
+                    None => vec![0; req.bytes as usize],
+                };
+                engine.set_mem_resp(&MemResp {
+                    data: (!req.write).then_some(data),
+                    err: None,
+                });
+            }
+        }
+        panic!("chain did not complete");
+    }
+
+    #[test]
+    fn chain_length_limited() {
+        let copy = ChainDesc {
+            src: 0x1000,
+            dst: 0x2000,
+            next: 0x100,
+            len: 8,
+            flags: 2,
+        };
+        let (event, fetches) = run_chain(4, 0x100, &[(0x100, copy)]);
+        let err = MemErr {
+            code: MemErrCode::RANGE,
+            addr: 0x100,
+        };
+        assert_eq!(event, GluonEvent::MEM { sid: 0, err });
+        assert_eq!(fetches, 4);
+
+        let descs = [(0x100, ChainDesc { next: 0x140, ..copy }), (0x140, ChainDesc { next: 0, ..copy })];
+        let (event, fetches) = run_chain(4, 0x100, &descs);
+        assert_eq!(event, GluonEvent::OK { sid: 0 });
+        assert_eq!(fetches, 2);
+    }
+
+    #[test]
+    fn chain_checks_every_desc() {
+        let copy = ChainDesc {
+            src: 0x1000,
+            dst: 0x2000,
+            next: 0,
+            len: 8,
+            flags: 2,
+        };
+        let range = |addr| GluonEvent::MEM {
+            sid: 0,
+            err: MemErr {
+                code: MemErrCode::RANGE,
+                addr,
+            },
+        };
+        let (event, fetches) = run_chain(16, 0x100, &[(0x100, ChainDesc { next: 0x10000, ..copy })]);
+        assert_eq!((event, fetches), (range(0x10000), 1));
+        let descs = [(0x100, ChainDesc { next: 0x140, ..copy }), (0x140, ChainDesc { dst: 0xfffc, ..copy })];
+        let (event, fetches) = run_chain(16, 0x100, &descs);
+        assert_eq!((event, fetches), (range(0xfffc), 2));
     }
 }
@@ -2,3 +2,4 @@ pub mod cs_engine;
 pub mod kernel_engine;
 pub mod mem_engine;
 pub mod printf_unit;
+pub mod tb_scheduler;
@@ -1,4 +1,362 @@
+use crate::common::base::{DMADir, DMAReq, MemErr, MemReq, MemResp};
+use cyclotron::info;
+use cyclotron::sim::log::Logger;
+use std::sync::Arc;
+
+/// Ring header layout in device memory, u32 words: `lock @0, head @4, tail @8, limit @12`, followed by
+/// the ring data. `head`, `tail` and `limit` are offsets into the data; the device appends at `tail` and
+/// never past `limit`.
+const LOCK_OFFSET: u64 = 0;
+const HEAD_OFFSET: u64 = 4;
+const HEADER_BYTES: u64 = 16;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum PrintfState {
+    /// Idle, waiting for a drain request.
+    #[default]
+    P0,
+    /// Swapping the lock word until the device has released it, or once if the kernel has stopped.
+    P1,
+    /// Reading `head` and `tail`.
+    P2,
+    /// D2H DMA of the unread slice in flight.
+    P3,
+    /// Moving `tail` back to `head` and releasing the lock.
+    P4,
+}
+
+/// Drains a kernel's printf ring to host memory. Output is appended from `printf_host_addr` onwards
+/// across drains, so the host ends up with everything the kernel printed, in order. The host buffer is
+/// as large as the ring: once a kernel has printed `limit` bytes, further output is dropped.
 pub struct PrintfUnit {
-    pub ring_head: u32,
-    pub host_addr: u32,
+    sid: u8,
+    /// Device address of the ring header; 0 if the kernel has no ring.
+    header_addr: u64,
+    host_addr: u64,
+    /// Bytes drained to the host for the current kernel.
+    host_bytes: u32,
+    drain_interval: u64,
+    cycles: u64,
+
+    pending: bool,
+    head: u32,
+    state: PrintfState,
+    mem_req: Option<MemReq>,
+    mem_resp: Option<MemResp>,
+    dma_req: Option<DMAReq>,
+    err: Option<MemErr>,
+
+    logger: Arc<Logger>,
+}
+
+impl PrintfUnit {
+    /// `drain_interval` is the number of cycles between drains while a kernel runs; 0 only drains when
+    /// asked to.
+    pub fn new(drain_interval: u64) -> Self {
+        PrintfUnit {
+            sid: 0,
+            header_addr: 0,
+            host_addr: 0,
+            host_bytes: 0,
+            drain_interval,
+            cycles: 0,
+            pending: false,
+            head: 0,
+            state: PrintfState::P0,
+            mem_req: None,
+            mem_resp: None,
+            dma_req: None,
+            err: None,
+            logger: Arc::new(Logger::new(0)),
+        }
+    }
+
+    pub fn set_logger(&mut self, logger: Arc<Logger>) {
+        self.logger = logger;
+    }
+
+    /// P0: latch the ring of a newly launched kernel.
+    pub fn init(&mut self, sid: u8, header_addr: u64, host_addr: u64) {
+        assert!(self.idle(), "Printf unit: init while draining");
+        self.sid = sid;
+        self.header_addr = header_addr;
+        self.host_addr = host_addr;
+        self.host_bytes = 0;
+        self.cycles = 0;
+        self.err = None;
+        info!(self.logger, "Printf unit: ring header 0x{:x}, host 0x{:x}", header_addr, host_addr);
+    }
+
+    /// Request a drain. A drain already in progress may have missed the latest output, so another one
+    /// follows it.
+    pub fn drain(&mut self) {
+        self.pending = self.header_addr != 0 && self.err.is_none();
+    }
+
+    /// No drain in progress or requested.
+    pub fn idle(&self) -> bool {
+        self.state == PrintfState::P0 && !self.pending
+    }
+
+    /// First DMA error of the current kernel. Draining stops after it.
+    pub fn err(&self) -> Option<MemErr> {
+        self.err
+    }
+
+    pub fn get_mem_req(&self) -> Option<&MemReq> {
+        self.mem_req.as_ref()
+    }
+
+    pub fn set_mem_resp(&mut self, resp: &MemResp) {
+        self.mem_resp = Some(resp.clone());
+    }
+
+    pub fn get_dma_req(&self) -> Option<&DMAReq> {
+        self.dma_req.as_ref()
+    }
+
+    pub fn done_dma_req(&mut self, result: Result<(), MemErr>) {
+        let dma_req = self.dma_req.as_mut().expect("Printf unit: DMA req not set");
+        dma_req.done = true;
+        dma_req.err = result.err();
+    }
+
+    /// Advance the drain. `running` is set while the kernel executes, enabling periodic drains. Once it is
+    /// clear, a lock still held belongs to a warp that finished, faulted or was killed and will never be
+    /// released, so the drain takes it over.
+    pub fn tick(&mut self, running: bool) {
+        if running && self.drain_interval != 0 {
+            self.cycles += 1;
+            if self.cycles == self.drain_interval {
+                self.cycles = 0;
+                self.drain();
+            }
+        }
+
+        match self.state {
+            PrintfState::P0 => {
+                if self.pending {
+                    self.pending = false;
+                    self.swap_lock();
+                    self.state = PrintfState::P1;
+                }
+            }
+            PrintfState::P1 => {
+                if let Some(resp) = self.take_mem_resp() {
+                    let held = read_u32(&resp, 0) != 0;
+                    if held && running {
+                        // Held by the device; the swap wrote 1 over 1, so just try again.
+                        self.swap_lock();
+                    } else {
+                        if held {
+                            info!(self.logger, "Printf unit: taking over the lock of a stopped kernel");
+                        }
+                        self.mem_req = Some(MemReq {
+                            addr: self.header_addr + HEAD_OFFSET,
+                            write: false,
+                            swap: false,
+                            bytes: 12,
+                            data: vec![],
+                        });
+                        self.state = PrintfState::P2;
+                    }
+                }
+            }
+            PrintfState::P2 => {
+                if let Some(resp) = self.take_mem_resp() {
+                    self.head = read_u32(&resp, 0);
+                    let limit = read_u32(&resp, 8);
+                    let tail = read_u32(&resp, 4).min(limit);
+                    let len = tail.saturating_sub(self.head).min(limit.saturating_sub(self.host_bytes));
+                    if tail > self.head + len {
+                        info!(self.logger, "Printf unit: host buffer full, dropping {} bytes", tail - self.head - len);
+                    }
+                    if len > 0 {
+                        self.dma_req = Some(DMAReq {
+                            sid: self.sid,
+                            dir: DMADir::D2H,
+                            src_addr: self.header_addr + HEADER_BYTES + self.head as u64,
+                            target_addr: self.host_addr,
+                            sz: len,
+                            fetch: false,
+                            done: false,
+                            err: None,
+                        });
+                        info!(self.logger, "Printf unit: draining {} bytes to 0x{:x}", len, self.host_addr);
+                        self.host_addr += len as u64;
+                        self.host_bytes += len;
+                        self.state = PrintfState::P3;
+                    } else {
+                        self.release();
+                    }
+                }
+            }
+            PrintfState::P3 => {
+                if self.dma_req.as_ref().is_some_and(|dma_req| dma_req.done) {
+                    let dma_req = self.dma_req.take().expect("Printf unit: unreachable");
+                    if let Some(err) = dma_req.err {
+                        info!(self.logger, "Printf unit: drain failed {:?}", err);
+                        self.err = Some(err);
+                        self.pending = false;
+                    }
+                    self.release();
+                }
+            }
+            PrintfState::P4 => {
+                if self.take_mem_resp().is_some() {
+                    self.state = PrintfState::P0;
+                }
+            }
+        }
+    }
+
+    /// Response to the ring access in flight. A failed access leaves the ring unreachable, so it stops
+    /// draining without releasing the lock.
+    fn take_mem_resp(&mut self) -> Option<MemResp> {
+        let resp = self.mem_resp.take()?;
+        self.mem_req = None;
+        match resp.err {
+            Some(err) => {
+                info!(self.logger, "Printf unit: ring access failed {:?}", err);
+                self.err = Some(err);
+                self.pending = false;
+                self.state = PrintfState::P0;
+                None
+            }
+            None => Some(resp),
+        }
+    }
+
+    fn swap_lock(&mut self) {
+        self.mem_req = Some(MemReq {
+            addr: self.header_addr + LOCK_OFFSET,
+            write: true,
+            swap: true,
+            bytes: 4,
+            data: 1u32.to_le_bytes().to_vec(),
+        });
+    }
+
+    /// Write `lock = 0, head, tail = head` in one access.
+    fn release(&mut self) {
+        let mut data = 0u32.to_le_bytes().to_vec();
+        data.extend_from_slice(&self.head.to_le_bytes());
+        data.extend_from_slice(&self.head.to_le_bytes());
+        self.mem_req = Some(MemReq {
+            addr: self.header_addr + LOCK_OFFSET,
+            write: true,
+            swap: false,
+            bytes: data.len() as u32,
+            data,
+        });
+        self.state = PrintfState::P4;
+    }
+}
+
+fn read_u32(resp: &MemResp, offset: usize) -> u32 {
+    let data = resp.data.as_ref().expect("Printf unit: read returned no data");
+    u32::from_le_bytes(data[offset..offset + 4].try_into().expect("Printf unit: short read"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RING: u64 = 0x1000;
+    const HOST: u64 = 0x8000;
+
+    /// Device memory holding a ring header at `RING` and its data, and the host buffer at `HOST`.
+    struct Memories {
+        dev: Vec<u8>,
+        host: Vec<u8>,
+    }
+
+    impl Memories {
+        fn new(lock: u32, head: u32, tail: u32, limit: u32) -> Self {
+            let mut dev = vec![0u8; 0x2000];
+            for (idx, word) in [lock, head, tail, limit].iter().enumerate() {
+                dev[RING as usize + idx * 4..][..4].copy_from_slice(&word.to_le_bytes());
+            }
+            for idx in 0..limit as usize {
+                dev[(RING + HEADER_BYTES) as usize + idx] = idx as u8;
+            }
+            Memories { dev, host: vec![] }
+        }
+
+        fn word(&self, offset: u64) -> u32 {
+            u32::from_le_bytes(self.dev[(RING + offset) as usize..][..4].try_into().unwrap())
+        }
+
+        fn set_word(&mut self, offset: u64, value: u32) {
+            self.dev[(RING + offset) as usize..][..4].copy_from_slice(&value.to_le_bytes());
+        }
+
+        /// Tick `unit` for up to `cycles` cycles, serving its requests, until it is idle.
+        fn run(&mut self, unit: &mut PrintfUnit, running: bool, cycles: usize) {
+            for _ in 0..cycles {
+                unit.tick(running);
+                if unit.idle() {
+                    return;
+                }
+                if let (Some(req), None) = (unit.get_mem_req(), &unit.mem_resp) {
+                    let range = req.addr as usize..(req.addr + req.bytes as u64) as usize;
+                    let old = self.dev[range.clone()].to_vec();
+                    if req.write {
+                        self.dev[range].copy_from_slice(&req.data);
+                    }
+                    let data = (!req.write || req.swap).then_some(old);
+                    unit.set_mem_resp(&MemResp { data, err: None });
+                }
+                if let Some(req) = unit.get_dma_req().filter(|req| !req.done).cloned() {
+                    assert_eq!(req.target_addr, HOST + self.host.len() as u64);
+                    let src = req.src_addr as usize;
+                    self.host.extend_from_slice(&self.dev[src..src + req.sz as usize]);
+                    unit.done_dma_req(Ok(()));
+                }
+            }
+        }
+    }
+
+    fn unit() -> PrintfUnit {
+        let mut unit = PrintfUnit::new(0);
+        unit.init(1, RING, HOST);
+        unit
+    }
+
+    #[test]
+    fn drains_up_to_limit() {
+        let mut mem = Memories::new(0, 4, 20, 32);
+        let mut unit = unit();
+        unit.drain();
+        mem.run(&mut unit, true, 100);
+        assert!(unit.idle());
+        assert_eq!(mem.host, (4..20).collect::<Vec<u8>>());
+        assert_eq!((mem.word(LOCK_OFFSET), mem.word(HEAD_OFFSET), mem.word(8)), (0, 4, 4));
+
+        // The kernel prints 26 more bytes, but only 16 fit in the host buffer of `limit` bytes.
+        mem.set_word(8, 30);
+        unit.drain();
+        mem.run(&mut unit, true, 100);
+        assert!(unit.idle());
+        assert_eq!(mem.host.len(), 32);
+        assert_eq!(mem.host[16..], (4..20).collect::<Vec<u8>>()[..]);
+        assert_eq!(unit.err(), None);
+    }
+
+    #[test]
+    fn held_lock_taken_over_once_stopped() {
+        let mut mem = Memories::new(1, 0, 8, 32);
+        let mut unit = unit();
+        unit.drain();
+        // A running warp holds the lock: the drain keeps swapping.
+        mem.run(&mut unit, true, 100);
+        assert_eq!(unit.state, PrintfState::P1);
+        assert!(mem.host.is_empty());
+
+        // The warp faulted or was killed while holding it.
+        mem.run(&mut unit, false, 100);
+        assert!(unit.idle());
+        assert_eq!(mem.host, (0..8).collect::<Vec<u8>>());
+        assert_eq!((mem.word(LOCK_OFFSET), mem.word(8)), (0, 0));
+    }
 }
@@ -0,0 +1,247 @@
+use serde::Deserialize;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum TbSchedulerPolicy {
+    /// The free GLUL with the fewest thread block slots, filled as far as possible.
+    #[default]
+    SMALLEST,
+    /// The free GLUL with the most thread block slots, filled as far as possible.
+    GREEDY,
+    /// Free GLULs in turn, starting after the GLUL dispatched to last.
+    RR,
+    /// At most `min_tb_per_cluster = ceil(total_threadblocks / clusters)` thread blocks per dispatch,
+    /// to the free GLUL that has received the fewest so far.
+    BALANCED,
+    /// GLUL `j` runs the `j`-th contiguous slice of block ids; a GLUL whose slice is exhausted takes the
+    /// tail of the slice with the most blocks left.
+    LOCALITY,
+}
+
+impl TbSchedulerPolicy {
+    pub fn scheduler(&self) -> Box<dyn TbScheduler> {
+        match self {
+            TbSchedulerPolicy::SMALLEST => Box::new(Smallest::default()),
+            TbSchedulerPolicy::GREEDY => Box::new(Greedy::default()),
+            TbSchedulerPolicy::RR => Box::new(RoundRobin::default()),
+            TbSchedulerPolicy::BALANCED => Box::new(Balanced::default()),
+            TbSchedulerPolicy::LOCALITY => Box::new(Locality::default()),
+        }
+    }
+}
+
+/// Thread blocks `first..first + n` (linear block ids) go to GLUL `glul`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TbDispatch {
+    pub glul: usize,
+    pub first: u32,
+    pub n: u32,
+}
+
+/// Decides which GLUL the kernel engine dispatches thread blocks to, and which ones.
+pub trait TbScheduler: Send {
+    /// A kernel of `total_tbs` thread blocks starts on a GLUG with `num_gluls` GLULs.
+    fn start(&mut self, total_tbs: u32, num_gluls: usize);
+    /// Pick a dispatch among `free`, the `(glul, thread block slots)` of the GLULs the engine may use
+    /// now, all with at least one slot. Returns `None` to wait.
+    fn pick(&mut self, free: &[(usize, u32)]) -> Option<TbDispatch>;
+}
+
+/// Hands out block ids in order.
+#[derive(Default)]
+struct Cursor {
+    next: u32,
+    total: u32,
+}
+
+impl Cursor {
+    fn take(&mut self, glul: usize, n: u32) -> Option<TbDispatch> {
+        let n = n.min(self.total - self.next);
+        (n > 0).then(|| {
+            let first = self.next;
+            self.next += n;
+            TbDispatch { glul, first, n }
+        })
+    }
+}
+
+#[derive(Default)]
+struct Smallest {
+    cursor: Cursor,
+}
+
+impl TbScheduler for Smallest {
+    fn start(&mut self, total_tbs: u32, _num_gluls: usize) {
+        self.cursor = Cursor { next: 0, total: total_tbs };
+    }
+
+    fn pick(&mut self, free: &[(usize, u32)]) -> Option<TbDispatch> {
+        let (glul, slots) = free.iter().min_by_key(|(_, slots)| *slots)?;
+        self.cursor.take(*glul, *slots)
+    }
+}
+
+#[derive(Default)]
+struct Greedy {
+    cursor: Cursor,
+}
+
+impl TbScheduler for Greedy {
+    fn start(&mut self, total_tbs: u32, _num_gluls: usize) {
+        self.cursor = Cursor { next: 0, total: total_tbs };
+    }
+
+    fn pick(&mut self, free: &[(usize, u32)]) -> Option<TbDispatch> {
+        // max_by_key keeps the last maximum; prefer the lowest GLUL on ties
+        let (glul, slots) = free.iter().rev().max_by_key(|(_, slots)| *slots)?;
+        self.cursor.take(*glul, *slots)
+    }
+}
+
+#[derive(Default)]
+struct RoundRobin {
+    cursor: Cursor,
+    rr_idx: usize,
+}
+
+impl TbScheduler for RoundRobin {
+    fn start(&mut self, total_tbs: u32, _num_gluls: usize) {
+        self.cursor = Cursor { next: 0, total: total_tbs };
+    }
+
+    fn pick(&mut self, free: &[(usize, u32)]) -> Option<TbDispatch> {
+        let (glul, slots) = free
+            .iter()
+            .find(|(glul, _)| *glul >= self.rr_idx)
+            .or(free.first())?;
+        let dispatch = self.cursor.take(*glul, *slots)?;
+        self.rr_idx = glul + 1;
+        Some(dispatch)
+    }
+}
+
+#[derive(Default)]
+struct Balanced {
+    cursor: Cursor,
+    min_tb_per_cluster: u32,
+    dispatched: Vec<u32>,
+}
+
+impl TbScheduler for Balanced {
+    fn start(&mut self, total_tbs: u32, num_gluls: usize) {
+        self.cursor = Cursor { next: 0, total: total_tbs };
+        self.min_tb_per_cluster = total_tbs.div_ceil(num_gluls.max(1) as u32);
+        self.dispatched = vec![0; num_gluls];
+    }
+
+    fn pick(&mut self, free: &[(usize, u32)]) -> Option<TbDispatch> {
+        let (glul, slots) = free.iter().min_by_key(|(glul, _)| (self.dispatched[*glul], *glul))?;
+        let dispatch = self.cursor.take(*glul, (*slots).min(self.min_tb_per_cluster))?;
+        self.dispatched[*glul] += dispatch.n;
+        Some(dispatch)
+    }
+}
+
+#[derive(Default)]
+struct Locality {
+    /// Undispatched block ids of each GLUL's slice.
+    slices: Vec<std::ops::Range<u32>>,
+}
+
+impl TbScheduler for Locality {
+    fn start(&mut self, total_tbs: u32, num_gluls: usize) {
+        let per_glul = total_tbs.div_ceil(num_gluls.max(1) as u32);
+        self.slices = (0..num_gluls as u32)
+            .map(|j| (j * per_glul).min(total_tbs)..((j + 1) * per_glul).min(total_tbs))
+            .collect();
+    }
+
+    fn pick(&mut self, free: &[(usize, u32)]) -> Option<TbDispatch> {
+        // GLULs with their own blocks left go first, in index order
+        if let Some((glul, slots)) = free.iter().find(|(glul, _)| self.slices[*glul].start < self.slices[*glul].end) {
+            let slice = &mut self.slices[*glul];
+            let n = (*slots).min(slice.len() as u32);
+            let first = slice.start;
+            slice.start += n;
+            return Some(TbDispatch { glul: *glul, first, n });
+        }
+        let (glul, slots) = free.first()?;
+        let slice = self.slices.iter_mut().rev().max_by_key(|slice| slice.len()).filter(|slice| slice.start < slice.end)?;
+        let n = (*slots).min(slice.len() as u32);
+        slice.end -= n;
+        Some(TbDispatch { glul: *glul, first: slice.end, n })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Start a kernel of `total_tbs` blocks and offer the scheduler each of `rounds` in turn.
+    fn picks(
+        policy: TbSchedulerPolicy,
+        total_tbs: u32,
+        num_gluls: usize,
+        rounds: &[&[(usize, u32)]],
+    ) -> Vec<Option<(usize, u32, u32)>> {
+        let mut scheduler = policy.scheduler();
+        scheduler.start(total_tbs, num_gluls);
+        rounds
+            .iter()
+            .map(|free| scheduler.pick(free).map(|dispatch| (dispatch.glul, dispatch.first, dispatch.n)))
+            .collect()
+    }
+
+    #[test]
+    fn smallest_fills_fewest_slots() {
+        let free: &[(usize, u32)] = &[(0, 4), (1, 2), (2, 3)];
+        assert_eq!(
+            picks(TbSchedulerPolicy::SMALLEST, 10, 3, &[free, free, &[(0, 4)], &[(2, 3)], free]),
+            [Some((1, 0, 2)), Some((1, 2, 2)), Some((0, 4, 4)), Some((2, 8, 2)), None]
+        );
+    }
+
+    #[test]
+    fn greedy_fills_most_slots() {
+        assert_eq!(
+            picks(
+                TbSchedulerPolicy::GREEDY,
+                10,
+                3,
+                &[&[(0, 2), (1, 4), (2, 4)], &[(0, 2), (2, 4)], &[(0, 2)], &[(0, 2)]]
+            ),
+            [Some((1, 0, 4)), Some((2, 4, 4)), Some((0, 8, 2)), None]
+        );
+    }
+
+    #[test]
+    fn rr_rotates_gluls() {
+        let free: &[(usize, u32)] = &[(0, 1), (1, 1), (2, 1)];
+        assert_eq!(
+            picks(TbSchedulerPolicy::RR, 10, 3, &[free, free, free, free, &[(0, 1), (2, 1)]]),
+            [Some((0, 0, 1)), Some((1, 1, 1)), Some((2, 2, 1)), Some((0, 3, 1)), Some((2, 4, 1))]
+        );
+    }
+
+    #[test]
+    fn balanced_caps_dispatches() {
+        let free: &[(usize, u32)] = &[(0, 8), (1, 8)];
+        assert_eq!(
+            picks(TbSchedulerPolicy::BALANCED, 8, 2, &[free, free, free]),
+            [Some((0, 0, 4)), Some((1, 4, 4)), None]
+        );
+        // The GLUL with the fewest blocks so far goes first, whatever its free slots.
+        let free: &[(usize, u32)] = &[(0, 2), (1, 8)];
+        assert_eq!(
+            picks(TbSchedulerPolicy::BALANCED, 6, 2, &[free, free, free, free]),
+            [Some((0, 0, 2)), Some((1, 2, 3)), Some((0, 5, 1)), None]
+        );
+    }
+
+    #[test]
+    fn locality_keeps_slices() {
+        assert_eq!(
+            picks(TbSchedulerPolicy::LOCALITY, 8, 2, &[&[(1, 2)], &[(0, 8)], &[(0, 8)], &[(0, 8)]]),
+            [Some((1, 4, 2)), Some((0, 0, 4)), Some((0, 6, 2)), None]
+        );
+    }
+}
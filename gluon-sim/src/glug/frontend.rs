@@ -1,30 +1,132 @@
-use crate::common::base::{Command, Configurable};
+use crate::common::base::Configurable;
+use crate::common::protocol::{GluonCommand, GluonEvent};
 use crate::common::queue::Queue;
+use crate::context::Context;
+use crate::ring::Rings;
 use serde::Deserialize;
+use std::ops::Range;
+use std::sync::Arc;
 
 #[derive(Debug, Clone, Copy, Deserialize)]
 #[serde(default)]
 pub struct FrontendConfig {
     command_queue_size: usize,
+    /// Headers fetched from one command ring per cycle.
+    ring_fetch_batch: usize,
 }
 
 impl Default for FrontendConfig {
     fn default() -> Self {
         FrontendConfig {
             command_queue_size: 4,
+            ring_fetch_batch: 4,
         }
     }
 }
 
-#[derive(Default)]
+/// A client command ring the frontend fetches from once its doorbell has been rung.
+struct RingSource {
+    context: Context,
+    rings: Arc<Rings>,
+    armed: bool,
+}
+
 pub struct Frontend {
-    pub command_queue: Queue<Command>,
+    pub command_queue: Queue<GluonCommand>,
+    ring_fetch_batch: usize,
+    rings: Vec<RingSource>,
+    /// Credits freed per stream id, so each client only gets back the credits its own commands used.
+    credits: Vec<u32>,
 }
 
 impl Configurable<FrontendConfig> for Frontend {
     fn new(config: &FrontendConfig) -> Self {
         Frontend {
             command_queue: Queue::new(config.command_queue_size),
+            ring_fetch_batch: config.ring_fetch_batch,
+            rings: Vec::new(),
+            credits: Vec::new(),
+        }
+    }
+}
+
+impl Frontend {
+    /// One credit per command queue entry; each host may have at most this many commands in flight
+    /// between its socket and the stream queues.
+    pub fn initial_credits(&self) -> u32 {
+        self.command_queue.cap() as u32
+    }
+
+    pub fn pop(&mut self) -> Option<GluonCommand> {
+        let cmd = self.command_queue.pop();
+        if let Some(cmd) = &cmd {
+            self.return_credit(cmd.sid());
+        }
+        cmd
+    }
+
+    /// A rejected command never enters the command queue, so its credit is returned immediately.
+    pub fn reject(&mut self, sid: u8) {
+        self.return_credit(sid);
+    }
+
+    pub fn take_credits(&mut self, sids: Range<u8>) -> u32 {
+        self.credits
+            .iter_mut()
+            .skip(sids.start as usize)
+            .take(sids.len())
+            .map(std::mem::take)
+            .sum()
+    }
+
+    pub fn attach_ring(&mut self, context: Context, rings: Arc<Rings>) {
+        self.detach_ring(context.id);
+        self.rings.push(RingSource {
+            context,
+            rings,
+            armed: false,
+        });
+    }
+
+    pub fn detach_ring(&mut self, context_id: usize) {
+        self.rings.retain(|source| source.context.id != context_id);
+    }
+
+    pub fn ring_doorbell(&mut self, context_id: usize) {
+        self.rings
+            .iter_mut()
+            .filter(|source| source.context.id == context_id)
+            .for_each(|source| source.armed = true);
+    }
+
+    /// A doorbell has been rung and the ring behind it has not been drained yet.
+    pub fn armed(&self) -> bool {
+        self.rings.iter().any(|source| source.armed)
+    }
+
+    /// Fetch a batch from every armed ring into the command queue, as far as it has room. Headers that
+    /// fail admission are returned as the events to report in their place.
+    pub fn fetch(&mut self) -> Vec<GluonEvent> {
+        let mut rejected = Vec::new();
+        for source in self.rings.iter_mut().filter(|source| source.armed) {
+            let room = self.command_queue.cap() - self.command_queue.len();
+            for buffer in source.rings.fetch_commands(room.min(self.ring_fetch_batch)) {
+                match source.context.admit(&buffer) {
+                    Ok(command) => {
+                        self.command_queue.push(command);
+                    }
+                    Err(event) => rejected.push(event),
+                }
+            }
+            source.armed = source.rings.pending_commands() > 0;
+        }
+        rejected
+    }
+
+    fn return_credit(&mut self, sid: u8) {
+        if self.credits.len() <= sid as usize {
+            self.credits.resize(sid as usize + 1, 0);
         }
+        self.credits[sid as usize] += 1;
     }
 }
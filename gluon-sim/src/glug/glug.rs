@@ -1,16 +1,26 @@
-use crate::common::base::{Clocked, CmdType, Command, Configurable, DMADir, Event, SimErr};
+use crate::common::base::{Clocked, CmdType, Configurable, SimErr};
+use crate::common::protocol::{GluonCommand, GluonEvent};
 use crate::glug::completion::Completion;
 use crate::glug::decode_dispatch::{DecodeDispatch, DecodeDispatchConfig};
+use crate::glug::dma::{DMAConfig, DMAPorts, DMAUnit};
+use crate::glug::arbiter::ArbiterStats;
+use crate::glug::dram::{DRAMConfig, DRAM};
 use crate::glug::engine::{Engine, EngineConfig};
 use crate::glug::frontend::{Frontend, FrontendConfig};
+use crate::glug::partition::{GLULPartition, KernelPlacement};
 use crate::glug::stream::{Stream, StreamConfig};
 use crate::glul::glul::{GLULConfig, GLUL};
-use cyclotron::base::mem::HasMemory;
+use crate::context::Context;
+use crate::ring::Rings;
+use crate::shared_memory::HostMemory;
+use crate::trace::{SharedTrace, TraceRecord};
 use cyclotron::info;
 use cyclotron::sim::config::MemConfig;
 use cyclotron::sim::log::Logger;
 use cyclotron::sim::flat_mem::FlatMemory;
 use serde::Deserialize;
+use std::collections::VecDeque;
+use std::ops::Range;
 use std::sync::{Arc, RwLock};
 
 #[derive(Debug, Default, Clone, Deserialize)]
@@ -21,43 +31,214 @@ pub struct GLUGConfig {
     pub decode_dispatch: DecodeDispatchConfig,
     pub stream: StreamConfig,
     pub engine: EngineConfig,
+    pub dma: DMAConfig,
+    pub dram: DRAMConfig,
     pub gluls: Vec<GLULConfig>,
     pub gluon_log_level: u64,
     pub muon_log_level: u64,
+    /// Client contexts, derived from the server configuration rather than read from the file. A FENCE
+    /// waits for its own context's streams, and memory engines keep copies driven by descriptors inside
+    /// the context's DRAM window. None treats the device as a single unrestricted context.
+    #[serde(skip)]
+    pub contexts: Vec<Context>,
+}
+
+/// How long an engine's memory and DMA requests waited for arbitration.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct EngineStats {
+    pub mem: ArbiterStats,
+    pub dma: ArbiterStats,
 }
 
 pub struct GLUG {
-    cmd_valid: bool,
-    cmd: Command,
+    cmd: Option<GluonCommand>,
+    /// Events that bypass the completion queue: rejected and aborted commands.
+    rejected: VecDeque<GluonEvent>,
+    /// Events and credits produced so far, so callers can tell whether a tick left anything to deliver.
+    events_produced: u64,
     sq_idx: usize,
 
     frontend: Frontend,
     decode_dispatch: DecodeDispatch,
     stream: Stream,
     engines: Vec<Box<dyn Engine>>,
+    dma: DMAUnit,
     completion: Completion,
 
     gluls: Vec<GLUL>,
+    partition: GLULPartition,
+    /// Kernel engines come first among the engines.
+    num_kernel_engines: usize,
+    contexts: Vec<Context>,
+    cycle: u64,
 
-    dram: Arc<RwLock<FlatMemory>>,
+    dram: DRAM,
+    /// Host memory per stream id; every stream of a client context shares the client's region.
+    hosts: Vec<Option<Arc<dyn HostMemory>>>,
+    trace: Option<SharedTrace>,
 
     logger: Arc<Logger>,
 }
 
 impl GLUG {
-    pub fn submit_command(&mut self, command: Command) {
-        self.cmd_valid = true;
-        self.cmd = command;
+    /// Ready/valid handshake into the frontend. Returns false and leaves the latched command untouched
+    /// if the previous command has not been pushed to the command queue yet.
+    pub fn submit_command(&mut self, command: GluonCommand) -> bool {
+        if self.cmd.is_some() {
+            return false;
+        }
+        self.cmd = Some(command);
+        self.record(TraceRecord::COMMAND(command));
+        true
+    }
+
+    pub fn set_trace(&mut self, trace: Option<SharedTrace>) {
+        self.trace = trace;
+    }
+
+    fn record(&self, record: TraceRecord) {
+        if let Some(trace) = &self.trace {
+            trace.lock().expect("trace poisoned").record(record);
+        }
+    }
+
+    /// Host memory that DMA on the given streams reads from and writes to, negotiated during the client
+    /// handshake. `None` detaches the region once the client has gone.
+    pub fn set_host_memory(&mut self, sids: Range<u8>, host: Option<Arc<dyn HostMemory>>) {
+        sids.for_each(|sid| {
+            *self.hosts.get_mut(sid as usize).expect("sid out of bounds") = host.clone();
+        });
+    }
+
+    /// Fetch the context's commands from its command ring instead of the socket.
+    pub fn attach_ring(&mut self, context: Context, rings: Arc<Rings>) {
+        self.frontend.attach_ring(context, rings);
+    }
+
+    pub fn detach_ring(&mut self, context_id: usize) {
+        self.frontend.detach_ring(context_id);
+    }
+
+    pub fn ring_doorbell(&mut self, context_id: usize) {
+        self.frontend.ring_doorbell(context_id);
+    }
+
+    pub fn num_streams(&self) -> usize {
+        self.stream.sq.len()
     }
 
-    pub fn get_completion(&mut self) -> Option<Event> {
-        if let Some(event) = self.completion.try_clear_completion() {
-            self.stream.clear_in_flight(event.sid());
+    pub fn can_submit(&self) -> bool {
+        self.cmd.is_none()
+    }
+
+    /// Drop a command that was refused before reaching the frontend, returning its credit and reporting
+    /// `event` to the host in its place.
+    pub fn reject_command(&mut self, event: GluonEvent) {
+        let sid = event.sid().expect("Rejection without sid");
+        info!(self.logger, "Rejected command for sid {}: {:?}", sid, event);
+        self.frontend.reject(sid);
+        self.record(TraceRecord::REJECT(event));
+        self.rejected.push_back(event);
+        self.events_produced += 1;
+    }
+
+    pub fn initial_credits(&self) -> u32 {
+        self.frontend.initial_credits()
+    }
+
+    pub fn events_produced(&self) -> u64 {
+        self.events_produced
+    }
+
+    /// True when ticking cannot make progress: no command is waiting anywhere in the pipeline and no
+    /// engine or GLUL has work. Completions may still be waiting for the host to collect them.
+    pub fn quiescent(&mut self) -> bool {
+        self.cmd.is_none()
+            && !self.frontend.armed()
+            && self.frontend.command_queue.empty()
+            && self.stream.sq.iter().all(|sq| sq.empty())
+            && self.decode_dispatch.qs.iter().all(|eq| eq.q.empty())
+            && self.engines.iter_mut().all(|engine| !Clocked::busy(engine.as_mut()))
+            && self.gluls.iter_mut().all(|glul| !glul.busy())
+    }
+
+    /// Credits freed on the given streams since the last call, to be returned to their host.
+    pub fn take_credits(&mut self, sids: Range<u8>) -> u32 {
+        self.frontend.take_credits(sids)
+    }
+
+    /// Next event for the given streams, rejections first.
+    pub fn get_completion(&mut self, sids: Range<u8>) -> Option<GluonEvent> {
+        let owned = |event: &GluonEvent| event.sid().is_some_and(|sid| sids.contains(&sid));
+        if let Some(idx) = self.rejected.iter().position(owned) {
+            self.rejected.remove(idx)
+        } else if let Some(event) = self.completion.try_clear_completion(sids.clone()) {
+            self.stream.clear_in_flight(event.sid().expect("Completion without sid"));
             Some(event)
         } else {
             None
         }
     }
+
+    /// Stop everything the host is waiting on after the simulator failed with `err`. Completions already
+    /// produced are kept; every other outstanding command completes with `SIM`, in stream order, and
+    /// returns its credit.
+    pub fn abort(&mut self, err: SimErr) {
+        info!(self.logger, "Aborting outstanding commands: {:?}", err);
+        for sid in 0..self.stream.sq.len() as u8 {
+            if let Some(event) = self.completion.take(sid) {
+                self.rejected.push_back(event);
+            } else if self.stream.sq_in_flight[sid as usize] {
+                self.rejected.push_back(GluonEvent::SIM { sid, err });
+            }
+            self.stream.clear_in_flight(sid);
+            while let Some(cmd) = self.stream.sq[sid as usize].pop() {
+                self.rejected.push_back(GluonEvent::SIM { sid: cmd.sid(), err });
+            }
+        }
+        self.decode_dispatch.qs.iter_mut().for_each(|eq| while eq.q.pop().is_some() {});
+        while let Some(cmd) = self.frontend.pop() {
+            self.rejected.push_back(GluonEvent::SIM { sid: cmd.sid(), err });
+        }
+        if let Some(cmd) = self.cmd.take() {
+            self.frontend.reject(cmd.sid());
+            self.rejected.push_back(GluonEvent::SIM { sid: cmd.sid(), err });
+        }
+        self.events_produced += 1;
+    }
+
+    pub fn engine_stats(&self) -> Vec<EngineStats> {
+        self.dma
+            .stats()
+            .iter()
+            .zip(self.dram.stats())
+            .map(|(dma, mem)| EngineStats { mem: *mem, dma: *dma })
+            .collect()
+    }
+
+    /// Where the last completed kernel of each kernel engine ran.
+    pub fn kernel_placements(&self) -> impl Iterator<Item = &KernelPlacement> {
+        self.partition.placements()
+    }
+
+    /// Stream ids of the client context `sid` belongs to; a FENCE only waits for these.
+    fn context_streams(&self, sid: u8) -> Range<usize> {
+        self.contexts
+            .iter()
+            .find(|context| context.sids.contains(&sid))
+            .map_or(0..self.num_streams(), |context| {
+                context.sids.start as usize..context.sids.end as usize
+            })
+    }
+
+    /// True once no command on the given streams is latched, queued, executing or awaiting delivery.
+    pub fn streams_idle(&self, sids: Range<u8>) -> bool {
+        let owned = |sid: u8| sids.contains(&sid);
+        !self.cmd.is_some_and(|cmd| owned(cmd.sid()))
+            && !self.frontend.command_queue.iter().any(|cmd| owned(cmd.sid()))
+            && !self.rejected.iter().any(|event| event.sid().is_some_and(owned))
+            && sids.clone().all(|sid| self.stream.idle(sid) && !self.completion.pending(sid))
+    }
 }
 
 impl Configurable<GLUGConfig> for GLUG {
@@ -78,6 +259,7 @@ impl Configurable<GLUGConfig> for GLUG {
             .collect::<Vec<_>>();
 
         let mut engines = engine_config.generate_engines(logger.clone());
+        let num_engines = engines.len();
         engines.iter_mut().for_each(|engine| {
             engine.set_gluls(
                 gluls
@@ -85,19 +267,28 @@ impl Configurable<GLUGConfig> for GLUG {
                     .map(|glul| glul.get_status().clone())
                     .collect::<Vec<_>>(),
             );
+            engine.set_contexts(config.contexts.clone());
         });
 
         GLUG {
-            cmd: Command::default(),
-            cmd_valid: false,
+            cmd: None,
+            rejected: VecDeque::new(),
+            events_produced: 0,
             sq_idx: 0,
             frontend: Frontend::new(&config.frontend),
             decode_dispatch: DecodeDispatch::new(&config.decode_dispatch),
             stream: Stream::new(&config.stream),
             engines,
+            dma: DMAUnit::new(&config.dma, num_engines, num_engines, logger.clone()),
             completion: Completion::new(&config.stream),
+            partition: GLULPartition::new(engine_config.glul_policy, engine_config.num_kernel_engines, gluls.len()),
+            num_kernel_engines: engine_config.num_kernel_engines,
+            contexts: config.contexts.clone(),
+            cycle: 0,
             gluls,
-            dram,
+            dram: DRAM::new(&config.dram, dram, num_engines + 2 * config.dma.num_blocks),
+            hosts: vec![None; config.stream.num_sq],
+            trace: None,
             logger,
         }
     }
@@ -107,33 +298,47 @@ impl Clocked for GLUG {
     fn tick(&mut self) -> Result<(), SimErr> {
         // TODO: Report erroring threadid
 
-        // Check GLUL completions, notify engines of completion or error, terminate GLULs of erroring engines
+        self.cycle += 1;
+
+        // Check GLUL completions, notify engines of completion or error, terminate the other GLULs of
+        // erroring engines
         self.gluls
             .iter_mut()
-            .filter_map(|glul| glul.try_acknowledge_done_err())
+            .enumerate()
+            .filter_map(|(glul_idx, glul)| glul.try_acknowledge_done_err().map(|result| (glul_idx, result)))
             .collect::<Vec<_>>()
             .into_iter()
-            .for_each(|result| {
+            .for_each(|(glul_idx, result)| {
+                if self.gluls[glul_idx].idle() {
+                    self.partition.release(glul_idx);
+                }
                 if let Ok((engine_idx, tbs)) = result {
                     self.engines
                         .get_mut(engine_idx)
                         .expect("Engine idx out of bounds")
-                        .notify_glul_done(tbs);
+                        .notify_glul_done(tbs.len() as u32);
                 } else if let Err((engine_idx, err)) = result {
                     self.engines
                         .get_mut(engine_idx)
                         .expect("Engine idx out of bounds")
                         .notify_glul_err(err);
-                    self.gluls
-                        .iter_mut()
-                        .for_each(|glul| glul.try_kill(engine_idx));
+                    self.gluls.iter_mut().enumerate().for_each(|(idx, glul)| {
+                        if glul.try_kill(engine_idx) {
+                            self.partition.release(idx);
+                        }
+                    });
                 }
             });
 
         // Enqueue engine completions
-        self.engines.iter_mut().for_each(|engine| {
+        self.engines.iter_mut().enumerate().for_each(|(idx, engine)| {
             if let Some(event) = engine.get_completion() {
+                if idx < self.num_kernel_engines {
+                    let placement = self.partition.finish(idx, event.sid().expect("Completion without sid"));
+                    info!(self.logger, "Kernel engine {} ran {:?}", idx, placement);
+                }
                 self.completion.set_completion(event);
+                self.events_produced += 1;
             }
         });
 
@@ -144,6 +349,7 @@ impl Clocked for GLUG {
             .for_each(|(idx, engine)| {
                 if let Some(glul_req) = engine.get_glul_req() {
                     let thread_blocks = glul_req.thread_blocks.as_ref().expect("Thread blocks not set").clone();
+                    self.partition.assign(glul_req.idx, idx, thread_blocks.block_idxs.len() as u32);
                     self.gluls[glul_req.idx].submit_thread_block(
                         thread_blocks,
                         idx,
@@ -155,71 +361,69 @@ impl Clocked for GLUG {
         // Tick GLULs
         self.gluls.iter_mut().try_for_each(|glul| glul.tick())?;
 
-        // Service Mem requests
-        if let Some(engine) = self
-            .engines
-            .iter_mut()
-            .find(|engine| engine.get_mem_req().is_some())
-        {
-            let mem_req = engine.get_mem_req().expect("Mem: unreachable");
-            let mut dram = self.dram.write().expect("gmem poisoned");
-            info!(self.logger, "Served mem {:?}", mem_req);
-            if mem_req.write {
-                dram.write(mem_req.addr as usize, &mem_req.data).expect("gmem write errored");
-                engine.set_mem_resp(None);
-            } else {
-                let read_data = dram.read(mem_req.addr as usize, mem_req.bytes as usize).expect("gmem read errored");
-                engine.set_mem_resp(Some(&read_data.to_vec()));
+        // Service Mem requests; engine `idx` uses DRAM port `idx`
+        self.dram.tick();
+        self.engines.iter_mut().enumerate().for_each(|(idx, engine)| {
+            if let Some(resp) = self.dram.take_resp(idx) {
+                info!(self.logger, "Served mem for engine {}", idx);
+                engine.set_mem_resp(&resp);
+            } else if let Some(mem_req) = engine.get_mem_req() {
+                if self.dram.port_idle(idx) {
+                    self.dram.request(idx, mem_req.clone());
+                }
             }
-        }
+        });
 
         // Service DMA requests
-        if let Some(engine) = self
-            .engines
-            .iter_mut()
-            .find(|engine| engine.get_dma_req().is_some())
-        {
-            let dma_req = engine.get_dma_req().expect("DMA: unreachable");
-            match dma_req.dir {
-                DMADir::H2D => {
-                    let mut dram = self.dram.write().expect("gmem poisoned");
-
-                    let data = (0..dma_req.sz)
-                        .map(|byte| unsafe { *((dma_req.src_addr + byte) as *const u8) })
-                        .collect::<Vec<u8>>();
-                    dram.write(dma_req.target_addr as usize, &data).expect("gmem write errored");
-                }
+        self.dma.tick(
+            &mut self.engines,
+            &mut DMAPorts {
+                hosts: &self.hosts,
+                dram: &mut self.dram,
+                trace: self.trace.as_ref(),
+            },
+        );
 
-                DMADir::D2H => {
-                    let dram = self.dram.read().expect("gmem poisoned");
-                    let data = dram.read(dma_req.src_addr as usize, dma_req.sz as usize).expect("gmem read errored");
-                    data.iter().enumerate().for_each(|(idx, byte)| unsafe {
-                        *((dma_req.target_addr + idx as u32) as *mut u8) = *byte;
-                    });
-                }
-            };
-
-            engine.done_dma_req();
-        }
+        // Grant idle GLULs to kernel engines with thread blocks left
+        let wants = self.engines[..self.num_kernel_engines]
+            .iter()
+            .map(|engine| engine.wants_gluls())
+            .collect::<Vec<_>>();
+        self.partition
+            .grant(self.cycle, &wants)
+            .into_iter()
+            .zip(self.engines.iter_mut())
+            .for_each(|(grants, engine)| engine.set_glul_grants(grants));
 
         // Tick engines
         self.engines
             .iter_mut()
             .try_for_each(|engine| engine.tick())?;
 
+        // Kill the thread blocks of kernels their engine stopped, e.g. on a timeout
+        self.engines.iter_mut().enumerate().for_each(|(engine_idx, engine)| {
+            if engine.take_kill() {
+                self.gluls.iter_mut().enumerate().for_each(|(idx, glul)| {
+                    if glul.try_kill(engine_idx) {
+                        self.partition.release(idx);
+                    }
+                });
+            }
+        });
+
         // Tick decode
         self.decode_dispatch
             .qs
             .iter_mut()
             .map(|eq| {
-                (
-                    eq.q.pop(),
-                    self.engines
-                        .iter_mut()
-                        .enumerate()
-                        .find(|(_, engine)| engine.cmd_type() == eq.engine_type && !engine.busy())
-                        .map(|(idx, _)| idx),
-                )
+                let engine_idx = self
+                    .engines
+                    .iter_mut()
+                    .enumerate()
+                    .find(|(_, engine)| engine.cmd_type() == eq.engine_type && !engine.busy())
+                    .map(|(idx, _)| idx);
+                // Commands stay queued until an engine of their type is free
+                (engine_idx.and_then(|_| eq.q.pop()), engine_idx)
             })
             .collect::<Vec<_>>()
             .iter()
@@ -250,28 +454,42 @@ impl Clocked for GLUG {
         }
 
         // Tick frontend
-        if self.cmd_valid && self.frontend.command_queue.push(self.cmd) {
-            info!(self.logger, "Pushed {:?} to command queue", self.cmd);
-            self.cmd_valid = false;
-            self.cmd = Command::default();
+        if let Some(cmd) = self.cmd {
+            if self.frontend.command_queue.push(cmd) {
+                info!(self.logger, "Pushed {:?} to command queue", cmd);
+                self.cmd = None;
+            }
         }
+        let queued = self.frontend.command_queue.len();
+        let rejections = self.frontend.fetch();
+        if self.trace.is_some() {
+            self.frontend
+                .command_queue
+                .iter()
+                .skip(queued)
+                .for_each(|cmd| self.record(TraceRecord::COMMAND(*cmd)));
+        }
+        rejections.into_iter().for_each(|event| self.reject_command(event));
 
         if let Some(frontend_out_cmd) = self
             .frontend
             .command_queue
             .peek()
             .map(|cmd| match cmd.cmd_type() {
-                CmdType::FENCE => self.completion.eq.iter().all(|event| event.is_none()),
+                CmdType::FENCE => self.completion.eq[self.context_streams(cmd.sid())]
+                    .iter()
+                    .all(|event| event.is_none()),
                 _ => self.stream.can_enqueue(cmd.sid()),
             })
             .unwrap_or(false)
             .then(|| {
                 self.frontend
-                    .command_queue
                     .pop()
                     .expect("Cannot be empty here")
             })
         {
+            // Popping returned a credit to the host.
+            self.events_produced += 1;
             self.stream.enqueue(frontend_out_cmd.sid(), frontend_out_cmd);
         }
 
@@ -1,7 +1,12 @@
+pub mod arbiter;
 pub mod completion;
 pub mod decode_dispatch;
+pub mod dma;
+pub mod dram;
 pub mod engine;
 pub mod engines;
 pub mod frontend;
+#[allow(clippy::module_inception)]
 pub mod glug;
+pub mod partition;
 pub mod stream;
\ No newline at end of file
@@ -0,0 +1,211 @@
+use serde::Deserialize;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum PartitionPolicy {
+    /// Any kernel may use any GLUL; idle GLULs go to the kernel that has waited longest.
+    #[default]
+    FIRST,
+    /// Kernel engine `i` only uses GLULs `j` with `j % num_kernel_engines == i`, or GLUL
+    /// `i % num_gluls` if there are fewer GLULs than kernel engines.
+    STATIC,
+    /// As `FIRST`, but a kernel holds at most its share of the GLULs: their number divided by the
+    /// number of running kernels, rounded up.
+    FAIR,
+}
+
+/// Thread blocks a completed kernel ran on each GLUL.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KernelPlacement {
+    pub sid: u8,
+    pub engine: usize,
+    pub tbs: Vec<u32>,
+}
+
+/// Decides which GLULs each kernel engine may dispatch thread blocks to. A GLUL belongs to one engine
+/// from the dispatch until all its thread blocks complete or are killed, and is granted to its owner
+/// for refills meanwhile. Each idle GLUL is granted to at most one engine per cycle, so concurrent
+/// kernels never share a GLUL.
+pub struct GLULPartition {
+    policy: PartitionPolicy,
+    num_gluls: usize,
+    /// Engine each GLUL runs thread blocks for.
+    owners: Vec<Option<usize>>,
+    /// Cycle each engine's kernel started waiting for GLULs, while it has thread blocks left.
+    waiting_since: Vec<Option<u64>>,
+    /// Thread blocks the running kernel of each engine has dispatched to each GLUL.
+    running: Vec<Vec<u32>>,
+    /// Where the last completed kernel of each engine ran; replaced on every completion so the
+    /// record stays bounded however long the simulator runs.
+    placements: Vec<Option<KernelPlacement>>,
+}
+
+impl GLULPartition {
+    pub fn new(policy: PartitionPolicy, num_kernel_engines: usize, num_gluls: usize) -> Self {
+        GLULPartition {
+            policy,
+            num_gluls,
+            owners: vec![None; num_gluls],
+            waiting_since: vec![None; num_kernel_engines],
+            running: vec![vec![0; num_gluls]; num_kernel_engines],
+            placements: vec![None; num_kernel_engines],
+        }
+    }
+
+    /// GLULs each kernel engine may dispatch to this cycle. `wants[engine]` marks the GLULs the
+    /// engine's remaining thread blocks fit on, and is empty once they have all been dispatched.
+    pub fn grant(&mut self, cycle: u64, wants: &[Vec<bool>]) -> Vec<Vec<bool>> {
+        let num_engines = self.waiting_since.len();
+        assert_eq!(wants.len(), num_engines, "GLUL partition: one demand per kernel engine");
+        wants.iter().zip(self.waiting_since.iter_mut()).for_each(|(want, since)| {
+            if want.iter().any(|fits| *fits) {
+                since.get_or_insert(cycle);
+            } else {
+                *since = None;
+            }
+        });
+
+        let held = |owners: &[Option<usize>], engine: usize| owners.iter().filter(|owner| **owner == Some(engine)).count();
+        let running = (0..num_engines)
+            .filter(|engine| self.waiting_since[*engine].is_some() || held(&self.owners, *engine) > 0)
+            .count();
+        let share = self.num_gluls.div_ceil(running.max(1));
+
+        let mut order = (0..num_engines).filter(|engine| self.waiting_since[*engine].is_some()).collect::<Vec<_>>();
+        order.sort_by_key(|engine| (self.waiting_since[*engine], *engine));
+
+        let mut claimed = self.owners.clone();
+        let mut grants = vec![vec![false; self.num_gluls]; num_engines];
+        for engine in order {
+            for glul in 0..self.num_gluls {
+                let allowed = wants[engine].get(glul).copied().unwrap_or(false)
+                    && (self.owners[glul] == Some(engine)
+                        || (claimed[glul].is_none()
+                            && self.eligible(engine, glul, num_engines)
+                            && (self.policy != PartitionPolicy::FAIR || held(&claimed, engine) < share)));
+                if allowed {
+                    claimed[glul] = Some(engine);
+                    grants[engine][glul] = true;
+                }
+            }
+        }
+        grants
+    }
+
+    fn eligible(&self, engine: usize, glul: usize, num_engines: usize) -> bool {
+        match self.policy {
+            PartitionPolicy::FIRST | PartitionPolicy::FAIR => true,
+            PartitionPolicy::STATIC if self.num_gluls < num_engines => glul == engine % self.num_gluls,
+            PartitionPolicy::STATIC => glul % num_engines == engine,
+        }
+    }
+
+    /// `engine` dispatched `tbs` thread blocks to `glul`.
+    pub fn assign(&mut self, glul: usize, engine: usize, tbs: u32) {
+        assert!(
+            self.owners[glul].is_none_or(|owner| owner == engine),
+            "GLUL partition: GLUL {} already in use",
+            glul
+        );
+        self.owners[glul] = Some(engine);
+        self.running[engine][glul] += tbs;
+    }
+
+    /// All thread blocks on `glul` completed or were killed.
+    pub fn release(&mut self, glul: usize) {
+        self.owners[glul] = None;
+    }
+
+    /// The kernel of `engine` completed on stream `sid`; record where it ran in place of the engine's
+    /// previous kernel.
+    pub fn finish(&mut self, engine: usize, sid: u8) -> &KernelPlacement {
+        let tbs = std::mem::replace(&mut self.running[engine], vec![0; self.num_gluls]);
+        self.placements[engine].insert(KernelPlacement { sid, engine, tbs })
+    }
+
+    /// Placement of the last completed kernel of each engine that has completed one.
+    pub fn placements(&self) -> impl Iterator<Item = &KernelPlacement> {
+        self.placements.iter().flatten()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL: [bool; 4] = [true; 4];
+    const NONE: [bool; 4] = [false; 4];
+
+    fn wants(engines: &[[bool; 4]]) -> Vec<Vec<bool>> {
+        engines.iter().map(|want| want.to_vec()).collect()
+    }
+
+    #[test]
+    fn first_prefers_longest_waiting() {
+        let mut partition = GLULPartition::new(PartitionPolicy::FIRST, 2, 4);
+        assert_eq!(partition.grant(0, &wants(&[NONE, ALL])), wants(&[NONE, ALL]));
+        // Engine 1 has waited since cycle 0, so it is offered every GLUL before engine 0.
+        assert_eq!(partition.grant(1, &wants(&[ALL, ALL])), wants(&[NONE, ALL]));
+        partition.assign(0, 1, 2);
+        partition.assign(1, 1, 1);
+        assert_eq!(
+            partition.grant(2, &wants(&[ALL, NONE])),
+            wants(&[[false, false, true, true], NONE])
+        );
+        // Refills go to the owner only.
+        assert_eq!(
+            partition.grant(3, &wants(&[ALL, ALL])),
+            wants(&[[false, false, true, true], [true, true, false, false]])
+        );
+        partition.release(0);
+        assert_eq!(
+            partition.grant(4, &wants(&[ALL, NONE])),
+            wants(&[[true, false, true, true], NONE])
+        );
+    }
+
+    #[test]
+    fn static_splits_gluls() {
+        let mut partition = GLULPartition::new(PartitionPolicy::STATIC, 2, 4);
+        assert_eq!(
+            partition.grant(0, &wants(&[ALL, ALL])),
+            wants(&[[true, false, true, false], [false, true, false, true]])
+        );
+        assert_eq!(
+            partition.grant(1, &wants(&[NONE, ALL])),
+            wants(&[NONE, [false, true, false, true]])
+        );
+
+        // With fewer GLULs than engines, engines 0 and 2 share GLUL 0.
+        let mut partition = GLULPartition::new(PartitionPolicy::STATIC, 3, 2);
+        let grants = partition.grant(0, &[vec![true; 2], vec![true; 2], vec![true; 2]]);
+        assert_eq!(grants, [vec![true, false], vec![false, true], vec![false, false]]);
+        let grants = partition.grant(1, &[vec![false; 2], vec![false; 2], vec![true; 2]]);
+        assert_eq!(grants, [vec![false, false], vec![false, false], vec![true, false]]);
+    }
+
+    #[test]
+    fn fair_caps_share() {
+        let mut partition = GLULPartition::new(PartitionPolicy::FAIR, 2, 4);
+        assert_eq!(partition.grant(0, &wants(&[ALL, NONE])), wants(&[ALL, NONE]));
+        partition.assign(0, 0, 1);
+        // Two running kernels: each may hold half of the GLULs.
+        assert_eq!(
+            partition.grant(1, &wants(&[ALL, ALL])),
+            wants(&[[true, true, false, false], [false, false, true, true]])
+        );
+    }
+
+    #[test]
+    fn finish_keeps_last_kernel() {
+        let mut partition = GLULPartition::new(PartitionPolicy::FIRST, 2, 4);
+        partition.assign(0, 0, 3);
+        partition.assign(2, 0, 2);
+        assert_eq!(partition.finish(0, 5).tbs, [3, 0, 2, 0]);
+        partition.assign(1, 1, 1);
+        partition.finish(1, 6);
+        partition.assign(3, 0, 4);
+        assert_eq!(partition.finish(0, 7).tbs, [0, 0, 0, 4]);
+        let placements = partition.placements().map(|placement| (placement.engine, placement.sid)).collect::<Vec<_>>();
+        assert_eq!(placements, [(0, 7), (1, 6)]);
+    }
+}
@@ -1,6 +1,6 @@
 use serde::Deserialize;
 
-use crate::common::{base::{Command, Configurable}, queue::Queue};
+use crate::common::{base::Configurable, protocol::GluonCommand, queue::Queue};
 
 #[derive(Debug, Clone, Deserialize)]
 #[serde(default)]
@@ -19,7 +19,7 @@ impl Default for StreamConfig {
 }
 
 pub struct Stream {
-    pub sq: Vec<Queue<Command>>,
+    pub sq: Vec<Queue<GluonCommand>>,
     pub sq_in_flight: Vec<bool>,
 }
 
@@ -37,12 +37,12 @@ impl Stream {
         !self.sq[sid as usize].full()
     }
 
-    pub fn enqueue(&mut self, sid: u8, cmd: Command) {
+    pub fn enqueue(&mut self, sid: u8, cmd: GluonCommand) {
         assert!(sid < self.sq.len() as u8, "sid out of bounds");
         self.sq[sid as usize].push(cmd);
     }
 
-    pub fn try_pop(&mut self, sid: u8) -> Option<Command> {
+    pub fn try_pop(&mut self, sid: u8) -> Option<GluonCommand> {
         assert!(sid < self.sq.len() as u8, "sid out of bounds");
         if self.sq_in_flight[sid as usize] {
             None
@@ -52,6 +52,12 @@ impl Stream {
         }
     }
 
+    /// No queued or in-flight command on the stream.
+    pub fn idle(&self, sid: u8) -> bool {
+        assert!(sid < self.sq.len() as u8, "sid out of bounds");
+        self.sq[sid as usize].empty() && !self.sq_in_flight[sid as usize]
+    }
+
     pub fn clear_in_flight(&mut self, sid: u8) {
         assert!(sid < self.sq.len() as u8, "sid out of bounds");
         self.sq_in_flight[sid as usize] = false;
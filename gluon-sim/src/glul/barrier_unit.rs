@@ -0,0 +1,146 @@
+use cyclotron::muon::warp::ExecErr;
+
+/// Barrier state of one threadblock. `valid` is set by the first arrival and cleared on release.
+#[derive(Debug, Default, Clone)]
+struct BarrierCounter {
+    valid: bool,
+    /// `(core, warp, pc)` of every warp waiting at the barrier.
+    arrived: Vec<(usize, usize, u32)>,
+}
+
+/// TB-level barrier for `__syncthreads`. Keeps one counter per threadblock slot of the GLUL and releases
+/// all warps of a threadblock once each of them has arrived.
+///
+/// Not driven by the GLUL yet: cyclotron cores report barrier arrivals only to `Neutrino`, which the GLUL
+/// keeps ticking until the cores can hand arrivals to this unit and take releases from it.
+#[derive(Debug, Default)]
+pub struct BarrierUnit {
+    warps_per_tb: usize,
+    counters: Vec<BarrierCounter>,
+}
+
+impl BarrierUnit {
+    pub fn init(&mut self, n_tbs: usize, warps_per_tb: usize) {
+        self.warps_per_tb = warps_per_tb;
+        self.counters = vec![BarrierCounter::default(); n_tbs];
+    }
+
+    pub fn reset(&mut self) {
+        self.counters.clear();
+    }
+
+    /// A new threadblock starts in slot `tb`.
+    pub fn clear(&mut self, tb: usize) {
+        self.counters[tb] = BarrierCounter::default();
+    }
+
+    /// A warp of threadblock `tb` reached the barrier and stalls until released.
+    pub fn arrive(&mut self, tb: usize, core: usize, warp: usize, pc: u32) {
+        let counter = self.counters.get_mut(tb).expect("Barrier unit: tb out of bounds");
+        if !counter.valid {
+            counter.valid = true;
+            counter.arrived.clear();
+        }
+        counter.arrived.push((core, warp, pc));
+    }
+
+    /// Check the barrier of threadblock `tb`, which has `live_warps` warps that have not retired yet.
+    /// Returns the `(core, warp)` pairs to release once all warps have arrived. If every live warp is
+    /// waiting but some warps of the threadblock retired without arriving, the barrier can never
+    /// complete and a deadlock error naming the first waiting warp is returned.
+    pub fn resolve(&mut self, tb: usize, live_warps: usize) -> Result<Vec<(usize, usize)>, ExecErr> {
+        let counter = self.counters.get_mut(tb).expect("Barrier unit: tb out of bounds");
+        if !counter.valid {
+            return Ok(vec![]);
+        }
+        if counter.arrived.len() == self.warps_per_tb {
+            counter.valid = false;
+            return Ok(counter.arrived.drain(..).map(|(core, warp, _)| (core, warp)).collect());
+        }
+        if counter.arrived.len() >= live_warps {
+            let (_, warp, pc) = counter.arrived[0];
+            return Err(ExecErr {
+                pc,
+                warp_id: warp,
+                message: Some(format!(
+                    "barrier deadlock in threadblock {}: {} of {} warps arrived, the rest retired",
+                    tb,
+                    counter.arrived.len(),
+                    self.warps_per_tb
+                )),
+            });
+        }
+        Ok(vec![])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unit() -> BarrierUnit {
+        let mut unit = BarrierUnit::default();
+        unit.init(2, 3);
+        unit
+    }
+
+    #[test]
+    fn releases_when_all_arrive() {
+        let mut unit = unit();
+        unit.arrive(0, 0, 0, 0x100);
+        unit.arrive(0, 0, 1, 0x100);
+        assert_eq!(unit.resolve(0, 3).unwrap(), vec![]);
+        unit.arrive(0, 1, 0, 0x100);
+        assert_eq!(unit.resolve(0, 3).unwrap(), vec![(0, 0), (0, 1), (1, 0)]);
+        // Released warps are not released again, and the next barrier starts from zero.
+        assert_eq!(unit.resolve(0, 3).unwrap(), vec![]);
+        unit.arrive(0, 1, 0, 0x140);
+        assert_eq!(unit.resolve(0, 3).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn partial_arrival_waits() {
+        let mut unit = unit();
+        unit.arrive(1, 2, 0, 0x100);
+        unit.arrive(1, 2, 1, 0x100);
+        for _ in 0..4 {
+            assert_eq!(unit.resolve(1, 3).unwrap(), vec![]);
+        }
+        // The other threadblock's counter is untouched.
+        assert_eq!(unit.resolve(0, 0).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn deadlock_after_exit() {
+        let mut unit = unit();
+        unit.arrive(0, 0, 1, 0x180);
+        unit.arrive(0, 0, 0, 0x1c0);
+        assert_eq!(unit.resolve(0, 3).unwrap(), vec![]);
+        // One warp retired without reaching the barrier.
+        let err = unit.resolve(0, 2).unwrap_err();
+        assert_eq!((err.pc, err.warp_id), (0x180, 1));
+        assert!(err.message.is_some_and(|message| message.contains("2 of 3 warps")));
+    }
+
+    #[test]
+    fn cleared_between_tbs() {
+        let mut unit = unit();
+        unit.arrive(1, 2, 0, 0x100);
+        unit.arrive(1, 2, 1, 0x100);
+        // The threadblock was killed and a new one starts in its slot.
+        unit.clear(1);
+        unit.arrive(1, 3, 0, 0x200);
+        assert_eq!(unit.resolve(1, 3).unwrap(), vec![]);
+        unit.arrive(1, 3, 1, 0x200);
+        unit.arrive(1, 3, 2, 0x200);
+        assert_eq!(unit.resolve(1, 3).unwrap(), vec![(3, 0), (3, 1), (3, 2)]);
+
+        // A reset drops every counter until the next kernel initializes them.
+        unit.arrive(0, 0, 0, 0x100);
+        unit.reset();
+        unit.init(1, 1);
+        assert_eq!(unit.resolve(0, 1).unwrap(), vec![]);
+        unit.arrive(0, 0, 3, 0x300);
+        assert_eq!(unit.resolve(0, 1).unwrap(), vec![(0, 3)]);
+    }
+}
@@ -7,6 +7,7 @@ use cyclotron::{
     muon::{
         config::{LaneConfig, MuonConfig},
         core::MuonCore,
+        scheduler::Scheduler,
         warp::ExecErr,
     },
     neutrino::{config::NeutrinoConfig, neutrino::Neutrino},
@@ -58,18 +59,59 @@ pub struct GLULReq {
 #[derive(Default, Debug, Clone)]
 pub struct GLULStatus {
     pub config: GLULConfig,
-    pub busy: Arc<RwLock<bool>>,
+    /// Thread blocks submitted and not yet completed or killed.
+    pub tbs: Arc<RwLock<u32>>,
 }
 
 impl Configurable<GLULConfig> for GLULStatus {
     fn new(config: &GLULConfig) -> Self {
         GLULStatus {
-            config: config.clone(),
-            busy: Arc::new(RwLock::new(false)),
+            config: *config,
+            tbs: Arc::new(RwLock::new(0)),
         }
     }
 }
 
+/// `(x, y, z)` index of a threadblock in the grid.
+pub type BlockIdx = (u32, u32, u32);
+
+/// Completed threadblocks, or the error that stopped a GLUL, with the engine they belong to.
+pub type GLULAck = Result<(usize, Vec<BlockIdx>), (usize, ExecErr)>;
+
+/// The part of a core the GLUL drives; implemented by cyclotron's `MuonCore`.
+pub trait GLULCore {
+    fn reset(&mut self);
+    fn spawn_n_warps(&mut self, pc: u32, block_idx: BlockIdx, thread_idxs: Vec<Vec<(u32, u32, u32)>>, bp: u32);
+    /// Advance a cycle, resolving barriers through `neutrino`.
+    fn tick(&mut self, neutrino: &mut Neutrino) -> Result<(), ExecErr>;
+    fn all_warps_retired(&self) -> bool;
+    /// Warp scheduler Neutrino updates, if the core has one.
+    fn scheduler(&mut self) -> Option<&mut Scheduler>;
+}
+
+impl GLULCore for MuonCore {
+    fn reset(&mut self) {
+        ModuleBehaviors::reset(self);
+    }
+
+    fn spawn_n_warps(&mut self, pc: u32, block_idx: BlockIdx, thread_idxs: Vec<Vec<(u32, u32, u32)>>, bp: u32) {
+        MuonCore::spawn_n_warps(self, pc, block_idx, thread_idxs, bp);
+    }
+
+    fn tick(&mut self, neutrino: &mut Neutrino) -> Result<(), ExecErr> {
+        self.tick_one();
+        self.process(neutrino)
+    }
+
+    fn all_warps_retired(&self) -> bool {
+        MuonCore::all_warps_retired(self)
+    }
+
+    fn scheduler(&mut self) -> Option<&mut Scheduler> {
+        Some(&mut self.scheduler)
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum GLULState {
     S0,
@@ -78,97 +120,44 @@ pub enum GLULState {
     S3,
 }
 
-pub struct GLUL {
+pub struct GLUL<C: GLULCore = MuonCore> {
     id: usize,
     status: GLULStatus,
-    cores: Vec<(MuonCore, bool)>, // core, scheduled
+    cores: Vec<(C, bool)>, // core, scheduled
+    /// Resolves `__syncthreads` until the cores can report barrier arrivals to `BarrierUnit`.
     neutrino: Neutrino,
+    /// Cores each threadblock of the running kernel occupies; slot `i` owns cores
+    /// `i * cores_per_tb..(i + 1) * cores_per_tb`.
+    cores_per_tb: usize,
+    /// Block index of the threadblock each slot runs.
+    slots: Vec<Option<BlockIdx>>,
     logger: Arc<Logger>,
 
     state: GLULState,
+    /// Threadblocks submitted but not yet spawned; also holds the shape of the running kernel.
     thread_blocks: Option<ThreadBlocks>,
     engine_idx: usize,
 
-    dram: Arc<RwLock<FlatMemory>>,
-
+    /// Block indices of the threadblocks completed since the last acknowledgement.
+    done_tbs: Vec<BlockIdx>,
     done: bool,
     err: Result<(), ExecErr>,
 }
 
-impl Clocked for GLUL {
+impl<C: GLULCore> Clocked for GLUL<C> {
     fn tick(&mut self) -> Result<(), SimErr> {
         match self.state {
-            GLULState::S0 => {
-                if self.thread_blocks.is_some() {
-                    *self.status.busy.write().expect("GLUL busy poisoned") = true;
-                    self.done = false;
-                    self.cores.iter_mut().for_each(|(core, _)| core.reset());
-                    self.neutrino.reset();
-                    self.state = GLULState::S1;
-                }
-            }
+            GLULState::S0 => {}
             GLULState::S1 => {
-                let thread_blocks = self.thread_blocks.as_ref().expect("Thread blocks not set");
-                let threads_per_tb = thread_blocks.block_dim.0
-                    * thread_blocks.block_dim.1
-                    * thread_blocks.block_dim.2;
-                let warps_per_tb = (threads_per_tb / self.status.config.num_lanes as u32).max(1);
-                let remaining_threads = threads_per_tb % self.status.config.num_lanes as u32;
-                let threads_in_last_warp = if remaining_threads == 0 {
-                    self.status.config.num_lanes as u32
-                } else {
-                    remaining_threads
-                };
-                let cores_per_tb = (warps_per_tb as f32 / self.status.config.num_warps as f32).ceil() as usize;
-                let warps_per_core = warps_per_tb / cores_per_tb as u32;
-                let mut thread_idx = (0, 0, 0);
-                debug!(
-                    self.logger,
-                    "GLUL: Spawning threadblocks {:?}, warps_per_tb {:?}, cores_per_tb {:?}, threads_in_last_warp {:?}", thread_blocks.block_idxs, warps_per_tb, cores_per_tb, threads_in_last_warp
-                );
-                thread_blocks.block_idxs.iter().enumerate().for_each(|(tb_idx, block_idx)| {
-                    let core_start = tb_idx * cores_per_tb;
-                    let core_end = core_start + cores_per_tb;
-                    debug!(
-                        self.logger,
-                        "GLUL: Threadblock {:?}, core_start {:?}, core_end {:?}", tb_idx, core_start, core_end
-                    );
-                    (core_start..core_end).for_each(|core_idx| {
-                        let mut thread_idxs = Vec::new();
-                        for warp_idx in 0..warps_per_core {
-                            let lanes_in_warp = if warp_idx == warps_per_core - 1 {
-                                threads_in_last_warp as usize
-                            } else {
-                                self.status.config.num_lanes
-                            };
-                            let mut warp_thread_idxs = Vec::new();
-                            for _ in 0..lanes_in_warp {
-                                warp_thread_idxs.push(thread_idx);
-                                thread_idx.0 = (thread_idx.0 + 1) % thread_blocks.block_dim.0;
-                                if block_idx.0 == 0 {
-                                    thread_idx.1 = (thread_idx.1 + 1) % thread_blocks.block_dim.1;
-                                    if thread_idx.1 == 0 {
-                                        thread_idx.2 = (thread_idx.2 + 1) % thread_blocks.block_dim.2;
-                                    }
-                                }
-                            }
-                            thread_idxs.push(warp_thread_idxs);
-                        }
-                        info!(
-                            self.logger,
-                            "GLUL: Spawning block_idx {:?}, warps {:?}, to core {:?}", block_idx, thread_idxs, core_idx
-                        );
-                        let core = self.cores.get_mut(core_idx).expect("Core index out of bounds");
-                    core.0.spawn_n_warps(thread_blocks.pc, block_idx.clone(), thread_idxs, thread_blocks.bp);
-                        core.1 = true;
-                    });
-                });
+                self.spawn_pending();
                 self.state = GLULState::S2;
             }
             GLULState::S2 => {
+                if self.pending() && self.slots.iter().any(|slot| slot.is_none()) {
+                    self.spawn_pending();
+                }
                 self.cores.iter_mut().filter(|(_, scheduled)| *scheduled).map(|(core, _)| core).for_each(|core| {
-                    core.tick_one();
-                    if let Err(e) = core.process(&mut self.neutrino) {
+                    if let Err(e) = core.tick(&mut self.neutrino) {
                         self.err = Err(e);
                         self.state = GLULState::S3;
                     }
@@ -176,19 +165,33 @@ impl Clocked for GLUL {
                 if self.state == GLULState::S2 {
                     self.neutrino.tick_one();
                     self.neutrino
-                        .update(&mut self.cores.iter_mut().filter(|(_, scheduled)| *scheduled).map(|(core, _)| &mut core.scheduler).collect());
+                        .update(&mut self.cores.iter_mut().filter(|(_, scheduled)| *scheduled).filter_map(|(core, _)| core.scheduler()).collect());
 
-                    if self.cores.iter().filter(|(_, scheduled)| *scheduled).map(|(core, _)| core).all(|core| core.all_warps_retired()) {
-                        self.err = Ok(());
-                        self.state = GLULState::S3;
+                    let cores_per_tb = self.cores_per_tb;
+                    // Free the slot of every threadblock whose warps have all retired
+                    for slot in 0..self.slots.len() {
+                        let tb_cores = slot * cores_per_tb..(slot + 1) * cores_per_tb;
+                        let Some(block_idx) = self.slots[slot] else {
+                            continue;
+                        };
+                        if self.cores[tb_cores.clone()].iter().all(|(core, _)| core.all_warps_retired()) {
+                            info!(self.logger, "GLUL {}: block_idx {:?} done", self.id, block_idx);
+                            self.slots[slot] = None;
+                            self.cores[tb_cores].iter_mut().for_each(|(_, scheduled)| *scheduled = false);
+                            self.done_tbs.push(block_idx);
+                            *self.status.tbs.write().expect("GLUL tbs poisoned") -= 1;
+                        }
+                    }
+                    if !self.pending() && self.slots.iter().all(|slot| slot.is_none()) {
+                        self.thread_blocks = None;
+                        self.state = GLULState::S0;
                     }
                 }
             }
             GLULState::S3 => {
                 self.done = true;
                 self.state = GLULState::S0;
-                self.cores.iter_mut().for_each(|(_, scheduled)| *scheduled = false);
-                *self.status.busy.write().expect("GLUL busy poisoned") = false;
+                self.stop();
             }
         };
 
@@ -196,7 +199,7 @@ impl Clocked for GLUL {
     }
 
     fn busy(&mut self) -> bool {
-        self.state != GLULState::S0
+        self.state != GLULState::S0 || self.done || !self.done_tbs.is_empty()
     }
 }
 
@@ -217,58 +220,294 @@ impl GLUL {
             smem_size: config.shmem,
             lane_config: LaneConfig::default(),
         };
+        let cores = (0..config.num_cores)
+            .map(|i| MuonCore::new(Arc::new(muon_config), glul_id, i, &muon_logger, dram.clone()))
+            .collect();
+        GLUL::with_cores(glul_id, config, cores, gluon_logger)
+    }
+}
+
+impl<C: GLULCore> GLUL<C> {
+    /// A GLUL driving `cores`, one per `config.num_cores`.
+    pub fn with_cores(glul_id: usize, config: GLULConfig, cores: Vec<C>, logger: Arc<Logger>) -> Self {
+        assert_eq!(cores.len(), config.num_cores, "GLUL {}: core count differs from the config", glul_id);
         GLUL {
             id: glul_id,
             status: GLULStatus::new(&config),
-            cores: (0..config.num_cores)
-                .map(|i| (MuonCore::new(Arc::new(muon_config), glul_id, i, &muon_logger, dram.clone()), false))
-                .collect(),
+            cores: cores.into_iter().map(|core| (core, false)).collect(),
             neutrino: Neutrino::new(Arc::new(NeutrinoConfig::default())),
-            logger: gluon_logger,
+            cores_per_tb: 1,
+            slots: vec![],
+            logger,
             state: GLULState::S0,
             thread_blocks: None,
             engine_idx: 0,
+            done_tbs: vec![],
             done: false,
             err: Ok(()),
-            dram,
         }
     }
 
+    /// Threadblocks are waiting for a slot.
+    fn pending(&self) -> bool {
+        self.thread_blocks.as_ref().is_some_and(|tbs| !tbs.block_idxs.is_empty())
+    }
+
+    /// Start submitted threadblocks in the free slots; the rest wait for slots to free up.
+    fn spawn_pending(&mut self) {
+        let free_slots = self.slots.iter().filter(|slot| slot.is_none()).count();
+        let thread_blocks = self.thread_blocks.as_mut().expect("Thread blocks not set");
+        let spawned = free_slots.min(thread_blocks.block_idxs.len());
+        let block_idxs = thread_blocks.block_idxs.drain(..spawned).collect::<Vec<_>>();
+        let thread_blocks = thread_blocks.clone();
+        let threads_per_tb = thread_blocks.block_dim.0
+            * thread_blocks.block_dim.1
+            * thread_blocks.block_dim.2;
+        let warps_per_tb = (threads_per_tb / self.status.config.num_lanes as u32).max(1);
+        let remaining_threads = threads_per_tb % self.status.config.num_lanes as u32;
+        let threads_in_last_warp = if remaining_threads == 0 {
+            self.status.config.num_lanes as u32
+        } else {
+            remaining_threads
+        };
+        let cores_per_tb = self.cores_per_tb;
+        let warps_per_core = warps_per_tb / cores_per_tb as u32;
+        debug!(
+            self.logger,
+            "GLUL: Spawning threadblocks {:?}, warps_per_tb {:?}, cores_per_tb {:?}, threads_in_last_warp {:?}", block_idxs, warps_per_tb, cores_per_tb, threads_in_last_warp
+        );
+        block_idxs.into_iter().for_each(|block_idx| {
+            let slot = self.slots.iter().position(|slot| slot.is_none()).expect("GLUL: no free threadblock slot");
+            self.slots[slot] = Some(block_idx);
+            let core_start = slot * cores_per_tb;
+            let core_end = core_start + cores_per_tb;
+            debug!(
+                self.logger,
+                "GLUL: Threadblock slot {:?}, core_start {:?}, core_end {:?}", slot, core_start, core_end
+            );
+            let mut thread_idx = (0, 0, 0);
+            (core_start..core_end).for_each(|core_idx| {
+                let mut thread_idxs = Vec::new();
+                for warp_idx in 0..warps_per_core {
+                    let lanes_in_warp = if warp_idx == warps_per_core - 1 {
+                        threads_in_last_warp as usize
+                    } else {
+                        self.status.config.num_lanes
+                    };
+                    let mut warp_thread_idxs = Vec::new();
+                    for _ in 0..lanes_in_warp {
+                        warp_thread_idxs.push(thread_idx);
+                        thread_idx.0 = (thread_idx.0 + 1) % thread_blocks.block_dim.0;
+                        if block_idx.0 == 0 {
+                            thread_idx.1 = (thread_idx.1 + 1) % thread_blocks.block_dim.1;
+                            if thread_idx.1 == 0 {
+                                thread_idx.2 = (thread_idx.2 + 1) % thread_blocks.block_dim.2;
+                            }
+                        }
+                    }
+                    thread_idxs.push(warp_thread_idxs);
+                }
+                info!(
+                    self.logger,
+                    "GLUL: Spawning block_idx {:?}, warps {:?}, to core {:?}", block_idx, thread_idxs, core_idx
+                );
+                let core = self.cores.get_mut(core_idx).expect("Core index out of bounds");
+                GLULCore::reset(&mut core.0);
+                core.0.spawn_n_warps(thread_blocks.pc, block_idx, thread_idxs, thread_blocks.bp);
+                core.1 = true;
+            });
+        });
+    }
+
+    /// Add threadblocks of `engine_idx` to the GLUL. While it runs threadblocks of the same kernel, they
+    /// start in the slots earlier threadblocks freed.
     pub fn submit_thread_block(&mut self, thread_blocks: ThreadBlocks, engine_idx: usize) {
         info!(
             self.logger,
             "Submitting {:?} to {:?}", thread_blocks, self.status.config
         );
-        self.thread_blocks = Some(thread_blocks);
-        self.engine_idx = engine_idx;
-        self.state = GLULState::S1;
+        *self.status.tbs.write().expect("GLUL tbs poisoned") += thread_blocks.block_idxs.len() as u32;
+        match self.thread_blocks.as_mut() {
+            Some(pending) => {
+                assert_eq!(self.engine_idx, engine_idx, "GLUL {}: threadblocks of another engine", self.id);
+                pending.block_idxs.extend(thread_blocks.block_idxs);
+            }
+            None => {
+                let threads_per_tb = thread_blocks.block_dim.0 * thread_blocks.block_dim.1 * thread_blocks.block_dim.2;
+                let warps_per_tb = (threads_per_tb / self.status.config.num_lanes as u32).max(1);
+                self.cores_per_tb = (warps_per_tb as f32 / self.status.config.num_warps as f32).ceil() as usize;
+                self.slots = vec![None; self.status.config.num_cores / self.cores_per_tb];
+                self.neutrino.reset();
+                self.thread_blocks = Some(thread_blocks);
+                self.engine_idx = engine_idx;
+            }
+        }
+        if self.state == GLULState::S0 {
+            self.state = GLULState::S1;
+        }
     }
 
-    pub fn try_acknowledge_done_err(&mut self) -> Option<Result<(usize, u32), (usize, ExecErr)>> {
-        if self.done {
+    /// Block indices of the threadblocks completed since the last call, or the error that stopped the
+    /// GLUL, with the engine they belong to.
+    pub fn try_acknowledge_done_err(&mut self) -> Option<GLULAck> {
+        if !self.done_tbs.is_empty() {
+            Some(Ok((self.engine_idx, std::mem::take(&mut self.done_tbs))))
+        } else if self.done {
             self.done = false;
-            let n_tb = self.thread_blocks.as_ref().expect("Thread blocks not set").block_idxs.len() as u32;
-            self.thread_blocks = None;
-            Some(
-                self.err
-                    .clone()
-                    .map(|()| (self.engine_idx, n_tb))
-                    .map_err(|e| (self.engine_idx, e)),
-            )
+            let err = std::mem::replace(&mut self.err, Ok(())).expect_err("GLUL: stopped without an error");
+            Some(Err((self.engine_idx, err)))
         } else {
             None
         }
     }
 
-    pub fn try_kill(&mut self, engine_idx: usize) {
-        if self.state != GLULState::S0 && self.engine_idx == engine_idx {
+    /// No threadblocks are running, waiting to start or waiting to be acknowledged.
+    pub fn idle(&self) -> bool {
+        self.state == GLULState::S0 && !self.done && self.done_tbs.is_empty()
+    }
+
+    /// Stop the thread blocks of `engine_idx`, if this GLUL is running them. Returns whether it was.
+    pub fn try_kill(&mut self, engine_idx: usize) -> bool {
+        if !self.idle() && self.engine_idx == engine_idx {
+            info!(self.logger, "GLUL {}: killed thread blocks of engine {}", self.id, engine_idx);
             self.done = false;
+            self.done_tbs.clear();
             self.state = GLULState::S0;
-            *self.status.busy.write().expect("GLUL busy poisoned") = false;
+            self.stop();
+            true
+        } else {
+            false
         }
     }
 
+    /// Drop every running and waiting threadblock.
+    fn stop(&mut self) {
+        self.thread_blocks = None;
+        self.slots.iter_mut().for_each(|slot| *slot = None);
+        self.cores.iter_mut().for_each(|(core, scheduled)| {
+            GLULCore::reset(core);
+            *scheduled = false;
+        });
+        self.neutrino.reset();
+        *self.status.tbs.write().expect("GLUL tbs poisoned") = 0;
+    }
+
     pub fn get_status(&self) -> &GLULStatus {
         &self.status
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Core whose warps retire `pc - 2 * block_idx.0` cycles after they are spawned.
+    #[derive(Default)]
+    struct TestCore {
+        left: Option<u32>,
+    }
+
+    impl GLULCore for TestCore {
+        fn reset(&mut self) {
+            self.left = None;
+        }
+
+        fn spawn_n_warps(&mut self, pc: u32, block_idx: BlockIdx, _: Vec<Vec<(u32, u32, u32)>>, _: u32) {
+            self.left = Some(pc - 2 * block_idx.0);
+        }
+
+        fn tick(&mut self, _: &mut Neutrino) -> Result<(), ExecErr> {
+            if let Some(left) = self.left.as_mut() {
+                *left = left.saturating_sub(1);
+            }
+            Ok(())
+        }
+
+        fn all_warps_retired(&self) -> bool {
+            self.left.is_none_or(|left| left == 0)
+        }
+
+        fn scheduler(&mut self) -> Option<&mut Scheduler> {
+            None
+        }
+    }
+
+    /// Four cores, and one core per single-warp threadblock, so four slots.
+    fn glul() -> GLUL<TestCore> {
+        let config = GLULConfig::default();
+        let cores = (0..config.num_cores).map(|_| TestCore::default()).collect();
+        GLUL::with_cores(0, config, cores, Arc::new(Logger::new(0)))
+    }
+
+    fn thread_blocks(block_idxs: Vec<BlockIdx>) -> ThreadBlocks {
+        ThreadBlocks {
+            pc: 20,
+            block_idxs,
+            block_dim: (16, 1, 1),
+            ..ThreadBlocks::default()
+        }
+    }
+
+    fn tbs(glul: &GLUL<TestCore>) -> u32 {
+        *glul.get_status().tbs.read().unwrap()
+    }
+
+    /// Tick `glul` for `cycles` cycles and return the acknowledgements, with the cycle they were taken.
+    fn run(glul: &mut GLUL<TestCore>, cycles: u32) -> Vec<(u32, GLULAck)> {
+        (1..=cycles)
+            .filter_map(|cycle| {
+                glul.tick().unwrap();
+                glul.try_acknowledge_done_err().map(|ack| (cycle, ack))
+            })
+            .collect()
+    }
+
+    fn done(acks: Vec<(u32, GLULAck)>) -> Vec<(usize, Vec<BlockIdx>)> {
+        acks.into_iter().map(|(_, ack)| ack.unwrap()).collect()
+    }
+
+    #[test]
+    fn refills_freed_slots() {
+        let mut glul = glul();
+        glul.submit_thread_block(thread_blocks((0..6).map(|x| (x, 0, 0)).collect()), 0);
+        assert_eq!(tbs(&glul), 6);
+        let acks = run(&mut glul, 100);
+        // Blocks 0-3 start right away and the shortest finishes first; 4 and 5 start in the slots 3 and 2
+        // free and finish together.
+        let cycles = acks.iter().map(|(cycle, _)| *cycle).collect::<Vec<_>>();
+        assert_eq!(cycles, [15, 17, 19, 21, 27]);
+        let blocks = done(acks).into_iter().map(|(_, blocks)| blocks).collect::<Vec<_>>();
+        assert_eq!(blocks, [vec![(3, 0, 0)], vec![(2, 0, 0)], vec![(1, 0, 0)], vec![(0, 0, 0)], vec![(5, 0, 0), (4, 0, 0)]]);
+        assert_eq!(tbs(&glul), 0);
+        assert!(glul.idle());
+    }
+
+    #[test]
+    fn acks_carry_tb_ids() {
+        let mut glul = glul();
+        glul.submit_thread_block(thread_blocks(vec![(1, 4, 0), (3, 0, 2)]), 3);
+        let acks = run(&mut glul, 5);
+        assert!(acks.is_empty());
+        // A refill of the same kernel while the first batch runs.
+        glul.submit_thread_block(thread_blocks(vec![(7, 7, 7)]), 3);
+        let acks = done(run(&mut glul, 100));
+        assert_eq!(acks, [(3, vec![(7, 7, 7)]), (3, vec![(3, 0, 2)]), (3, vec![(1, 4, 0)])]);
+    }
+
+    #[test]
+    fn kill_drops_pending_tbs() {
+        let mut glul = glul();
+        glul.submit_thread_block(thread_blocks((0..6).map(|x| (x, 0, 0)).collect()), 1);
+        assert!(run(&mut glul, 5).is_empty());
+        assert!(!glul.try_kill(0));
+        assert!(glul.try_kill(1));
+        assert_eq!(tbs(&glul), 0);
+        assert!(glul.idle());
+        assert!(glul.cores.iter().all(|(_, scheduled)| !scheduled));
+        // Neither the running nor the waiting blocks complete later.
+        assert!(run(&mut glul, 100).is_empty());
+
+        glul.submit_thread_block(thread_blocks(vec![(9, 0, 0)]), 2);
+        assert_eq!(done(run(&mut glul, 100)), [(2, vec![(9, 0, 0)])]);
+    }
+}
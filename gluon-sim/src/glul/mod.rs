@@ -1 +1,3 @@
+pub mod barrier_unit;
+#[allow(clippy::module_inception)]
 pub mod glul;
@@ -1,4 +1,9 @@
 pub mod common;
+pub mod config;
+pub mod context;
 pub mod glug;
 pub mod glul;
+pub mod ring;
+pub mod shared_memory;
 pub mod top;
+pub mod trace;
@@ -5,100 +5,82 @@ use std::io;
 use std::io::ErrorKind;
 use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
 use std::path::Path;
+use std::pin::pin;
+use std::process::ExitCode;
 use std::sync::Arc;
 
 use gluon::common::base::Configurable;
-use gluon::common::base::{Clocked, Command};
-use serde::Deserialize;
+use gluon::common::base::{Clocked, SimErr};
+use gluon::common::protocol::{GluonEvent, COMMAND_BYTES, HANDSHAKE_BYTES, PROTOCOL_VERSION};
+use gluon::config::load_config;
+use gluon::context::Context;
+use gluon::ring::Rings;
+use gluon::shared_memory::{HostMemory, SharedMemoryRegion};
+use gluon::top::{Top, TopConfig};
+use gluon::trace::{SharedTrace, TraceWriter};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::unix::OwnedReadHalf;
 use tokio::net::unix::OwnedWriteHalf;
 use tokio::net::{unix::SocketAddr, UnixListener, UnixStream};
-use tokio::sync::Mutex;
-use gluon::glug::decode_dispatch::DecodeDispatchConfig;
-use gluon::glug::engine::EngineConfig;
-use gluon::glug::engines::cs_engine::CSEngineConfig;
-use gluon::glug::engines::kernel_engine::KernelEngineConfig;
-use gluon::glug::engines::mem_engine::MemEngineConfig;
-use gluon::glug::frontend::FrontendConfig;
-use gluon::glug::glug::GLUGConfig;
-use gluon::top::{SimConfig, Top, TopConfig};
-
-mod shared_memory;
-use shared_memory::SharedMemoryRegion;
+use tokio::sync::{Mutex, Notify, Semaphore};
+use tokio::task::JoinSet;
 
 const DEFAULT_CONFIG_PATH: &str = "config.toml";
 
-#[derive(Deserialize)]
-struct Config {
-    server: ServerConfig,
-    #[serde(default)]
-    sim: SimConfig,
-    #[serde(default)]
-    glug: GLUGConfig,
-    #[serde(default)]
-    frontend: FrontendConfig,
-    #[serde(default, rename = "decode_dispatch")]
-    decode_dispatch: DecodeDispatchConfig,
-    #[serde(default)]
-    engine: EngineConfig,
-    #[serde(default, rename = "kernel_engine")]
-    kernel_engine: KernelEngineConfig,
-    #[serde(default, rename = "mem_engine")]
-    mem_engine: MemEngineConfig,
-    #[serde(default, rename = "cs_engine")]
-    cs_engine: CSEngineConfig,
+/// The simulator shared by all sessions, with the wakeups that let every task sleep while it has
+/// nothing to do.
+struct Device {
+    top: Mutex<Top>,
+    /// Wakes the simulator loop: a command was submitted or a stream was freed.
+    work: Notify,
+    /// Wakes completion writers: events or credits were produced, or the simulator stopped.
+    events: Notify,
+    /// Wakes readers holding a command: the frontend latch was drained, or the simulator stopped.
+    space: Notify,
+    /// Handed to every simulator instance so the trace covers the whole server run.
+    trace: Option<SharedTrace>,
 }
 
-#[derive(Deserialize)]
-struct ServerConfig {
-    socket_path: String,
+/// Contexts not currently owned by a client. A permit is held for as long as a client owns a context.
+struct ContextPool {
+    free: Mutex<Vec<Context>>,
+    permits: Semaphore,
+    total: usize,
 }
 
-impl Config {
-    fn into_server_and_top(self) -> (ServerConfig, TopConfig) {
-        let mut glug_config = self.glug;
-        let mut engine_config = self.engine;
-        engine_config.kernel_engine_config = self.kernel_engine;
-        engine_config.mem_engine_config = self.mem_engine;
-        engine_config.cs_engine_config = self.cs_engine;
-
-        glug_config.frontend = self.frontend;
-        glug_config.decode_dispatch = self.decode_dispatch;
-        glug_config.engine = engine_config;
-
-        (
-            self.server,
-            TopConfig {
-                sim: self.sim,
-                glug: glug_config,
-            },
-        )
-    }
+/// What the client sends alongside its memfd.
+struct Handshake {
+    base: usize,
+    /// Protocol version the client was built against.
+    version: u32,
+    /// Entries of the command and completion rings at the start of the region; 0 keeps headers and
+    /// completions on the socket.
+    ring_entries: u32,
 }
 
-fn load_config(path: &str) -> Result<Config, Box<dyn Error>> {
-    let contents = fs::read_to_string(path)?;
-    let config = toml::from_str(&contents)?;
-    Ok(config)
-}
-
-async fn receive_shared_memory_region(stream: &UnixStream) -> io::Result<SharedMemoryRegion> {
+async fn receive_shared_memory_region(
+    stream: &UnixStream,
+) -> io::Result<(SharedMemoryRegion, Handshake)> {
     loop {
         stream.readable().await?;
         match recv_memfd(stream.as_raw_fd()) {
-            Ok((fd, base)) => return SharedMemoryRegion::from_owned_fd(fd, base),
+            Ok((fd, handshake)) => {
+                return Ok((
+                    SharedMemoryRegion::from_owned_fd(fd, handshake.base)?,
+                    handshake,
+                ))
+            }
             Err(err) if err.kind() == ErrorKind::WouldBlock => continue,
             Err(err) => return Err(err),
         }
     }
 }
 
-fn recv_memfd(socket_fd: RawFd) -> io::Result<(OwnedFd, usize)> {
+fn recv_memfd(socket_fd: RawFd) -> io::Result<(OwnedFd, Handshake)> {
     const CMSG_BUFFER_LEN: usize =
         unsafe { libc::CMSG_SPACE(std::mem::size_of::<RawFd>() as u32) as usize };
 
-    let mut data_buf = [0u8; std::mem::size_of::<u64>()];
+    let mut data_buf = [0u8; HANDSHAKE_BYTES];
     let mut cmsg_buffer = [0u8; CMSG_BUFFER_LEN];
 
     loop {
@@ -132,7 +114,7 @@ fn recv_memfd(socket_fd: RawFd) -> io::Result<(OwnedFd, usize)> {
         if received as usize != data_buf.len() {
             return Err(io::Error::new(
                 ErrorKind::InvalidData,
-                "shared memory base missing",
+                "shared memory handshake truncated",
             ));
         }
 
@@ -144,9 +126,22 @@ fn recv_memfd(socket_fd: RawFd) -> io::Result<(OwnedFd, usize)> {
                 if !data.is_null() {
                     let fd = unsafe { *data };
                     let mut base_bytes = [0u8; std::mem::size_of::<u64>()];
-                    base_bytes.copy_from_slice(&data_buf);
+                    base_bytes.copy_from_slice(&data_buf[0..8]);
                     let base = u64::from_le_bytes(base_bytes) as usize;
-                    return Ok((unsafe { OwnedFd::from_raw_fd(fd) }, base));
+                    let mut version_bytes = [0u8; std::mem::size_of::<u32>()];
+                    version_bytes.copy_from_slice(&data_buf[8..12]);
+                    let version = u32::from_le_bytes(version_bytes);
+                    let mut ring_bytes = [0u8; std::mem::size_of::<u32>()];
+                    ring_bytes.copy_from_slice(&data_buf[12..16]);
+                    let ring_entries = u32::from_le_bytes(ring_bytes);
+                    return Ok((
+                        unsafe { OwnedFd::from_raw_fd(fd) },
+                        Handshake {
+                            base,
+                            version,
+                            ring_entries,
+                        },
+                    ));
                 }
             }
 
@@ -160,16 +155,46 @@ fn recv_memfd(socket_fd: RawFd) -> io::Result<(OwnedFd, usize)> {
     }
 }
 
-async fn enqueue_command(mut stream: OwnedReadHalf, addr: SocketAddr, top: Arc<Mutex<Top>>) -> tokio::io::Result<()> {
-    let mut buffer = [0_u8; 16];
+async fn enqueue_command(
+    mut stream: OwnedReadHalf,
+    addr: SocketAddr,
+    context: Context,
+    device: Arc<Device>,
+) -> tokio::io::Result<()> {
+    let mut buffer = [0_u8; COMMAND_BYTES];
     loop {
         match stream.read_exact(&mut buffer).await {
             Ok(_) => {
-                let command = Command::from_bytes(buffer);
-                {
-                    let mut top_guard = top.lock().await;
-                    top_guard.submit_command(command);
-                    top_guard.tick().unwrap();
+                let command = match context.admit(&buffer) {
+                    Ok(command) => command,
+                    Err(event) => {
+                        eprintln!("Rejected command {buffer:?} from {addr:?}: {event:?}");
+                        device.top.lock().await.reject_command(event);
+                        device.events.notify_waiters();
+                        continue;
+                    }
+                };
+                // Hold the command until the frontend latch is free; the host's credits bound how many
+                // commands can pile up behind it in the socket. A stopped simulator completes it with its error.
+                loop {
+                    let mut space = pin!(device.space.notified());
+                    space.as_mut().enable();
+                    {
+                        let mut top = device.top.lock().await;
+                        if let Some(err) = top.failed() {
+                            top.reject_command(GluonEvent::SIM {
+                                sid: command.sid(),
+                                err,
+                            });
+                            device.events.notify_waiters();
+                            break;
+                        }
+                        if top.submit_command(command) {
+                            device.work.notify_one();
+                            break;
+                        }
+                    }
+                    space.await;
                 }
             }
             Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
@@ -178,40 +203,397 @@ async fn enqueue_command(mut stream: OwnedReadHalf, addr: SocketAddr, top: Arc<M
             }
             Err(err) => return Err(err),
         }
-        tokio::task::yield_now().await;
     }
 }
 
-async fn tick_sim(top: Arc<Mutex<Top>>) -> tokio::io::Result<()> {
+/// Drive the simulator until it fails; returns the error it stopped with. Sleeps whenever the simulator
+/// is quiescent and wakes the session tasks waiting on what a tick produced.
+async fn tick_sim(device: Arc<Device>) -> SimErr {
+    loop {
+        let quiescent = {
+            let mut top = device.top.lock().await;
+            let events_produced = top.events_produced();
+            let latched = !top.can_submit();
+            let result = top.tick();
+            if result.is_err() || top.events_produced() != events_produced {
+                device.events.notify_waiters();
+            }
+            if result.is_err() || (latched && top.can_submit()) {
+                device.space.notify_waiters();
+            }
+            if let Err(err) = result {
+                return err;
+            }
+            top.quiescent()
+        };
+        if quiescent {
+            device.work.notified().await;
+        }
+    }
+}
+
+async fn dequeue_completion(
+    mut stream: OwnedWriteHalf,
+    context: Context,
+    device: Arc<Device>,
+) -> tokio::io::Result<()> {
     loop {
-        let mut top_guard = top.lock().await;
-        top_guard.tick().unwrap();
+        let mut events = pin!(device.events.notified());
+        events.as_mut().enable();
+        let (credits, event, fatal) = {
+            let mut top = device.top.lock().await;
+            let credits = top.take_credits(context.sids.clone());
+            let event = top.get_completion(context.sids.clone());
+            // Once the simulator has failed, every outstanding command has an event queued, so an empty
+            // queue means the client has been told about all of them.
+            let fatal = top
+                .failed()
+                .filter(|_| event.is_none())
+                .map(|err| GluonEvent::FATAL {
+                    err,
+                    cycles: top.cycles_elapsed(),
+                });
+            (credits, event, fatal)
+        };
+        if credits > 0 {
+            stream
+                .write_all(&GluonEvent::CREDIT { credits }.encode())
+                .await?;
+        }
+        if let Some(fatal) = fatal {
+            stream.write_all(&fatal.encode()).await?;
+            println!("Sent shutdown: {:?}", fatal);
+            return Ok(());
+        }
+        match event {
+            Some(mut event) => {
+                // Collecting a completion frees its stream for the next command.
+                device.work.notify_one();
+                if let Some(sid) = event.sid() {
+                    event.set_sid(context.to_local_sid(sid));
+                }
+                stream.write_all(&event.encode()).await?;
+                println!("Sent completion: {:?}", event);
+            }
+            None => events.await,
+        }
+    }
+}
+
+/// Ring transport intake: every byte from the client is a doorbell for its command ring.
+async fn ring_doorbells(
+    mut stream: OwnedReadHalf,
+    addr: SocketAddr,
+    context: Context,
+    device: Arc<Device>,
+) -> tokio::io::Result<()> {
+    let mut buffer = [0_u8; 64];
+    loop {
+        if stream.read(&mut buffer).await? == 0 {
+            println!("Client closed connection: {addr:?}");
+            return Ok(());
+        }
+        device.top.lock().await.ring_doorbell(context.id);
+        device.work.notify_one();
+        // The host also rings after draining the completion ring, which may unblock the writer.
+        device.events.notify_waiters();
+    }
+}
+
+/// Ring transport delivery: move the context's events onto its completion ring, then ring the client's
+/// doorbell with a single byte. The command ring bounds what the host can have outstanding, so credits
+/// are dropped.
+async fn post_completions(
+    mut stream: OwnedWriteHalf,
+    context: Context,
+    rings: Arc<Rings>,
+    device: Arc<Device>,
+) -> tokio::io::Result<()> {
+    loop {
+        let mut events = pin!(device.events.notified());
+        events.as_mut().enable();
+        let (posted, fatal) = {
+            let mut top = device.top.lock().await;
+            top.take_credits(context.sids.clone());
+            let mut posted = 0;
+            let mut drained = false;
+            while rings.event_space() > 0 {
+                let Some(mut event) = top.get_completion(context.sids.clone()) else {
+                    drained = true;
+                    break;
+                };
+                if let Some(sid) = event.sid() {
+                    event.set_sid(context.to_local_sid(sid));
+                }
+                rings.push_event(&event);
+                println!("Posted completion: {:?}", event);
+                posted += 1;
+            }
+            if posted > 0 {
+                device.work.notify_one();
+            }
+            // As on the socket, FATAL follows the last outstanding completion.
+            let fatal = top
+                .failed()
+                .filter(|_| drained && rings.event_space() > 0)
+                .map(|err| GluonEvent::FATAL {
+                    err,
+                    cycles: top.cycles_elapsed(),
+                });
+            if let Some(fatal) = &fatal {
+                rings.push_event(fatal);
+                println!("Posted shutdown: {:?}", fatal);
+            }
+            (posted, fatal.is_some())
+        };
+        if posted > 0 || fatal {
+            stream.write_all(&[1]).await?;
+        }
+        if fatal {
+            return Ok(());
+        }
+        events.await;
+    }
+}
+
+/// Run one client session to completion: handshake, then command intake and completion delivery on
+/// the client's context until the client disconnects. Returns once both session tasks have stopped.
+async fn serve_client(
+    mut stream: UnixStream,
+    addr: SocketAddr,
+    context: &Context,
+    device: Arc<Device>,
+) -> Result<(), Box<dyn Error>> {
+    let (shared_memory, handshake) = receive_shared_memory_region(&stream).await?;
+    if handshake.version != PROTOCOL_VERSION {
+        let reject = GluonEvent::REJECT { version: PROTOCOL_VERSION };
+        stream.write_all(&reject.encode()).await?;
+        return Err(format!(
+            "client speaks protocol v{}, server speaks v{PROTOCOL_VERSION}",
+            handshake.version
+        )
+        .into());
     }
+    println!("Shared memory region: {:?}", shared_memory);
+    let shared_memory = Arc::new(shared_memory);
+    device.top.lock().await.set_host_memory(
+        context.sids.clone(),
+        Some(Arc::clone(&shared_memory) as Arc<dyn HostMemory>),
+    );
+
+    let (read_half, mut write_half) = stream.into_split();
+
+    let credits = device.top.lock().await.initial_credits();
+    let hello = GluonEvent::HELLO {
+        version: PROTOCOL_VERSION,
+        credits,
+        streams: context.streams(),
+        dram_base: context.dram.start,
+        dram_size: context.dram.end - context.dram.start,
+    };
+    write_half.write_all(&hello.encode()).await?;
+
+    let (mut h1, mut h2) = if handshake.ring_entries == 0 {
+        (
+            tokio::task::spawn(enqueue_command(
+                read_half,
+                addr,
+                context.clone(),
+                Arc::clone(&device),
+            )),
+            tokio::task::spawn(dequeue_completion(
+                write_half,
+                context.clone(),
+                Arc::clone(&device),
+            )),
+        )
+    } else {
+        let rings = Arc::new(Rings::new(shared_memory, handshake.ring_entries)?);
+        println!(
+            "Client {addr:?} uses {}-entry rings",
+            handshake.ring_entries
+        );
+        device
+            .top
+            .lock()
+            .await
+            .attach_ring(context.clone(), Arc::clone(&rings));
+        (
+            tokio::task::spawn(ring_doorbells(
+                read_half,
+                addr,
+                context.clone(),
+                Arc::clone(&device),
+            )),
+            tokio::task::spawn(post_completions(
+                write_half,
+                context.clone(),
+                rings,
+                Arc::clone(&device),
+            )),
+        )
+    };
+
+    // The reader ends on disconnect and the writer ends on a broken pipe; either one ends the session.
+    // Only the task still running is stopped and awaited; a finished JoinHandle must not be polled again.
+    let result = tokio::select! {
+        result = &mut h1 => {
+            h2.abort();
+            let _ = h2.await;
+            result
+        }
+        result = &mut h2 => {
+            h1.abort();
+            let _ = h1.await;
+            result
+        }
+    };
+
+    result?.map_err(|err| err.into())
 }
 
-async fn dequeue_completion(mut stream: OwnedWriteHalf, top: Arc<Mutex<Top>>) -> tokio::io::Result<()> {
+/// Detach the client's host memory and let the commands it left behind run out, discarding their events
+/// and credits, so the next client on these streams starts clean.
+async fn release_context(context: &Context, device: &Device) {
+    {
+        let mut top = device.top.lock().await;
+        top.detach_ring(context.id);
+        top.set_host_memory(context.sids.clone(), None);
+    }
     loop {
-        if let Some(event) = {
-            let mut top_guard = top.lock().await;
-            top_guard.get_completion()
-        } {
-            stream.write_all(event.bytes.as_slice()).await?;
-            println!("Sent completion: {:?}", event);
+        let mut events = pin!(device.events.notified());
+        events.as_mut().enable();
+        {
+            let mut top = device.top.lock().await;
+            while top.get_completion(context.sids.clone()).is_some() {
+                device.work.notify_one();
+            }
+            top.take_credits(context.sids.clone());
+            if top.streams_idle(context.sids.clone()) {
+                return;
+            }
         }
-        tokio::task::yield_now().await;
+        events.await;
+    }
+}
+
+/// Wait for a free context, serve the client on it, then hand the context back. The simulator is reset
+/// once the last client is gone.
+async fn run_session(
+    stream: UnixStream,
+    addr: SocketAddr,
+    pool: Arc<ContextPool>,
+    device: Arc<Device>,
+    top_config: Arc<TopConfig>,
+) {
+    // The pool is closed when the simulator fails; clients still waiting for a context are dropped.
+    let Ok(_permit) = pool.permits.acquire().await else {
+        return;
+    };
+    let context = pool
+        .free
+        .lock()
+        .await
+        .pop()
+        .expect("Server: permit held without a free context");
+    println!(
+        "Client {addr:?} assigned context {}: streams {:?}, DRAM {:#x?}",
+        context.id, context.sids, context.dram
+    );
+
+    if let Err(err) = serve_client(stream, addr.clone(), &context, Arc::clone(&device)).await {
+        eprintln!("Client {addr:?} failed: {err}");
+    }
+    release_context(&context, &device).await;
+
+    let mut free = pool.free.lock().await;
+    println!("Context {} released", context.id);
+    free.push(context);
+    let mut top = device.top.lock().await;
+    if free.len() == pool.total && top.failed().is_none() {
+        print_engine_stats(&top);
+        print_kernel_placements(&top);
+        *top = Top::new(&top_config);
+        top.set_trace(device.trace.clone());
+        println!("Simulator reset, waiting for next client");
+    }
+}
+
+fn print_engine_stats(top: &Top) {
+    for (idx, stats) in top.engine_stats().iter().enumerate() {
+        println!(
+            "Engine {idx}: {} mem grants waited {} cycles, {} DMA grants waited {} cycles",
+            stats.mem.grants, stats.mem.wait_cycles, stats.dma.grants, stats.dma.wait_cycles
+        );
+    }
+}
+
+fn print_kernel_placements(top: &Top) {
+    for placement in top.kernel_placements() {
+        let gluls = placement
+            .tbs
+            .iter()
+            .enumerate()
+            .filter(|(_, tbs)| **tbs > 0)
+            .map(|(glul, tbs)| format!("{tbs} on GLUL {glul}"))
+            .collect::<Vec<_>>();
+        println!(
+            "Engine {}: last kernel (sid {}): thread blocks {}",
+            placement.engine,
+            placement.sid,
+            if gluls.is_empty() {
+                "none".to_string()
+            } else {
+                gluls.join(", ")
+            }
+        );
+    }
+}
+
+/// Exit status for a simulator failure; 1 is left to server errors.
+fn exit_code(err: SimErr) -> ExitCode {
+    match err {
+        SimErr::TIMEOUT => ExitCode::from(2),
+        SimErr::EXECUTION => ExitCode::from(3),
     }
 }
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
+async fn main() -> Result<ExitCode, Box<dyn Error>> {
     let config_path = env::args()
         .nth(1)
         .unwrap_or_else(|| DEFAULT_CONFIG_PATH.to_string());
-    let config = load_config(&config_path)?;
+    let (config, config_text) = load_config(&config_path)?;
     let (server_config, top_config) = config.into_server_and_top();
-    let socket_path = server_config.socket_path;
 
-    let top = Arc::new(Mutex::new(Top::new(&top_config)));
+    let trace = match &server_config.trace_path {
+        Some(path) => {
+            println!("Recording trace to {path}");
+            Some(Arc::new(std::sync::Mutex::new(TraceWriter::create(
+                Path::new(path),
+                &config_text,
+            )?)))
+        }
+        None => None,
+    };
+    let mut top = Top::new(&top_config);
+    top.set_trace(trace.clone());
+    let contexts = server_config.contexts(top.num_streams())?;
+    let device = Arc::new(Device {
+        top: Mutex::new(top),
+        work: Notify::new(),
+        events: Notify::new(),
+        space: Notify::new(),
+        trace,
+    });
+    let top_config = Arc::new(top_config);
+    let pool = Arc::new(ContextPool {
+        permits: Semaphore::new(contexts.len()),
+        total: contexts.len(),
+        // Hand out context 0 first.
+        free: Mutex::new(contexts.into_iter().rev().collect()),
+    });
+    let socket_path = server_config.socket_path;
 
     if Path::new(&socket_path).exists() {
         fs::remove_file(&socket_path)?;
@@ -223,36 +605,70 @@ async fn main() -> Result<(), Box<dyn Error>> {
             eprintln!(
                 "Permission denied while binding Unix socket at {socket_path}; skipping server startup."
             );
-            return Ok(());
+            return Ok(ExitCode::SUCCESS);
         }
         Err(err) => return Err(err.into()),
     };
-    println!("Server listening on {socket_path}");
-
-    match listener.accept().await {
-        Ok((stream, addr)) => {
-            println!("Client connected: {addr:?}");
-
-            let shared_memory = receive_shared_memory_region(&stream).await?;
-            println!("Shared memory region: {:?}", shared_memory);
-
-            let (read_half, write_half) = stream.into_split();
-
-            env_logger::init();
+    println!(
+        "Server listening on {socket_path} for up to {} clients",
+        pool.total
+    );
+
+    env_logger::init();
+
+    let mut ticker = tokio::task::spawn(tick_sim(Arc::clone(&device)));
+    let mut sessions = JoinSet::new();
+
+    // Each client gets its own context; clients beyond max_clients wait for one to be released. Serving
+    // stops when the simulator fails or the listener breaks.
+    let failure = loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, addr)) => {
+                    println!("Client connected: {addr:?}");
+                    sessions.spawn(run_session(
+                        stream,
+                        addr,
+                        Arc::clone(&pool),
+                        Arc::clone(&device),
+                        Arc::clone(&top_config),
+                    ));
+                }
+                Err(err) => {
+                    eprintln!("Failed to accept connection: {err}");
+                    break None;
+                }
+            },
+            result = &mut ticker => break Some(result?),
+            Some(_) = sessions.join_next() => {}
+        }
+    };
 
-            let h1 = tokio::task::spawn(enqueue_command(read_half, addr, Arc::clone(&top)));
-            let h2 = tokio::task::spawn(tick_sim(Arc::clone(&top)));
-            let h3 = tokio::task::spawn(dequeue_completion(write_half, Arc::clone(&top)));
-            let _ = tokio::join!(h1, h2, h3);
+    let status = match failure {
+        Some(err) => {
+            {
+                let top = device.top.lock().await;
+                eprintln!(
+                    "Simulator failed after {} cycles: {err:?}",
+                    top.cycles_elapsed()
+                );
+                print_engine_stats(&top);
+                print_kernel_placements(&top);
+            }
+            // Connected clients get their outstanding completions and a FATAL message, then hang up.
+            pool.permits.close();
+            while sessions.join_next().await.is_some() {}
+            exit_code(err)
         }
-        Err(err) => {
-            eprintln!("Failed to accept connection: {err}");
+        None => {
+            ticker.abort();
+            ExitCode::FAILURE
         }
-    }
+    };
 
     if Path::new(&socket_path).exists() {
         let _ = fs::remove_file(&socket_path);
     }
 
-    Ok(())
+    Ok(status)
 }
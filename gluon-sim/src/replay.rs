@@ -0,0 +1,361 @@
+use std::collections::VecDeque;
+use std::env;
+use std::error::Error;
+use std::path::Path;
+use std::process::ExitCode;
+use std::sync::{Arc, Mutex};
+
+use gluon::common::base::{Clocked, Configurable, MemErr, MemErrCode};
+use gluon::common::protocol::GluonEvent;
+use gluon::config::Config;
+use gluon::shared_memory::HostMemory;
+use gluon::top::{Top, TopConfig};
+use gluon::trace::{TraceReader, TraceRecord};
+
+/// Host memory of one stream during replay: serves the bytes DMA read in the recorded run, in order.
+/// Writes are dropped; what the device sent back is checked through the responses.
+struct TraceHost {
+    reads: Mutex<VecDeque<(u64, Vec<u8>)>>,
+}
+
+impl HostMemory for TraceHost {
+    fn read(&self, host_addr: u64, len: usize) -> Result<Vec<u8>, MemErr> {
+        let mut reads = self.reads.lock().expect("trace host poisoned");
+        match reads.front() {
+            Some((addr, data)) if *addr == host_addr && data.len() == len => {
+                Ok(reads.pop_front().expect("Replay: unreachable").1)
+            }
+            // The recorded run failed this read, or the replay diverged; either way the device sees an error.
+            _ => Err(MemErr {
+                code: MemErrCode::HOST,
+                addr: host_addr,
+            }),
+        }
+    }
+
+    fn write(&self, _host_addr: u64, _data: &[u8]) -> Result<(), MemErr> {
+        Ok(())
+    }
+}
+
+/// The records between two `RESET`s: one simulator instance.
+#[derive(Default)]
+struct Segment {
+    records: Vec<(u64, TraceRecord)>,
+}
+
+#[derive(Default)]
+struct Summary {
+    commands: usize,
+    events: usize,
+    cycles: u64,
+    mismatches: usize,
+}
+
+fn load_trace(path: &Path) -> Result<(TopConfig, Vec<Segment>), Box<dyn Error>> {
+    let mut config = None;
+    let mut segments: Vec<Segment> = Vec::new();
+    for entry in TraceReader::open(path)? {
+        let (cycle, record) = entry?;
+        match record {
+            TraceRecord::CONFIG(text) => config = Some(Config::parse(&text)?.into_server_and_top().1),
+            TraceRecord::RESET => segments.push(Segment::default()),
+            record => segments
+                .last_mut()
+                .ok_or("trace record before the first RESET")?
+                .records
+                .push((cycle, record)),
+        }
+    }
+    Ok((config.ok_or("trace has no configuration")?, segments))
+}
+
+/// Responses the recorded run delivered, per stream, and the rejections whose responses are not compared.
+struct Responses {
+    expected: Vec<VecDeque<(u64, GluonEvent)>>,
+    rejected: Vec<Vec<GluonEvent>>,
+}
+
+impl Responses {
+    fn new(streams: u8) -> Self {
+        Responses {
+            expected: vec![VecDeque::new(); streams as usize],
+            rejected: vec![Vec::new(); streams as usize],
+        }
+    }
+
+    fn reject(&mut self, event: GluonEvent) {
+        let sid = event.sid().expect("Replay: rejection without sid");
+        self.rejected[sid as usize].push(event);
+    }
+
+    /// Consume the response to an earlier rejection, if `event` is one.
+    fn take_rejection(&mut self, event: &GluonEvent) -> bool {
+        let rejected = &mut self.rejected[event.sid().expect("Replay: event without sid") as usize];
+        match rejected.iter().position(|rejection| rejection == event) {
+            Some(idx) => {
+                rejected.swap_remove(idx);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn record(&mut self, cycle: u64, event: GluonEvent) {
+        if !self.take_rejection(&event) {
+            let sid = event.sid().expect("Replay: recorded event without sid");
+            self.expected[sid as usize].push_back((cycle, event));
+        }
+    }
+
+    /// Compare a response of the replay with the recorded one, printing any difference.
+    fn check(&mut self, index: usize, cycle: u64, event: GluonEvent, summary: &mut Summary) {
+        if self.take_rejection(&event) {
+            return;
+        }
+        summary.events += 1;
+        let sid = event.sid().expect("Replay: event without sid");
+        match self.expected[sid as usize].pop_front() {
+            Some((_, recorded)) if recorded == event => {}
+            Some((recorded_cycle, recorded)) => {
+                summary.mismatches += 1;
+                println!(
+                    "Segment {index}, sid {sid}: expected {recorded:?} (cycle {recorded_cycle}), got {event:?} (cycle {cycle})"
+                );
+            }
+            None => {
+                summary.mismatches += 1;
+                println!("Segment {index}, sid {sid}: unexpected {event:?} (cycle {cycle})");
+            }
+        }
+    }
+}
+
+/// Stream of a recorded input or response, checked against the streams of the replayed simulator so a
+/// corrupt or foreign trace is reported instead of indexing out of range.
+fn stream_of(record: &TraceRecord, streams: u8) -> Result<usize, String> {
+    let sid = match record {
+        TraceRecord::COMMAND(cmd) => Some(cmd.sid()),
+        TraceRecord::HOSTREAD { sid, .. } => Some(*sid),
+        TraceRecord::REJECT(event) | TraceRecord::EVENT(event) => event.sid(),
+        TraceRecord::CONFIG(_) | TraceRecord::RESET => None,
+    };
+    match sid {
+        Some(sid) if sid < streams => Ok(sid as usize),
+        Some(sid) => Err(format!("{record:?} names stream {sid}, the configuration has {streams}")),
+        None => Err(format!("{record:?} belongs to no stream")),
+    }
+}
+
+/// Drive a fresh simulator with the segment's commands at their recorded cycles and compare its
+/// responses with the recorded ones. Responses are compared per stream: streams of different clients
+/// were drained by independent sessions, so only their order within a stream is deterministic.
+/// Rejections are re-injected but their responses are not compared, since the server reports them as
+/// soon as they happen, ahead of completions nobody has collected yet.
+fn replay(config: &TopConfig, segment: Segment, index: usize) -> Result<Summary, String> {
+    let mut top = Top::new(config);
+    let streams = top.num_streams() as u8;
+    let mut reads = vec![VecDeque::new(); streams as usize];
+    let mut recorded = Responses::new(streams);
+    let mut inputs = VecDeque::new();
+    let mut summary = Summary::default();
+
+    for (cycle, record) in segment.records {
+        stream_of(&record, streams).map_err(|err| format!("Segment {index}: {err}"))?;
+        match record {
+            TraceRecord::HOSTREAD { sid, addr, data } => reads[sid as usize].push_back((addr, data)),
+            TraceRecord::EVENT(event) => recorded.record(cycle, event),
+            TraceRecord::REJECT(event) => {
+                recorded.reject(event);
+                inputs.push_back((cycle, record));
+            }
+            record => inputs.push_back((cycle, record)),
+        }
+    }
+    for (sid, reads) in reads.into_iter().enumerate() {
+        let host: Arc<dyn HostMemory> = Arc::new(TraceHost { reads: Mutex::new(reads) });
+        top.set_host_memory(sid as u8..sid as u8 + 1, Some(host));
+    }
+
+    // Rejections are registered again as they are replayed.
+    recorded.rejected.iter_mut().for_each(Vec::clear);
+    loop {
+        // The server does not tick an idle simulator, so a command recorded later than an idle cycle
+        // arrived while the simulator was waiting for it.
+        while let Some((cycle, record)) = inputs.front() {
+            if *cycle > top.cycles_elapsed() && !top.quiescent() {
+                break;
+            }
+            match record {
+                TraceRecord::COMMAND(cmd) => {
+                    if !top.submit_command(*cmd) {
+                        break;
+                    }
+                    summary.commands += 1;
+                }
+                TraceRecord::REJECT(event) => {
+                    recorded.reject(*event);
+                    top.reject_command(*event);
+                }
+                other => unreachable!("Replay: {:?} is not an input", other),
+            }
+            inputs.pop_front();
+        }
+
+        top.take_credits(0..streams);
+        while let Some(event) = top.get_completion(0..streams) {
+            recorded.check(index, top.cycles_elapsed(), event, &mut summary);
+        }
+
+        if inputs.is_empty() && (top.failed().is_some() || top.quiescent()) {
+            break;
+        }
+        // A failure aborts every outstanding command with SIM, which is compared like any other response.
+        let _ = top.tick();
+    }
+    summary.cycles = top.cycles_elapsed();
+    if let Some(err) = top.failed() {
+        println!("Segment {index}: simulator failed after {} cycles: {err:?}", summary.cycles);
+    }
+
+    for (sid, missing) in recorded.expected.iter().enumerate() {
+        for (cycle, event) in missing {
+            summary.mismatches += 1;
+            println!("Segment {index}, sid {sid}: missing {event:?} (cycle {cycle})");
+        }
+    }
+    Ok(summary)
+}
+
+fn main() -> Result<ExitCode, Box<dyn Error>> {
+    let Some(trace_path) = env::args().nth(1) else {
+        eprintln!("usage: gluon-replay <trace>");
+        return Ok(ExitCode::FAILURE);
+    };
+    env_logger::init();
+
+    let (config, segments) = load_trace(Path::new(&trace_path))?;
+    let mut mismatches = 0;
+    // The server starts a new segment whenever its last client leaves, the final one is usually empty.
+    for (index, segment) in segments.into_iter().enumerate().filter(|(_, segment)| !segment.records.is_empty()) {
+        let summary = replay(&config, segment, index)?;
+        println!(
+            "Segment {index}: {} commands, {} events over {} cycles, {} mismatches",
+            summary.commands, summary.events, summary.cycles, summary.mismatches
+        );
+        mismatches += summary.mismatches;
+    }
+
+    if mismatches == 0 {
+        println!("Replay matched {trace_path}");
+        Ok(ExitCode::SUCCESS)
+    } else {
+        println!("Replay diverged from {trace_path}: {mismatches} mismatches");
+        Ok(ExitCode::FAILURE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use gluon::common::protocol::{CopyCommand, GluonCommand, SetCommand};
+    use gluon::trace::{SharedTrace, TraceWriter};
+
+    use super::*;
+
+    const CONFIG: &str = "[server]\nsocket_path = \"unused\"\n";
+    const HOST_BASE: u64 = 0x7f00_0000_0000;
+
+    /// Host memory backed by a vector at `HOST_BASE`.
+    struct VecHost(Mutex<Vec<u8>>);
+
+    impl HostMemory for VecHost {
+        fn read(&self, host_addr: u64, len: usize) -> Result<Vec<u8>, MemErr> {
+            let offset = (host_addr - HOST_BASE) as usize;
+            Ok(self.0.lock().unwrap()[offset..offset + len].to_vec())
+        }
+
+        fn write(&self, host_addr: u64, data: &[u8]) -> Result<(), MemErr> {
+            let offset = (host_addr - HOST_BASE) as usize;
+            self.0.lock().unwrap()[offset..offset + data.len()].copy_from_slice(data);
+            Ok(())
+        }
+    }
+
+    /// A trace file in the temp directory, removed when dropped.
+    struct TempTrace(PathBuf);
+
+    impl TempTrace {
+        fn new(name: &str) -> Self {
+            TempTrace(env::temp_dir().join(format!("gluon-replay-{}-{}.trace", name, std::process::id())))
+        }
+
+        fn writer(&self) -> SharedTrace {
+            Arc::new(Mutex::new(TraceWriter::create(&self.0, CONFIG).unwrap()))
+        }
+    }
+
+    impl Drop for TempTrace {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn top_config() -> TopConfig {
+        Config::parse(CONFIG).unwrap().into_server_and_top().1
+    }
+
+    #[test]
+    fn recorded_run_replays() {
+        let trace = TempTrace::new("run");
+        let mut top = Top::new(&top_config());
+        let streams = top.num_streams() as u8;
+        top.set_trace(Some(trace.writer()));
+        let host = Arc::new(VecHost(Mutex::new((0..=255).collect())));
+        top.set_host_memory(0..streams, Some(Arc::clone(&host) as Arc<dyn HostMemory>));
+
+        let copy = |src, dst, flags| CopyCommand { sid: 0, src, dst, len: 64, flags };
+        let mut commands = VecDeque::from([
+            GluonCommand::COPY(copy(HOST_BASE, 0x1000, 0)),
+            GluonCommand::SET(SetCommand { sid: 1, dst: 0x2000, value: 0x5a, len: 16, flags: 1 }),
+            GluonCommand::COPY(copy(0x1000, HOST_BASE + 128, 1)),
+        ]);
+        let mut events = Vec::new();
+        while !commands.is_empty() || !top.quiescent() {
+            if let Some(cmd) = commands.front() {
+                if top.submit_command(*cmd) {
+                    commands.pop_front();
+                }
+            }
+            top.tick().unwrap();
+            top.take_credits(0..streams);
+            events.extend(std::iter::from_fn(|| top.get_completion(0..streams)));
+        }
+        assert_eq!(events.len(), 3);
+        assert!(events.iter().all(|event| matches!(event, GluonEvent::OK { .. })));
+        assert_eq!(host.0.lock().unwrap()[128..192], (0..64).collect::<Vec<u8>>()[..]);
+        // Dropping the simulator closes the trace.
+        drop(top);
+
+        let (config, segments) = load_trace(&trace.0).unwrap();
+        assert_eq!(segments.len(), 1);
+        let summary = replay(&config, segments.into_iter().next().unwrap(), 0).unwrap();
+        assert_eq!((summary.commands, summary.events, summary.mismatches), (3, 3, 0));
+    }
+
+    #[test]
+    fn bad_sid_rejected() {
+        let streams = Top::new(&top_config()).num_streams() as u8;
+        let trace = TempTrace::new("sid");
+        {
+            let writer = trace.writer();
+            let mut writer = writer.lock().unwrap();
+            writer.record(TraceRecord::RESET);
+            writer.record(TraceRecord::EVENT(GluonEvent::OK { sid: streams }));
+        }
+        let (config, segments) = load_trace(&trace.0).unwrap();
+        let err = replay(&config, segments.into_iter().next().unwrap(), 0).err();
+        assert!(err.is_some_and(|err| err.contains(&format!("stream {streams}"))));
+    }
+}
@@ -0,0 +1,193 @@
+use std::io;
+use std::io::ErrorKind;
+use std::sync::Arc;
+
+use crate::common::protocol::{GluonEvent, COMMAND_BYTES, EVENT_BYTES};
+use crate::shared_memory::SharedMemoryRegion;
+
+/// Command and completion rings at the start of a client's shared memory region, the optional
+/// alternative to sending every header over the socket.
+///
+/// Layout: `cmd_head` (u32, advanced by the simulator), `cmd_tail` (host), `evt_head` (host), `evt_tail`
+/// (simulator), padding to 64 bytes, then `entries` command slots followed by `entries` event slots.
+/// Indices run freely and select slot `index % entries`, so `entries` must be a power of two. Producers
+/// fill slots before publishing the new tail.
+#[derive(Debug)]
+pub struct Rings {
+    region: Arc<SharedMemoryRegion>,
+    entries: u32,
+}
+
+const CMD_HEAD: u64 = 0x00;
+const CMD_TAIL: u64 = 0x04;
+const EVT_HEAD: u64 = 0x08;
+const EVT_TAIL: u64 = 0x0c;
+const HEADER_BYTES: u64 = 0x40;
+
+impl Rings {
+    pub fn new(region: Arc<SharedMemoryRegion>, entries: u32) -> io::Result<Self> {
+        if !entries.is_power_of_two() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("ring entries must be a power of two, got {entries}"),
+            ));
+        }
+        if Self::bytes(entries) > region.size() as u64 {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("{entries}-entry rings do not fit in the shared memory region"),
+            ));
+        }
+        Ok(Rings { region, entries })
+    }
+
+    /// Bytes of shared memory the rings occupy; payloads must live past this offset.
+    pub fn bytes(entries: u32) -> u64 {
+        HEADER_BYTES + entries as u64 * (COMMAND_BYTES + EVENT_BYTES) as u64
+    }
+
+    /// Headers the host has published but the simulator has not fetched yet.
+    pub fn pending_commands(&self) -> u32 {
+        self.load(CMD_TAIL).wrapping_sub(self.load(CMD_HEAD)).min(self.entries)
+    }
+
+    /// Take up to `max` headers off the command ring, handing their slots back to the host.
+    pub fn fetch_commands(&self, max: usize) -> Vec<[u8; COMMAND_BYTES]> {
+        let head = self.load(CMD_HEAD);
+        let count = self.pending_commands().min(max as u32);
+        let commands = (0..count)
+            .map(|idx| {
+                let slot = self.command_slot(head.wrapping_add(idx));
+                self.region
+                    .read(slot, COMMAND_BYTES)
+                    .expect("Rings: command slot outside the shared region")
+                    .try_into()
+                    .expect("impossible")
+            })
+            .collect();
+        self.store(CMD_HEAD, head.wrapping_add(count));
+        commands
+    }
+
+    pub fn event_space(&self) -> u32 {
+        let used = self.load(EVT_TAIL).wrapping_sub(self.load(EVT_HEAD));
+        self.entries.saturating_sub(used)
+    }
+
+    /// Publish `event` on the completion ring. Returns false and drops nothing if the ring is full.
+    pub fn push_event(&self, event: &GluonEvent) -> bool {
+        if self.event_space() == 0 {
+            return false;
+        }
+        let tail = self.load(EVT_TAIL);
+        self.region
+            .write(self.event_slot(tail), &event.encode())
+            .expect("Rings: event slot outside the shared region");
+        self.store(EVT_TAIL, tail.wrapping_add(1));
+        true
+    }
+
+    fn command_slot(&self, idx: u32) -> u64 {
+        self.region.base() + HEADER_BYTES + (idx % self.entries) as u64 * COMMAND_BYTES as u64
+    }
+
+    fn event_slot(&self, idx: u32) -> u64 {
+        self.region.base()
+            + HEADER_BYTES
+            + self.entries as u64 * COMMAND_BYTES as u64
+            + (idx % self.entries) as u64 * EVENT_BYTES as u64
+    }
+
+    fn load(&self, field: u64) -> u32 {
+        self.region
+            .load_u32(self.region.base() + field)
+            .expect("Rings: header outside the shared region")
+    }
+
+    fn store(&self, field: u64, value: u32) {
+        self.region
+            .store_u32(self.region.base() + field, value)
+            .expect("Rings: header outside the shared region");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::fd::{FromRawFd, OwnedFd};
+
+    use super::*;
+
+    const BASE: u64 = 0x1000_0000;
+    const ENTRIES: u32 = 4;
+
+    fn rings() -> (Rings, Arc<SharedMemoryRegion>) {
+        let size = Rings::bytes(ENTRIES) as usize;
+        // SAFETY: plain syscalls on a fresh fd that is handed to OwnedFd right away
+        let fd = unsafe {
+            let fd = libc::memfd_create(c"rings".as_ptr(), 0);
+            assert!(fd >= 0, "memfd_create failed");
+            assert_eq!(libc::ftruncate(fd, size as libc::off_t), 0);
+            OwnedFd::from_raw_fd(fd)
+        };
+        let region = Arc::new(SharedMemoryRegion::from_owned_fd(fd, BASE as usize).unwrap());
+        (Rings::new(Arc::clone(&region), ENTRIES).unwrap(), region)
+    }
+
+    /// Host side of the command ring: fill the slot at `cmd_tail` and publish it.
+    fn submit(region: &SharedMemoryRegion, tag: u8) {
+        let tail = region.load_u32(BASE + CMD_TAIL).unwrap();
+        let slot = BASE + HEADER_BYTES + (tail % ENTRIES) as u64 * COMMAND_BYTES as u64;
+        region.write(slot, &[tag; COMMAND_BYTES]).unwrap();
+        region.store_u32(BASE + CMD_TAIL, tail.wrapping_add(1)).unwrap();
+    }
+
+    #[test]
+    fn commands_wrap() {
+        let (rings, region) = rings();
+        // Start just below the u32 wrap so both the slot index and the counters wrap.
+        region.store_u32(BASE + CMD_HEAD, u32::MAX - 1).unwrap();
+        region.store_u32(BASE + CMD_TAIL, u32::MAX - 1).unwrap();
+        for round in 0..3u8 {
+            for idx in 0..3 {
+                submit(&region, round * 3 + idx);
+            }
+            assert_eq!(rings.pending_commands(), 3);
+            let tags: Vec<u8> = rings.fetch_commands(8).iter().map(|cmd| cmd[0]).collect();
+            assert_eq!(tags, [round * 3, round * 3 + 1, round * 3 + 2]);
+            assert_eq!(rings.pending_commands(), 0);
+        }
+        assert_eq!(region.load_u32(BASE + CMD_HEAD).unwrap(), (u32::MAX - 1).wrapping_add(9));
+    }
+
+    #[test]
+    fn events_full() {
+        let (rings, region) = rings();
+        for sid in 0..ENTRIES as u8 {
+            assert!(rings.push_event(&GluonEvent::OK { sid }));
+        }
+        assert_eq!(rings.event_space(), 0);
+        assert!(!rings.push_event(&GluonEvent::OK { sid: 9 }));
+        assert_eq!(region.load_u32(BASE + EVT_TAIL).unwrap(), ENTRIES);
+
+        // The host consumes two events; the next two land in the freed slots, wrapping around.
+        region.store_u32(BASE + EVT_HEAD, 2).unwrap();
+        assert!(rings.push_event(&GluonEvent::OK { sid: 4 }));
+        assert!(rings.push_event(&GluonEvent::OK { sid: 5 }));
+        assert!(!rings.push_event(&GluonEvent::OK { sid: 6 }));
+        let sids: Vec<u8> = (0..ENTRIES).map(|idx| region.read(rings.event_slot(idx), 1).unwrap()[0]).collect();
+        assert_eq!(sids, [4, 5, 2, 3]);
+    }
+
+    #[test]
+    fn bogus_tail_clamped() {
+        let (rings, region) = rings();
+        region.store_u32(BASE + CMD_TAIL, 1000).unwrap();
+        assert_eq!(rings.pending_commands(), ENTRIES);
+        assert_eq!(rings.fetch_commands(usize::MAX).len(), ENTRIES as usize);
+        assert_eq!(region.load_u32(BASE + CMD_HEAD).unwrap(), ENTRIES);
+
+        // A tail behind the head wraps to a huge distance and is clamped the same way.
+        region.store_u32(BASE + CMD_TAIL, 0).unwrap();
+        assert_eq!(rings.pending_commands(), ENTRIES);
+    }
+}
@@ -2,13 +2,27 @@ use std::io;
 use std::io::ErrorKind;
 use std::os::fd::{AsRawFd, OwnedFd, RawFd};
 use std::ptr::NonNull;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use crate::common::base::{MemErr, MemErrCode};
+
+/// Host memory that DMA copies to and from, addressed with the client's pointers.
+pub trait HostMemory: Send + Sync {
+    fn read(&self, host_addr: u64, len: usize) -> Result<Vec<u8>, MemErr>;
+    fn write(&self, host_addr: u64, data: &[u8]) -> Result<(), MemErr>;
+}
 
 /// Manages a shared memory mapping backed by a memfd.
+///
+/// Host pointers in commands are virtual addresses in the client's mapping. They are translated to
+/// offsets against the client's `base` and checked against the region size before every access, so the
+/// simulator can map the region anywhere and a bad pointer yields an error instead of a segfault.
 #[derive(Debug)]
 pub struct SharedMemoryRegion {
     _fd: OwnedFd,
     ptr: NonNull<u8>,
     size: usize,
+    base: u64,
 }
 
 unsafe impl Send for SharedMemoryRegion {}
@@ -31,100 +45,117 @@ impl SharedMemoryRegion {
             ));
         }
 
-        let ptr = map_shared_region(fd.as_raw_fd(), size, Some(base))
-            .ok_or_else(io::Error::last_os_error)?;
+        let ptr = map_shared_region(fd.as_raw_fd(), size)?;
 
         Ok(SharedMemoryRegion {
             _fd: fd,
             // SAFETY: map_shared_region never returns null pointer on success
             ptr: unsafe { NonNull::new_unchecked(ptr) },
             size,
+            base: base as u64,
         })
     }
-}
 
-impl Drop for SharedMemoryRegion {
-    fn drop(&mut self) {
+    /// Copy `len` bytes starting at client address `host_addr` out of the region.
+    pub fn read(&self, host_addr: u64, len: usize) -> Result<Vec<u8>, MemErr> {
+        let offset = self.translate(host_addr, len)?;
+        let mut data = vec![0u8; len];
+        // SAFETY: translate checked offset + len against the mapping
         unsafe {
-            let _ = libc::munmap(self.ptr.as_ptr().cast(), self.size);
+            std::ptr::copy_nonoverlapping(self.ptr.as_ptr().add(offset), data.as_mut_ptr(), len);
         }
+        Ok(data)
+    }
+
+    /// Copy `data` into the region at client address `host_addr`.
+    pub fn write(&self, host_addr: u64, data: &[u8]) -> Result<(), MemErr> {
+        let offset = self.translate(host_addr, data.len())?;
+        // SAFETY: translate checked offset + len against the mapping
+        unsafe {
+            std::ptr::copy_nonoverlapping(data.as_ptr(), self.ptr.as_ptr().add(offset), data.len());
+        }
+        Ok(())
     }
-}
 
-fn map_shared_region(fd: std::os::fd::RawFd, size: usize, preferred_base: Option<usize>) -> Option<*mut u8> {
-    const PREFERRED_BASES: &[usize] = &[0x1000_0000, 0x2000_0000, 0x3000_0000, 0x4000_0000];
+    /// Atomically load the 4-byte aligned word at `host_addr`, ordered after the host's release store.
+    pub fn load_u32(&self, host_addr: u64) -> Result<u32, MemErr> {
+        let word = self.word(host_addr)?;
+        Ok(word.load(Ordering::Acquire))
+    }
 
-    let prot = libc::PROT_READ | libc::PROT_WRITE;
+    /// Atomically store the 4-byte aligned word at `host_addr`, publishing every earlier write to the host.
+    pub fn store_u32(&self, host_addr: u64, value: u32) -> Result<(), MemErr> {
+        let word = self.word(host_addr)?;
+        word.store(value, Ordering::Release);
+        Ok(())
+    }
 
-    if let Some(base) = preferred_base {
-        unsafe {
-            let fixed_flag = map_fixed_noreplace_flag();
-            let mut flags = libc::MAP_SHARED;
-            if fixed_flag != 0 {
-                flags |= fixed_flag;
-            } else {
-                flags |= libc::MAP_FIXED;
-            }
-            let desired = base as *mut libc::c_void;
-            let mapped = libc::mmap(desired, size, prot, flags, fd, 0);
-            if mapped != libc::MAP_FAILED {
-                return Some(mapped.cast());
-            }
+    pub fn base(&self) -> u64 {
+        self.base
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    fn word(&self, host_addr: u64) -> Result<&AtomicU32, MemErr> {
+        let offset = self.translate(host_addr, std::mem::size_of::<u32>())?;
+        if offset % std::mem::align_of::<AtomicU32>() != 0 {
+            return Err(MemErr {
+                code: MemErrCode::HOST,
+                addr: host_addr,
+            });
         }
+        // SAFETY: translate checked the word against the mapping and it is aligned; the mapping lives as
+        // long as self
+        Ok(unsafe { AtomicU32::from_ptr(self.ptr.as_ptr().add(offset).cast()) })
     }
 
-    #[cfg(any(target_os = "linux"))]
-    unsafe {
-        let fixed_flag = map_fixed_noreplace_flag();
-        if fixed_flag != 0 {
-            for &base in PREFERRED_BASES {
-                let desired = base as *mut libc::c_void;
-                let mapped = libc::mmap(desired, size, prot, libc::MAP_SHARED | fixed_flag, fd, 0);
-                if mapped != libc::MAP_FAILED {
-                    return Some(mapped.cast());
-                }
-            }
+    fn translate(&self, host_addr: u64, len: usize) -> Result<usize, MemErr> {
+        let err = MemErr {
+            code: MemErrCode::HOST,
+            addr: host_addr,
+        };
+        let offset = host_addr.checked_sub(self.base).ok_or(err)?;
+        let end = offset.checked_add(len as u64).ok_or(err)?;
+        if end > self.size as u64 {
+            return Err(err);
         }
+        Ok(offset as usize)
+    }
+}
+
+impl HostMemory for SharedMemoryRegion {
+    fn read(&self, host_addr: u64, len: usize) -> Result<Vec<u8>, MemErr> {
+        SharedMemoryRegion::read(self, host_addr, len)
+    }
 
-        #[cfg(target_arch = "x86_64")]
-        {
-            let mapped = libc::mmap(
-                std::ptr::null_mut(),
-                size,
-                prot,
-                libc::MAP_SHARED | libc::MAP_32BIT,
-                fd,
-                0,
-            );
-            if mapped != libc::MAP_FAILED {
-                return Some(mapped.cast());
-            }
+    fn write(&self, host_addr: u64, data: &[u8]) -> Result<(), MemErr> {
+        SharedMemoryRegion::write(self, host_addr, data)
+    }
+}
+
+impl Drop for SharedMemoryRegion {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = libc::munmap(self.ptr.as_ptr().cast(), self.size);
         }
     }
+}
+
+fn map_shared_region(fd: RawFd, size: usize) -> io::Result<*mut u8> {
+    let prot = libc::PROT_READ | libc::PROT_WRITE;
 
     unsafe {
         let mapped = libc::mmap(std::ptr::null_mut(), size, prot, libc::MAP_SHARED, fd, 0);
         if mapped == libc::MAP_FAILED {
-            None
+            Err(io::Error::last_os_error())
         } else {
-            Some(mapped.cast())
+            Ok(mapped.cast())
         }
     }
 }
 
-#[cfg(any(target_os = "linux"))]
-const fn map_fixed_noreplace_flag() -> libc::c_int {
-    #[cfg(any(target_env = "gnu", target_env = "musl"))]
-    {
-        libc::MAP_FIXED_NOREPLACE
-    }
-
-    #[cfg(not(any(target_env = "gnu", target_env = "musl")))]
-    {
-        0
-    }
-}
-
 fn file_size(fd: RawFd) -> io::Result<usize> {
     unsafe {
         let mut stat: libc::stat = std::mem::zeroed();
@@ -1,64 +1,149 @@
-use crate::common::base::{Clocked, Command, Configurable, Event, SimErr};
-use crate::glug::glug::{GLUGConfig, GLUG};
+use crate::common::base::{Clocked, Configurable, SimErr};
+use crate::common::protocol::{GluonCommand, GluonEvent};
+use crate::context::Context;
+use crate::glug::glug::{EngineStats, GLUGConfig, GLUG};
+use crate::glug::partition::KernelPlacement;
+use crate::ring::Rings;
+use crate::shared_memory::HostMemory;
+use crate::trace::{SharedTrace, TraceRecord};
 use serde::Deserialize;
+use std::ops::Range;
+use std::sync::Arc;
 
-#[derive(Debug, Clone, Copy, Deserialize)]
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
 #[serde(default)]
 pub struct SimConfig {
     pub timeout_cycles: u64,
 }
 
-impl Default for SimConfig {
-    fn default() -> Self {
-        Self { timeout_cycles: 0 }
-    }
-}
-
-#[derive(Debug, Clone)]
+#[derive(Debug, Default, Clone)]
 pub struct TopConfig {
     pub sim: SimConfig,
     pub glug: GLUGConfig,
 }
 
-impl Default for TopConfig {
-    fn default() -> Self {
-        Self {
-            sim: SimConfig::default(),
-            glug: GLUGConfig::default(),
-        }
-    }
-}
-
 pub struct Top {
     glug: GLUG,
     cycles_elapsed: u64,
     cycles_timeout: u64,
+    failed: Option<SimErr>,
+    trace: Option<SharedTrace>,
 }
 
 impl Top {
-    pub fn submit_command(&mut self, command: Command) {
-        self.glug.submit_command(command);
+    pub fn submit_command(&mut self, command: GluonCommand) -> bool {
+        self.glug.submit_command(command)
+    }
+
+    pub fn set_host_memory(&mut self, sids: Range<u8>, host: Option<Arc<dyn HostMemory>>) {
+        self.glug.set_host_memory(sids, host);
+    }
+
+    pub fn reject_command(&mut self, event: GluonEvent) {
+        self.glug.reject_command(event);
     }
 
-    pub fn get_completion(&mut self) -> Option<Event> {
-        self.glug.get_completion()
+    pub fn attach_ring(&mut self, context: Context, rings: Arc<Rings>) {
+        self.glug.attach_ring(context, rings);
+    }
+
+    pub fn detach_ring(&mut self, context_id: usize) {
+        self.glug.detach_ring(context_id);
+    }
+
+    pub fn ring_doorbell(&mut self, context_id: usize) {
+        self.glug.ring_doorbell(context_id);
+    }
+
+    pub fn num_streams(&self) -> usize {
+        self.glug.num_streams()
+    }
+
+    pub fn can_submit(&self) -> bool {
+        self.glug.can_submit()
+    }
+
+    pub fn initial_credits(&self) -> u32 {
+        self.glug.initial_credits()
+    }
+
+    pub fn take_credits(&mut self, sids: Range<u8>) -> u32 {
+        self.glug.take_credits(sids)
+    }
+
+    pub fn get_completion(&mut self, sids: Range<u8>) -> Option<GluonEvent> {
+        let event = self.glug.get_completion(sids);
+        if let (Some(trace), Some(event)) = (&self.trace, event) {
+            trace.lock().expect("trace poisoned").record(TraceRecord::EVENT(event));
+        }
+        event
+    }
+
+    /// Record everything this simulator is sent and delivers into `trace`, starting a new segment of it.
+    pub fn set_trace(&mut self, trace: Option<SharedTrace>) {
+        if let Some(trace) = &trace {
+            let mut writer = trace.lock().expect("trace poisoned");
+            writer.set_cycle(self.cycles_elapsed);
+            writer.record(TraceRecord::RESET);
+        }
+        self.glug.set_trace(trace.clone());
+        self.trace = trace;
+    }
+
+    pub fn events_produced(&self) -> u64 {
+        self.glug.events_produced()
+    }
+
+    pub fn quiescent(&mut self) -> bool {
+        self.glug.quiescent()
+    }
+
+    pub fn streams_idle(&self, sids: Range<u8>) -> bool {
+        self.glug.streams_idle(sids)
+    }
+
+    pub fn engine_stats(&self) -> Vec<EngineStats> {
+        self.glug.engine_stats()
+    }
+
+    pub fn kernel_placements(&self) -> impl Iterator<Item = &KernelPlacement> {
+        self.glug.kernel_placements()
     }
 
     pub fn cycles_elapsed(&self) -> u64 {
         self.cycles_elapsed
     }
+
+    /// The error the simulator stopped with. Once set, the simulator no longer advances and every
+    /// outstanding command has been completed with `SIM`.
+    pub fn failed(&self) -> Option<SimErr> {
+        self.failed
+    }
 }
 
 impl Clocked for Top {
     fn tick(&mut self) -> Result<(), SimErr> {
-        self.glug.tick()?;
-        self.cycles_elapsed = self.cycles_elapsed.saturating_add(1);
+        if let Some(err) = self.failed {
+            return Err(err);
+        }
+
+        let result = self.glug.tick().and_then(|_| {
+            self.cycles_elapsed = self.cycles_elapsed.saturating_add(1);
+            if let Some(trace) = &self.trace {
+                trace.lock().expect("trace poisoned").set_cycle(self.cycles_elapsed);
+            }
+            if self.cycles_timeout != 0 && self.cycles_elapsed >= self.cycles_timeout {
+                Err(SimErr::TIMEOUT)
+            } else {
+                Ok(())
+            }
+        });
 
-        if self.cycles_timeout != 0 && self.cycles_elapsed >= self.cycles_timeout {
-            Err(SimErr::TIMEOUT)
-        } else {
-            Ok(())
+        if let Err(err) = result {
+            self.glug.abort(err);
+            self.failed = Some(err);
         }
+        result
     }
 
     fn busy(&mut self) -> bool {
@@ -72,6 +157,8 @@ impl Configurable<TopConfig> for Top {
             glug: GLUG::new(&config.glug),
             cycles_elapsed: 0,
             cycles_timeout: config.sim.timeout_cycles,
+            failed: None,
+            trace: None,
         }
     }
 }
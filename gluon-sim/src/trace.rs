@@ -0,0 +1,289 @@
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::common::protocol::{GluonCommand, GluonEvent, COMMAND_BYTES, EVENT_BYTES, PROTOCOL_VERSION};
+
+pub const TRACE_MAGIC: &[u8; 8] = b"GLUTRACE";
+/// Bumped whenever the layout of the header or of a record changes. v2 added the protocol version to the header.
+pub const TRACE_VERSION: u32 = 2;
+
+/// One entry of a trace file. After the magic, the trace version and the `PROTOCOL_VERSION` the commands and
+/// events are encoded with, every record is a tag byte, the cycle it was
+/// recorded at (u64) and a tag-specific body, all little-endian. Commands and events are stored in their
+/// wire encoding with global stream ids.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TraceRecord {
+    /// The server configuration the run used, as TOML text: `len (u32), bytes`.
+    CONFIG(String),
+    /// A fresh simulator starts here; cycles restart at 0.
+    RESET,
+    /// A command entered the frontend: `32-byte header`.
+    COMMAND(GluonCommand),
+    /// A command was refused before reaching the frontend and answered with this event: `32-byte event`.
+    REJECT(GluonEvent),
+    /// DMA pulled host memory into the device: `sid (u8), addr (u64), len (u32), bytes`.
+    HOSTREAD { sid: u8, addr: u64, data: Vec<u8> },
+    /// An event was delivered to the host: `32-byte event`.
+    EVENT(GluonEvent),
+}
+
+impl TraceRecord {
+    fn tag(&self) -> u8 {
+        match self {
+            TraceRecord::CONFIG(_) => 0,
+            TraceRecord::RESET => 1,
+            TraceRecord::COMMAND(_) => 2,
+            TraceRecord::REJECT(_) => 3,
+            TraceRecord::HOSTREAD { .. } => 4,
+            TraceRecord::EVENT(_) => 5,
+        }
+    }
+
+    fn read_from(tag: u8, input: &mut impl Read) -> io::Result<Self> {
+        let invalid = |what: String| io::Error::new(ErrorKind::InvalidData, what);
+        match tag {
+            0 => {
+                let len = read_u32(input)? as usize;
+                let text = String::from_utf8(read_bytes(input, len)?)
+                    .map_err(|_| invalid("trace config is not UTF-8".to_string()))?;
+                Ok(TraceRecord::CONFIG(text))
+            }
+            1 => Ok(TraceRecord::RESET),
+            2 => {
+                let mut buffer = [0u8; COMMAND_BYTES];
+                input.read_exact(&mut buffer)?;
+                GluonCommand::decode(&buffer)
+                    .map(TraceRecord::COMMAND)
+                    .map_err(|err| invalid(format!("bad command in trace: {:?}", err)))
+            }
+            3 | 5 => {
+                let mut buffer = [0u8; EVENT_BYTES];
+                input.read_exact(&mut buffer)?;
+                let event = GluonEvent::decode(&buffer)
+                    .map_err(|err| invalid(format!("bad event in trace: {:?}", err)))?;
+                Ok(if tag == 3 { TraceRecord::REJECT(event) } else { TraceRecord::EVENT(event) })
+            }
+            4 => {
+                let mut sid = [0u8; 1];
+                input.read_exact(&mut sid)?;
+                let addr = read_u64(input)?;
+                let len = read_u32(input)? as usize;
+                Ok(TraceRecord::HOSTREAD { sid: sid[0], addr, data: read_bytes(input, len)? })
+            }
+            other => Err(invalid(format!("unknown trace record tag {}", other))),
+        }
+    }
+
+    fn write_to(&self, cycle: u64, out: &mut impl Write) -> io::Result<()> {
+        out.write_all(&[self.tag()])?;
+        out.write_all(&cycle.to_le_bytes())?;
+        match self {
+            TraceRecord::CONFIG(text) => {
+                out.write_all(&(text.len() as u32).to_le_bytes())?;
+                out.write_all(text.as_bytes())
+            }
+            TraceRecord::RESET => Ok(()),
+            TraceRecord::COMMAND(cmd) => out.write_all(&cmd.encode()),
+            TraceRecord::REJECT(event) | TraceRecord::EVENT(event) => out.write_all(&event.encode()),
+            TraceRecord::HOSTREAD { sid, addr, data } => {
+                out.write_all(&[*sid])?;
+                out.write_all(&addr.to_le_bytes())?;
+                out.write_all(&(data.len() as u32).to_le_bytes())?;
+                out.write_all(data)
+            }
+        }
+    }
+}
+
+/// Records a run into a self-contained trace file: the configuration, everything the host sent and
+/// received, and the host memory DMA read, so the run can be reproduced without the host.
+pub struct TraceWriter {
+    out: BufWriter<File>,
+    cycle: u64,
+}
+
+/// The trace is written from the simulator and from every session, so it is shared behind a lock.
+pub type SharedTrace = Arc<Mutex<TraceWriter>>;
+
+impl TraceWriter {
+    pub fn create(path: &Path, config: &str) -> io::Result<Self> {
+        let mut out = BufWriter::new(File::create(path)?);
+        out.write_all(TRACE_MAGIC)?;
+        out.write_all(&TRACE_VERSION.to_le_bytes())?;
+        out.write_all(&PROTOCOL_VERSION.to_le_bytes())?;
+        let mut writer = TraceWriter { out, cycle: 0 };
+        writer.record(TraceRecord::CONFIG(config.to_string()));
+        Ok(writer)
+    }
+
+    /// Cycle stamped on the records that follow.
+    pub fn set_cycle(&mut self, cycle: u64) {
+        self.cycle = cycle;
+    }
+
+    pub fn record(&mut self, record: TraceRecord) {
+        record
+            .write_to(self.cycle, &mut self.out)
+            .expect("Trace: write failed");
+        // Events are rare and a failing run may not shut down cleanly, so make them durable right away.
+        if matches!(record, TraceRecord::EVENT(_) | TraceRecord::RESET) {
+            self.out.flush().expect("Trace: flush failed");
+        }
+    }
+}
+
+impl Drop for TraceWriter {
+    fn drop(&mut self) {
+        let _ = self.out.flush();
+    }
+}
+
+/// Reads a trace file back as `(cycle, record)` pairs.
+pub struct TraceReader {
+    input: BufReader<File>,
+}
+
+impl TraceReader {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let mut input = BufReader::new(File::open(path)?);
+        let mut magic = [0u8; 8];
+        input.read_exact(&mut magic)?;
+        if &magic != TRACE_MAGIC {
+            return Err(io::Error::new(ErrorKind::InvalidData, "not a gluon trace"));
+        }
+        let version = read_u32(&mut input)?;
+        if version != TRACE_VERSION {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("trace version {} unsupported, expected {}", version, TRACE_VERSION),
+            ));
+        }
+        let protocol = read_u32(&mut input)?;
+        if protocol != PROTOCOL_VERSION {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("trace recorded with protocol v{}, replay speaks v{}", protocol, PROTOCOL_VERSION),
+            ));
+        }
+        Ok(TraceReader { input })
+    }
+}
+
+impl Iterator for TraceReader {
+    type Item = io::Result<(u64, TraceRecord)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut tag = [0u8; 1];
+        match self.input.read_exact(&mut tag) {
+            Ok(()) => {}
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => return None,
+            Err(err) => return Some(Err(err)),
+        }
+        Some(read_u64(&mut self.input).and_then(|cycle| {
+            TraceRecord::read_from(tag[0], &mut self.input).map(|record| (cycle, record))
+        }))
+    }
+}
+
+fn read_u32(input: &mut impl Read) -> io::Result<u32> {
+    let mut buffer = [0u8; 4];
+    input.read_exact(&mut buffer)?;
+    Ok(u32::from_le_bytes(buffer))
+}
+
+fn read_u64(input: &mut impl Read) -> io::Result<u64> {
+    let mut buffer = [0u8; 8];
+    input.read_exact(&mut buffer)?;
+    Ok(u64::from_le_bytes(buffer))
+}
+
+fn read_bytes(input: &mut impl Read, len: usize) -> io::Result<Vec<u8>> {
+    let mut buffer = vec![0u8; len];
+    input.read_exact(&mut buffer)?;
+    Ok(buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::common::protocol::{CopyCommand, KernelCommand};
+
+    /// A trace file in the temp directory, removed when dropped.
+    struct TempTrace(PathBuf);
+
+    impl TempTrace {
+        fn new(name: &str) -> Self {
+            TempTrace(std::env::temp_dir().join(format!("gluon-{}-{}.trace", name, std::process::id())))
+        }
+    }
+
+    impl Drop for TempTrace {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn records_roundtrip() {
+        let records = vec![
+            TraceRecord::RESET,
+            TraceRecord::COMMAND(GluonCommand::KERNEL(KernelCommand {
+                sid: 1,
+                host_addr: 0x7f00_0000_1000,
+                sz: 128,
+                gpu_addr: 0x1_0000_2000,
+            })),
+            TraceRecord::COMMAND(GluonCommand::COPY(CopyCommand {
+                sid: 2,
+                src: 0x7f00_0000_2000,
+                dst: 0x1_0000_0000,
+                len: 64,
+                flags: 0,
+            })),
+            TraceRecord::REJECT(GluonEvent::OK { sid: 3 }),
+            TraceRecord::HOSTREAD {
+                sid: 2,
+                addr: 0x7f00_0000_2000,
+                data: (0..64).collect(),
+            },
+            TraceRecord::HOSTREAD {
+                sid: 2,
+                addr: 0x7f00_0000_3000,
+                data: vec![],
+            },
+            TraceRecord::EVENT(GluonEvent::TIMEOUT { sid: 1, cycles: 5000 }),
+            TraceRecord::CONFIG("[server]\nname = \"gluon\"\n".to_string()),
+        ];
+        let trace = TempTrace::new("roundtrip");
+        {
+            let mut writer = TraceWriter::create(&trace.0, "cycles = 10").unwrap();
+            for (cycle, record) in records.iter().enumerate() {
+                writer.set_cycle(cycle as u64 * 100);
+                writer.record(record.clone());
+            }
+        }
+        let read: Vec<_> = TraceReader::open(&trace.0)
+            .unwrap()
+            .collect::<io::Result<_>>()
+            .unwrap();
+        let mut expected = vec![(0, TraceRecord::CONFIG("cycles = 10".to_string()))];
+        expected.extend(records.into_iter().enumerate().map(|(cycle, record)| (cycle as u64 * 100, record)));
+        assert_eq!(read, expected);
+    }
+
+    #[test]
+    fn protocol_mismatch_rejected() {
+        let trace = TempTrace::new("mismatch");
+        let mut bytes = TRACE_MAGIC.to_vec();
+        bytes.extend(TRACE_VERSION.to_le_bytes());
+        bytes.extend((PROTOCOL_VERSION - 1).to_le_bytes());
+        std::fs::write(&trace.0, bytes).unwrap();
+        let err = TraceReader::open(&trace.0).err().expect("mismatched trace opened");
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+}
@@ -11,7 +11,9 @@
 #include <cstdint>
 #include <cstring>
 #include <cstdlib>
+#include <deque>
 #include <fcntl.h>
+#include <sched.h>
 #include <iomanip>
 #include <iostream>
 #include <limits>
@@ -43,10 +45,45 @@ struct SharedMemoryRegion {
     ~SharedMemoryRegion() { Reset(); }
 };
 
+// Must match PROTOCOL_VERSION in gluon-sim/src/common/protocol.rs.
+constexpr std::uint32_t kProtocolVersion = 11;
+constexpr std::size_t kMessageBytes = 32;
+constexpr std::uint8_t kEventCredit = 2;
+constexpr std::uint8_t kEventHello = 3;
+constexpr std::uint8_t kEventReject = 9;
+
+using Message = std::array<std::uint8_t, kMessageBytes>;
+
+// Shared-memory ring transport; must match gluon-sim/src/ring.rs. The rings sit at the start of the shared
+// region: cmd_head, cmd_tail, evt_head, evt_tail (u32 each), padding to 64 bytes, then the command slots
+// followed by the event slots. A single byte on the socket is a doorbell in either direction.
+constexpr std::size_t kRingHeaderBytes = 64;
+constexpr std::size_t kCmdHead = 0x00;
+constexpr std::size_t kCmdTail = 0x04;
+constexpr std::size_t kEvtHead = 0x08;
+constexpr std::size_t kEvtTail = 0x0c;
+constexpr std::size_t kPageBytes = 4096;
+
+struct DriverConfig {
+    std::string socket_path;
+    // 0 sends commands over the socket; otherwise the entries of each ring, a power of two.
+    std::uint32_t ring_entries = 0;
+};
+
 struct ConnectionState {
     bool initialized = false;
     int sock = -1;
     SharedMemoryRegion shared;
+    // Commands the simulator frontend can still accept; returned by credit messages.
+    std::uint32_t credits = 0;
+    // Completions that arrived while waiting for credits.
+    std::deque<Message> pending;
+    // Stream ids and device memory the simulator assigned to this connection.
+    std::uint8_t streams = 0;
+    DeviceMemoryWindow dram{};
+    std::uint32_t ring_entries = 0;
+    // Offset of the payload staging area; the rings live below it.
+    std::size_t payload_offset = 0;
 };
 
 ConnectionState& GetState() {
@@ -54,7 +91,7 @@ ConnectionState& GetState() {
     return state;
 }
 
-std::optional<std::string> LoadSocketPath() {
+std::optional<DriverConfig> LoadConfig() {
     constexpr const char* kConfigPath = "config.toml";
     toml::table config;
     try {
@@ -73,14 +110,46 @@ std::optional<std::string> LoadSocketPath() {
         std::cerr << "socket_path missing from [server] section\n";
         return std::nullopt;
     }
-    return socket_path;
+    DriverConfig driver_config;
+    driver_config.socket_path = *socket_path;
+    driver_config.ring_entries = config["driver"]["ring_entries"].value_or(0u);
+    if (driver_config.ring_entries & (driver_config.ring_entries - 1)) {
+        std::cerr << "ring_entries must be a power of two\n";
+        return std::nullopt;
+    }
+    return driver_config;
+}
+
+std::size_t RingBytes(std::uint32_t entries) {
+    return kRingHeaderBytes + static_cast<std::size_t>(entries) * (kCommandBytes + kMessageBytes);
+}
+
+std::uint32_t* RingField(ConnectionState& state, std::size_t offset) {
+    return reinterpret_cast<std::uint32_t*>(static_cast<std::uint8_t*>(state.shared.addr) + offset);
+}
+
+std::uint32_t LoadRingField(ConnectionState& state, std::size_t offset) {
+    return __atomic_load_n(RingField(state, offset), __ATOMIC_ACQUIRE);
 }
 
-bool SendFileDescriptor(int sock, int fd, std::uintptr_t addr) {
+void StoreRingField(ConnectionState& state, std::size_t offset, std::uint32_t value) {
+    __atomic_store_n(RingField(state, offset), value, __ATOMIC_RELEASE);
+}
+
+void* PayloadBase(ConnectionState& state) {
+    return static_cast<std::uint8_t*>(state.shared.addr) + state.payload_offset;
+}
+
+bool SendFileDescriptor(int sock, int fd, std::uintptr_t addr, std::uint32_t ring_entries) {
     struct ::msghdr msg = {};
-    std::uint64_t buffer = static_cast<std::uint64_t>(addr);
+    // Handshake: shared memory base, protocol version, ring entries (0 for the socket transport).
+    std::uint8_t buffer[16] = {};
+    std::uint64_t base = static_cast<std::uint64_t>(addr);
+    std::memcpy(buffer, &base, sizeof(base));
+    std::memcpy(buffer + 8, &kProtocolVersion, sizeof(kProtocolVersion));
+    std::memcpy(buffer + 12, &ring_entries, sizeof(ring_entries));
     struct ::iovec iov;
-    iov.iov_base = &buffer;
+    iov.iov_base = buffer;
     iov.iov_len = sizeof(buffer);
     msg.msg_iov = &iov;
     msg.msg_iovlen = 1;
@@ -118,6 +187,128 @@ bool SendAll(int fd, const std::uint8_t* data, std::size_t size) {
     return true;
 }
 
+bool RecvAll(int fd, std::uint8_t* data, std::size_t size) {
+    std::size_t total_received = 0;
+    while (total_received < size) {
+        ssize_t received = ::recv(fd, data + total_received, size - total_received, 0);
+        if (received == -1) {
+            if (errno == EINTR) {
+                continue;
+            }
+            return false;
+        }
+        if (received == 0) {
+            return false;
+        }
+        total_received += static_cast<std::size_t>(received);
+    }
+    return true;
+}
+
+std::uint32_t ReadU32(const Message& message, std::size_t offset) {
+    std::uint32_t value = 0;
+    std::memcpy(&value, message.data() + offset, sizeof(value));
+    return value;
+}
+
+std::uint64_t ReadU64(const Message& message, std::size_t offset) {
+    std::uint64_t value = 0;
+    std::memcpy(&value, message.data() + offset, sizeof(value));
+    return value;
+}
+
+// The simulator greets every connection with its protocol version, the initial command credits and the
+// client's context: how many stream ids it owns and which device memory it may use. A simulator on
+// another protocol version answers with a reject carrying its version instead.
+bool ReceiveHello(ConnectionState& state) {
+    Message message{};
+    if (!RecvAll(state.sock, message.data(), message.size())) {
+        std::cerr << "Connection lost before hello\n";
+        return false;
+    }
+    if (message[1] == kEventReject) {
+        std::cerr << "Simulator rejected the connection: driver v" << kProtocolVersion << ", simulator v"
+                  << ReadU32(message, 2) << '\n';
+        return false;
+    }
+    if (message[1] != kEventHello) {
+        std::cerr << "Expected hello, got message type " << static_cast<int>(message[1]) << '\n';
+        return false;
+    }
+    std::uint32_t version = ReadU32(message, 2);
+    if (version != kProtocolVersion) {
+        std::cerr << "Protocol mismatch: driver v" << kProtocolVersion << ", simulator v" << version << '\n';
+        return false;
+    }
+    state.credits = ReadU32(message, 6);
+    state.streams = message[10];
+    state.dram.base = ReadU64(message, 11);
+    state.dram.size = ReadU64(message, 19);
+    std::cout << "Assigned " << static_cast<int>(state.streams) << " streams, device memory 0x" << std::hex
+              << state.dram.base << "+0x" << state.dram.size << std::dec << '\n';
+    return true;
+}
+
+// Reads one message from the simulator. Credit messages are absorbed into the credit count; completions
+// are returned to the caller.
+std::optional<Message> ReceiveMessage(ConnectionState& state) {
+    Message message{};
+    if (!RecvAll(state.sock, message.data(), message.size())) {
+        return std::nullopt;
+    }
+    if (message[1] == kEventCredit) {
+        state.credits += ReadU32(message, 2);
+    }
+    return message;
+}
+
+bool RingDoorbell(ConnectionState& state) {
+    const std::uint8_t doorbell = 1;
+    return SendAll(state.sock, &doorbell, sizeof(doorbell));
+}
+
+// Waits for a free command slot; the simulator frees slots as its frontend fetches them.
+void WaitCommandSlot(ConnectionState& state) {
+    while (LoadRingField(state, kCmdTail) - LoadRingField(state, kCmdHead) >= state.ring_entries) {
+        sched_yield();
+    }
+}
+
+// Moves everything on the completion ring into the pending queue. Returns false if the connection dropped
+// while waiting for the simulator's doorbell.
+bool ReceiveRingEvents(ConnectionState& state) {
+    std::uint32_t head = LoadRingField(state, kEvtHead);
+    if (head == LoadRingField(state, kEvtTail)) {
+        std::uint8_t doorbell = 0;
+        return RecvAll(state.sock, &doorbell, sizeof(doorbell));
+    }
+    const std::uint8_t* slots = static_cast<const std::uint8_t*>(state.shared.addr) + kRingHeaderBytes +
+                                static_cast<std::size_t>(state.ring_entries) * kCommandBytes;
+    for (; head != LoadRingField(state, kEvtTail); ++head) {
+        Message message{};
+        std::memcpy(message.data(), slots + (head % state.ring_entries) * kMessageBytes, kMessageBytes);
+        state.pending.push_back(message);
+    }
+    StoreRingField(state, kEvtHead, head);
+    // Tell the simulator the completion ring has room again.
+    return RingDoorbell(state);
+}
+
+bool AcquireCredit(ConnectionState& state) {
+    while (state.credits == 0) {
+        std::optional<Message> message = ReceiveMessage(state);
+        if (!message) {
+            std::cerr << "Connection lost while waiting for command credits\n";
+            return false;
+        }
+        if ((*message)[1] != kEventCredit) {
+            state.pending.push_back(*message);
+        }
+    }
+    --state.credits;
+    return true;
+}
+
 }
 
 void ShutdownConnection() {
@@ -127,23 +318,35 @@ void ShutdownConnection() {
         state.sock = -1;
     }
     state.shared.Reset();
+    state.credits = 0;
+    state.pending.clear();
+    state.streams = 0;
+    state.dram = {};
+    state.ring_entries = 0;
+    state.payload_offset = 0;
     state.initialized = false;
 }
 
 bool InitConnection(std::size_t shared_mem_bytes) {
     ConnectionState& state = GetState();
     if (state.initialized) {
-        if (shared_mem_bytes <= state.shared.size) {
+        if (shared_mem_bytes <= state.shared.size - state.payload_offset) {
             return true;
         }
         ShutdownConnection();
     }
-    auto socket_path = LoadSocketPath();
-    if (!socket_path) {
+    auto driver_config = LoadConfig();
+    if (!driver_config) {
         return false;
     }
-    if (socket_path->size() >= sizeof(sockaddr_un{}.sun_path)) {
-        std::cerr << "Socket path is too long: " << *socket_path << '\n';
+    const std::string& socket_path = driver_config->socket_path;
+    // Ring mode keeps the rings below the payload area, on their own pages.
+    std::size_t payload_offset = 0;
+    if (driver_config->ring_entries != 0) {
+        payload_offset = (RingBytes(driver_config->ring_entries) + kPageBytes - 1) / kPageBytes * kPageBytes;
+    }
+    if (socket_path.size() >= sizeof(sockaddr_un{}.sun_path)) {
+        std::cerr << "Socket path is too long: " << socket_path << '\n';
         return false;
     }
     int sock = ::socket(AF_UNIX, SOCK_STREAM, 0);
@@ -153,8 +356,8 @@ bool InitConnection(std::size_t shared_mem_bytes) {
     }
     sockaddr_un server_address{};
     server_address.sun_family = AF_UNIX;
-    std::strncpy(server_address.sun_path, socket_path->c_str(), sizeof(server_address.sun_path) - 1);
-    std::cout << "Connecting to " << *socket_path << "...\n";
+    std::strncpy(server_address.sun_path, socket_path.c_str(), sizeof(server_address.sun_path) - 1);
+    std::cout << "Connecting to " << socket_path << "...\n";
     if (::connect(sock, reinterpret_cast<sockaddr*>(&server_address), sizeof(server_address)) == -1) {
         const int connect_errno = errno;
         std::cerr << "Failed to connect: " << std::strerror(connect_errno) << '\n';
@@ -162,7 +365,7 @@ bool InitConnection(std::size_t shared_mem_bytes) {
         return false;
     }
     SharedMemoryRegion region;
-    region.size = shared_mem_bytes;
+    region.size = payload_offset + shared_mem_bytes;
     region.fd = ::memfd_create("gluon-payload", MFD_CLOEXEC);
     if (region.fd == -1) {
         std::cerr << "Failed to create shared memory: " << std::strerror(errno) << '\n';
@@ -175,61 +378,21 @@ bool InitConnection(std::size_t shared_mem_bytes) {
         ::close(sock);
         return false;
     }
-    region.addr = MAP_FAILED;
-#ifdef MAP_FIXED_NOREPLACE
-    {
-        constexpr std::uintptr_t kPreferredBases[] = {
-            0x10000000u,
-            0x20000000u,
-            0x30000000u,
-            0x40000000u,
-        };
-        for (std::uintptr_t base : kPreferredBases) {
-            void* desired = reinterpret_cast<void*>(base);
-            void* mapped = ::mmap(
-                desired,
-                region.size,
-                PROT_READ | PROT_WRITE,
-                MAP_SHARED | MAP_FIXED_NOREPLACE,
-                region.fd,
-                0);
-            if (mapped != MAP_FAILED) {
-                region.addr = mapped;
-                break;
-            }
-        }
-    }
-#endif
-#ifdef MAP_32BIT
-    if (region.addr == MAP_FAILED) {
-        void* mapped = ::mmap(
-            nullptr,
-            region.size,
-            PROT_READ | PROT_WRITE,
-            MAP_SHARED | MAP_32BIT,
-            region.fd,
-            0);
-        if (mapped != MAP_FAILED) {
-            region.addr = mapped;
-        }
-    }
-#endif
-    if (region.addr == MAP_FAILED) {
-        region.addr = ::mmap(
-            nullptr,
-            region.size,
-            PROT_READ | PROT_WRITE,
-            MAP_SHARED,
-            region.fd,
-            0);
-    }
+    // Commands carry 64-bit host pointers, so the region can live anywhere in the address space.
+    region.addr = ::mmap(
+        nullptr,
+        region.size,
+        PROT_READ | PROT_WRITE,
+        MAP_SHARED,
+        region.fd,
+        0);
     if (region.addr == MAP_FAILED) {
         std::cerr << "Failed to map shared memory: " << std::strerror(errno) << '\n';
         region.Reset();
         ::close(sock);
         return false;
     }
-    if (!SendFileDescriptor(sock, region.fd, reinterpret_cast<std::uintptr_t>(region.addr))) {
+    if (!SendFileDescriptor(sock, region.fd, reinterpret_cast<std::uintptr_t>(region.addr), driver_config->ring_entries)) {
         region.Reset();
         ::close(sock);
         return false;
@@ -242,7 +405,15 @@ bool InitConnection(std::size_t shared_mem_bytes) {
     region.addr = MAP_FAILED;
     region.size = 0;
     state.sock = sock;
+    state.credits = 0;
+    state.pending.clear();
+    state.ring_entries = driver_config->ring_entries;
+    state.payload_offset = payload_offset;
     state.initialized = true;
+    if (!ReceiveHello(state)) {
+        ShutdownConnection();
+        return false;
+    }
     static bool registered = false;
     if (!registered) {
         std::atexit([] { ShutdownConnection(); });
@@ -251,7 +422,7 @@ bool InitConnection(std::size_t shared_mem_bytes) {
     return true;
 }
 
-std::optional<std::string> SubmitCommand(const std::array<std::uint8_t, 16>& header,
+std::optional<std::string> SubmitCommand(const CommandHeader& header,
                                          const void* payload,
                                          std::size_t payload_size) {
     ConnectionState& state = GetState();
@@ -261,36 +432,47 @@ std::optional<std::string> SubmitCommand(const std::array<std::uint8_t, 16>& hea
             return std::nullopt;
         }
     }
-    if (payload_size > state.shared.size) {
+    if (payload_size > state.shared.size - state.payload_offset) {
         std::cerr << "Command payload size exceeds shared memory size\n";
         return std::nullopt;
     }
-    std::array<std::uint8_t, 16> header_bytes = header;
+    CommandHeader header_bytes = header;
     if (payload_size > 0) {
         if (!payload) {
             std::cerr << "Command payload missing data pointer\n";
             return std::nullopt;
         }
-        std::memcpy(state.shared.addr, payload, payload_size);
+        std::memcpy(PayloadBase(state), payload, payload_size);
     }
-    std::uintptr_t shared_base = reinterpret_cast<std::uintptr_t>(state.shared.addr);
-    if (shared_base > std::numeric_limits<std::uint32_t>::max()) {
-        std::cerr << "Shared memory address exceeds 32-bit range\n";
-        return std::nullopt;
-    }
-    std::uint32_t shared_base_u32 = static_cast<std::uint32_t>(shared_base);
+    std::uint64_t shared_base = static_cast<std::uint64_t>(reinterpret_cast<std::uintptr_t>(PayloadBase(state)));
     if (header_bytes[1] == radCmdType_MEM) {
-        if (header_bytes[15] == radMemCpyDir_H2D) {
-            std::memcpy(header_bytes.data() + 3, &shared_base_u32, sizeof(shared_base_u32));
-        } else {
-            std::memcpy(header_bytes.data() + 7, &shared_base_u32, sizeof(shared_base_u32));
+        if (header_bytes[23] == radMemCpyDir_H2D) {
+            std::memcpy(header_bytes.data() + 3, &shared_base, sizeof(shared_base));
+        } else if (header_bytes[23] == radMemCpyDir_D2H) {
+            std::memcpy(header_bytes.data() + 11, &shared_base, sizeof(shared_base));
         }
     } else if (header_bytes[1] == radCmdType_KERNEL) {
-        std::memcpy(header_bytes.data() + 2, &shared_base_u32, sizeof(shared_base_u32));
+        std::memcpy(header_bytes.data() + 2, &shared_base, sizeof(shared_base));
     }
     std::cout << "Submitting command (id=" << static_cast<int>(header_bytes[0])
               << ", size=" << payload_size
               << ")\n";
+    if (state.ring_entries != 0) {
+        WaitCommandSlot(state);
+        std::uint32_t tail = LoadRingField(state, kCmdTail);
+        std::uint8_t* slot = static_cast<std::uint8_t*>(state.shared.addr) + kRingHeaderBytes +
+                             (tail % state.ring_entries) * kCommandBytes;
+        std::memcpy(slot, header_bytes.data(), header_bytes.size());
+        StoreRingField(state, kCmdTail, tail + 1);
+        if (!RingDoorbell(state)) {
+            std::cerr << "Failed to ring doorbell: " << std::strerror(errno) << '\n';
+            return std::nullopt;
+        }
+        return std::string("OK");
+    }
+    if (!AcquireCredit(state)) {
+        return std::nullopt;
+    }
     if (!SendAll(state.sock, header_bytes.data(), header_bytes.size())) {
         std::cerr << "Failed to send command: " << std::strerror(errno) << '\n';
         return std::nullopt;
@@ -303,20 +485,36 @@ std::optional<std::string> ReceiveError() {
     if (!state.initialized) {
         return std::nullopt;
     }
-    char buffer[1024] = {0};
-    ssize_t received = ::recv(state.sock, buffer, sizeof(buffer) - 1, 0);
-    if (received == -1) {
-        if (errno == EAGAIN || errno == EWOULDBLOCK) {
+    while (state.pending.empty() && state.ring_entries != 0) {
+        if (!ReceiveRingEvents(state)) {
+            ShutdownConnection();
             return std::nullopt;
         }
-        std::cerr << "Failed to receive data: " << std::strerror(errno) << '\n';
-        return std::nullopt;
     }
-    if (received == 0) {
-        ShutdownConnection();
-        return std::nullopt;
+    while (state.pending.empty()) {
+        std::optional<Message> message = ReceiveMessage(state);
+        if (!message) {
+            ShutdownConnection();
+            return std::nullopt;
+        }
+        if ((*message)[1] != kEventCredit) {
+            state.pending.push_back(*message);
+        }
+    }
+    Message message = state.pending.front();
+    state.pending.pop_front();
+    return std::string(reinterpret_cast<const char*>(message.data()), message.size());
+}
+
+std::optional<DeviceMemoryWindow> GetDeviceMemoryWindow() {
+    ConnectionState& state = GetState();
+    if (!state.initialized) {
+        if (!InitConnection(1 << 20)) {
+            std::cerr << "Failed to initialize connection\n";
+            return std::nullopt;
+        }
     }
-    return std::string(buffer, static_cast<std::size_t>(received));
+    return state.dram;
 }
 
 void* GetSharedMemoryBase() {
@@ -324,7 +522,7 @@ void* GetSharedMemoryBase() {
     if (!state.initialized) {
         return nullptr;
     }
-    return state.shared.addr;
+    return PayloadBase(state);
 }
 
 }  // namespace rad
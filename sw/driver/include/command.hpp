@@ -17,6 +17,9 @@ enum radCmdType {
 enum radMemCmdType {
     radMemCmdType_COPY,
     radMemCmdType_SET,
+    radMemCmdType_COPY2D,
+    radMemCmdType_COPY3D,
+    radMemCmdType_CHAIN,
 };
 
 class Command {
@@ -36,10 +39,10 @@ public:
 
 class CopyCommand : public Command {
 public:
-    CopyCommand(uint32_t src_addr, uint32_t dst_addr, uint32_t size, void *userspace_dst_addr, bool d2h) :
+    CopyCommand(uint64_t src_addr, uint64_t dst_addr, uint32_t size, void *userspace_dst_addr, bool d2h) :
         Command(radCmdType_MEM), src_addr(src_addr), dst_addr(dst_addr), size(size), userspace_dst_addr(userspace_dst_addr), d2h(d2h) {}
-    uint32_t src_addr;
-    uint32_t dst_addr;
+    uint64_t src_addr;
+    uint64_t dst_addr;
     uint32_t size;
     void *userspace_dst_addr;
     bool d2h;
@@ -9,12 +9,25 @@
 
 namespace rad {
 
-std::optional<std::string> SubmitCommand(const std::array<std::uint8_t, 16>& header,
+// Command header size of protocol v2; must match COMMAND_BYTES in gluon-sim/src/common/protocol.rs.
+constexpr std::size_t kCommandBytes = 32;
+
+using CommandHeader = std::array<std::uint8_t, kCommandBytes>;
+
+std::optional<std::string> SubmitCommand(const CommandHeader& header,
                                          const void* payload,
                                          std::size_t payload_size);
 
 std::optional<std::string> ReceiveError();
 
+// The slice of device memory the simulator assigned to this client; connects on first use.
+struct DeviceMemoryWindow {
+    std::uint64_t base;
+    std::uint64_t size;
+};
+
+std::optional<DeviceMemoryWindow> GetDeviceMemoryWindow();
+
 void* GetSharedMemoryBase();
 
 }
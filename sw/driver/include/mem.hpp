@@ -1,21 +1,31 @@
 #ifndef MEM_HPP
 #define MEM_HPP
 
+#include "driver.h"
 #include "rad_defs.h"
 
+#include <algorithm>
 #include <cstdint>
 #include <optional>
 
-static std::uint64_t g_device_mem_used = GPU_MEM_START_ADDR;
+static std::uint64_t g_device_mem_used = 0;
 
-std::optional<uint32_t> allocateDeviceMemory(size_t bytes) {
-    static const std::uint64_t capacity = static_cast<std::uint64_t>(GPU_DRAM_SIZE);
+// Bump-allocates inside the device memory window the simulator assigned to this client.
+std::optional<uint64_t> allocateDeviceMemory(size_t bytes) {
+    static std::uint64_t capacity = 0;
+    if (capacity == 0) {
+        std::optional<rad::DeviceMemoryWindow> window = rad::GetDeviceMemoryWindow();
+        if (!window)
+            return std::nullopt;
+        g_device_mem_used = std::max<std::uint64_t>(window->base, GPU_MEM_START_ADDR);
+        capacity = window->base + window->size;
+    }
     size_t aligned_bytes = bytes + (bytes % sizeof(uint32_t));
     if (g_device_mem_used > capacity)
         return std::nullopt;
     if (bytes > capacity - g_device_mem_used)
         return std::nullopt;
-    uint32_t addr = static_cast<uint32_t>(g_device_mem_used);
+    uint64_t addr = g_device_mem_used;
     g_device_mem_used += aligned_bytes;
     return addr;
 }
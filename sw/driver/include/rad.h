@@ -20,11 +20,20 @@ typedef struct {
 enum radErrorCode {
     radError_NONE,
     radError_EXECUTION,
+    radError_INVALID_COMMAND = 4,
+    radError_MEMORY = 5,
+    radError_SIMULATOR = 6,
+    // The simulator stopped and the connection is closing; not tied to a command.
+    radError_FATAL = 7,
+    // The kernel exceeded its cycle budget and was killed.
+    radError_TIMEOUT = 8,
 };
 
+// Sent as the COPY flags byte.
 enum radMemCpyDir {
     radMemCpyDir_H2D,
     radMemCpyDir_D2H,
+    radMemCpyDir_D2D,
 };
 
 struct radError {
@@ -72,7 +81,9 @@ struct radParamBuf {
     }
 };
 
-void radKernelLaunch(const char *kernel_name, radDim3 grid_dim, radDim3 block_dim, radParamBuf* params);
+// timeout_cycles is the kernel's watchdog budget in simulator cycles; 0 uses the simulator's default.
+void radKernelLaunch(const char *kernel_name, radDim3 grid_dim, radDim3 block_dim, radParamBuf* params,
+                     uint32_t timeout_cycles = 0);
 
 void radMemCpy(void *dst, void *src, size_t bytes, radMemCpyDir dir);
 
@@ -20,6 +20,28 @@ void write_u32_le(std::uint8_t* dst, std::uint32_t value) {
     dst[3] = static_cast<std::uint8_t>((value >> 24) & 0xFF);
 }
 
+void write_u64_le(std::uint8_t* dst, std::uint64_t value) {
+    write_u32_le(dst, static_cast<std::uint32_t>(value & 0xFFFFFFFF));
+    write_u32_le(dst + 4, static_cast<std::uint32_t>(value >> 32));
+}
+
+// Kernel images, stacks and TLS are addressed by the 32-bit cores and must live below 4 GiB.
+std::optional<std::uint32_t> allocateCoreAddressableMemory(size_t bytes) {
+    auto addr = allocateDeviceMemory(bytes);
+    if (!addr || *addr + bytes > UINT32_MAX)
+        return std::nullopt;
+    return static_cast<std::uint32_t>(*addr);
+}
+
+// Bytes of the kernel header the simulator reads, see "Kernel Payload" in docs/gluon.md. Parameters start
+// at KERNEL_HEADER_MEM_END, so it must leave room for the whole header.
+constexpr std::size_t kKernelHeaderBytes = 74;
+static_assert(KERNEL_HEADER_MEM_END >= kKernelHeaderBytes, "KERNEL_HEADER_MEM_END is shorter than the kernel header");
+
+// Every kernel gets a printf ring: a header of u32 { lock, head, tail, limit } followed by the data.
+constexpr std::size_t kPrintfRingHeaderBytes = 16;
+constexpr std::uint32_t kPrintfRingBytes = 4096;
+
 struct BufferWriter {
     std::uint8_t* cursor;
     std::uint8_t* end;
@@ -32,6 +54,14 @@ struct BufferWriter {
         return true;
     }
 
+    bool write_u64(std::uint64_t value) {
+        if (!remaining(8))
+            return false;
+        write_u64_le(cursor, value);
+        cursor += 8;
+        return true;
+    }
+
     bool write_u8(std::uint8_t value) {
         if (!remaining(1))
             return false;
@@ -70,7 +100,8 @@ static CommandStream command_stream;
 void radKernelLaunch(const char *kernel_name,
                                  radDim3 grid_dim,
                                  radDim3 block_dim,
-                                 radParamBuf* params) {
+                                 radParamBuf* params,
+                                 uint32_t timeout_cycles) {
     
     ELFLoader *loader = new ELFLoader("sw/test/build/kernel.elf");
  
@@ -82,11 +113,16 @@ void radKernelLaunch(const char *kernel_name,
             params_data = params->data();
     }
 
-    size_t payload_size = KERNEL_HEADER_MEM_END + params_size + loader->size;
-    auto kernel_payload_addr_opt = allocateDeviceMemory(payload_size);
+    // The printf ring header ends the payload, so the launch DMA initializes it; the ring data follows
+    // it in device memory.
+    size_t image_end = KERNEL_HEADER_MEM_END + params_size + loader->size;
+    size_t ring_offset = (image_end + 3) & ~static_cast<size_t>(3);
+    size_t payload_size = ring_offset + kPrintfRingHeaderBytes;
+    auto kernel_payload_addr_opt = allocateCoreAddressableMemory(payload_size + kPrintfRingBytes);
     assert(kernel_payload_addr_opt);
     uint32_t kernel_payload_addr = *kernel_payload_addr_opt;
     uint32_t kernel_reloc_addr = kernel_payload_addr + KERNEL_HEADER_MEM_END + params_size;
+    uint32_t printf_ring_addr = kernel_payload_addr + ring_offset;
     
     // apply relocations
     loader->applyRelocations(kernel_reloc_addr);
@@ -94,12 +130,12 @@ void radKernelLaunch(const char *kernel_name,
     uint32_t kernel_pc = loader->getSymbolAddress(kernel_name, kernel_reloc_addr);
 
     // allocate stack space in GPU mem
-    auto stack_base_addr_opt = allocateDeviceMemory(KERNEL_STACK_SIZE);
+    auto stack_base_addr_opt = allocateCoreAddressableMemory(KERNEL_STACK_SIZE);
     assert(stack_base_addr_opt);
     uint32_t stack_base_addr = *stack_base_addr_opt + KERNEL_STACK_SIZE - 4;
 
     // allocate tls space
-    auto tls_base_addr_opt = allocateDeviceMemory(KERNEL_TLS_SIZE);
+    auto tls_base_addr_opt = allocateCoreAddressableMemory(KERNEL_TLS_SIZE);
     assert(tls_base_addr_opt);
     uint32_t tls_base_addr = *tls_base_addr_opt;
 
@@ -119,13 +155,20 @@ void radKernelLaunch(const char *kernel_name,
         !writer.write_u32(static_cast<std::uint32_t>(block_dim.x)) ||
         !writer.write_u32(static_cast<std::uint32_t>(block_dim.y)) ||
         !writer.write_u32(static_cast<std::uint32_t>(block_dim.z)) ||
-        !writer.write_u32(KERNEL_PRINTF_HOST_ADDR) ||
+        !writer.write_u64(KERNEL_PRINTF_HOST_ADDR) ||
         !writer.write_u8(KERNEL_REGS_PER_THREAD) ||
         !writer.write_u32(KERNEL_SMEM_PER_BLOCK) ||
         !writer.write_u8(KERNEL_FLAGS) ||
-        !writer.write_zero(KERNEL_HEADER_MEM_PADDING) ||
+        !writer.write_u64(printf_ring_addr) ||
+        !writer.write_u32(timeout_cycles) ||
+        !writer.write_zero(KERNEL_HEADER_MEM_END - (writer.cursor - payload.get())) ||
         !writer.write_block(params_data, params_size) ||
         !writer.write_block(loader->binary_data, loader->size) ||
+        !writer.write_zero(ring_offset - image_end) ||
+        !writer.write_u32(0) ||
+        !writer.write_u32(0) ||
+        !writer.write_u32(0) ||
+        !writer.write_u32(kPrintfRingBytes) ||
         !writer.finished()) {
         fprintf(stderr, "radKernelLaunch: failed to populate payload\n");
         return;
@@ -138,12 +181,12 @@ void radKernelLaunch(const char *kernel_name,
 
     uint8_t cmd_id = command_stream.add_command(std::make_unique<KernelCommand>(loader->binary_data, loader->size, kernel_reloc_addr));
 
-    std::array<std::uint8_t, 16> header_bytes{};
+    rad::CommandHeader header_bytes{};
     header_bytes[0] = cmd_id;
     header_bytes[1] = radCmdType_KERNEL;
-    write_u32_le(header_bytes.data() + 2, 0);
-    write_u32_le(header_bytes.data() + 6, static_cast<std::uint32_t>(payload_size));
-    write_u32_le(header_bytes.data() + 10, kernel_payload_addr);
+    write_u64_le(header_bytes.data() + 2, 0);
+    write_u32_le(header_bytes.data() + 10, static_cast<std::uint32_t>(payload_size));
+    write_u64_le(header_bytes.data() + 14, kernel_payload_addr);
     auto response = rad::SubmitCommand(header_bytes, payload.get(), payload_size);
     if (!response)
         fprintf(stderr, "radKernelLaunch: failed to submit kernel launch\n");
@@ -154,8 +197,8 @@ void radMemCpy(void *dst, void *src, size_t bytes, radMemCpyDir dir) {
     if (dst == nullptr || src == nullptr)
         return;
     
-    uint32_t src_addr_u32 = static_cast<uint32_t>(reinterpret_cast<std::uintptr_t>(src));
-    uint32_t dst_addr_u32 = static_cast<uint32_t>(reinterpret_cast<std::uintptr_t>(dst));
+    uint64_t src_addr_u64 = static_cast<uint64_t>(reinterpret_cast<std::uintptr_t>(src));
+    uint64_t dst_addr_u64 = static_cast<uint64_t>(reinterpret_cast<std::uintptr_t>(dst));
     uint32_t size_u32 = static_cast<uint32_t>(bytes);
 
     void *src_addr, *dst_addr, *payload_addr;
@@ -167,24 +210,30 @@ void radMemCpy(void *dst, void *src, size_t bytes, radMemCpyDir dir) {
         payload_addr = src;
         payload_size = bytes;
         userspace_dst_addr = 0;
-    } else {
+    } else if (dir == radMemCpyDir_D2H) {
         src_addr = src;
         dst_addr = 0;
         payload_addr = nullptr;
         payload_size = 0;
         userspace_dst_addr = dst;
+    } else {
+        src_addr = src;
+        dst_addr = dst;
+        payload_addr = nullptr;
+        payload_size = 0;
+        userspace_dst_addr = 0;
     }
 
-    uint8_t cmd_id = command_stream.add_command(std::make_unique<CopyCommand>(src_addr_u32, dst_addr_u32, size_u32, userspace_dst_addr, dir));
+    uint8_t cmd_id = command_stream.add_command(std::make_unique<CopyCommand>(src_addr_u64, dst_addr_u64, size_u32, userspace_dst_addr, dir == radMemCpyDir_D2H));
     
-    std::array<std::uint8_t, 16> header_bytes{};
+    rad::CommandHeader header_bytes{};
     header_bytes[0] = cmd_id;
     header_bytes[1] = radCmdType_MEM;
     header_bytes[2] = radMemCmdType_COPY;
-    write_u32_le(header_bytes.data() + 3, static_cast<std::uint32_t>(reinterpret_cast<std::uintptr_t>(src_addr)));
-    write_u32_le(header_bytes.data() + 7, static_cast<std::uint32_t>(reinterpret_cast<std::uintptr_t>(dst_addr)));
-    write_u32_le(header_bytes.data() + 11, static_cast<std::uint32_t>(bytes));
-    header_bytes[15] = dir;
+    write_u64_le(header_bytes.data() + 3, static_cast<std::uint64_t>(reinterpret_cast<std::uintptr_t>(src_addr)));
+    write_u64_le(header_bytes.data() + 11, static_cast<std::uint64_t>(reinterpret_cast<std::uintptr_t>(dst_addr)));
+    write_u32_le(header_bytes.data() + 19, static_cast<std::uint32_t>(bytes));
+    header_bytes[23] = dir;
     auto response = rad::SubmitCommand(header_bytes, payload_addr, payload_size);
     if (!response)
         fprintf(stderr, "radMemCpy: failed to submit mem copy\n");
@@ -211,6 +260,13 @@ void radGetError(radError *err) {
     if (!response)
         fprintf(stderr, "radGetError: failed to receive error\n");
     if (response) {
+        if (static_cast<radErrorCode>(response->at(1)) == radError_FATAL) {
+            fprintf(stderr, "radGetError: simulator stopped (error %d)\n", static_cast<int>(response->at(2)));
+            err->err_code = radError_FATAL;
+            err->cmd_id = 0;
+            err->pc = 0;
+            return;
+        }
         uint8_t response_cmd_id = response->at(0);
         Command* command = command_stream.ack_command(response_cmd_id);
         if (command) {
@@ -232,7 +288,7 @@ void radGetError(radError *err) {
             pc = translated_pc;
         }
 
-        if (command->cmd_type == radCmdType_MEM) {
+        if (command->cmd_type == radCmdType_MEM && err->err_code == radError_NONE) {
             CopyCommand* copy_command = static_cast<CopyCommand*>(command);
             if (copy_command->d2h) {
                 void *shared_mem_base = rad::GetSharedMemoryBase();